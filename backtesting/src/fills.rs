@@ -0,0 +1,228 @@
+use time::{Month, OffsetDateTime};
+use ibapi_handler::IBApiBar;
+use crate::portfolio::{Fill, Portfolio};
+use crate::{Signal, SignalType};
+
+/// An order placed on a signal: a positive quantity buys, a negative one sells.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    /// When the signal was given.
+    pub timestamp: i64,
+    pub identifier: String,
+    pub quantity: f64,
+    /// The signal's price, the close of the bar it was given on.
+    pub price: f64,
+}
+
+impl Order {
+    /// The order for a buy or sell signal, None for holds.
+    pub fn from_signal(signal: &Signal) -> Option<Order> {
+        let quantity = signal.quantity.unwrap_or(1) as f64;
+        let quantity = match signal.signal_type {
+            SignalType::Buy => quantity,
+            SignalType::Sell => -quantity,
+            SignalType::Hold => return None,
+        };
+        Some(Order { timestamp: signal.timestamp, identifier: signal.identifier.clone(), quantity, price: signal.price.into_inner() })
+    }
+}
+
+/// Decides how orders are filled in a backtest: when, at what price, how much of them and at what cost.
+pub trait FillModel {
+    /// Whether orders are filled on the bar after their signal's, rather than on the signal's own bar.
+    fn next_bar(&self) -> bool;
+
+    /// Fills `order` on `bar`, or returns None if nothing of it is filled. The fill must not take more than
+    /// `portfolio.allowed_quantity` allows; whatever is not filled is cancelled.
+    fn fill(&mut self, order: &Order, bar: &IBApiBar, portfolio: &Portfolio) -> Option<Fill>;
+
+    /// Forgets whatever the model kept track of, before a new backtest.
+    fn reset(&mut self) {}
+}
+
+/// The price an order is filled at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillPrice {
+    /// The signal's price on the signal's bar, i.e. trading the close the signal was computed from.
+    SignalClose,
+    /// The open of the next bar.
+    NextOpen,
+    /// A limit order at the signal's price, filled in the next bar if its range reaches the limit:
+    /// at the open if the bar opens through it, at the limit otherwise.
+    NextLimit,
+}
+
+/// How far the fill price moves against an order. Slipped prices stay inside the fill bar's high/low range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slippage {
+    None,
+    /// A fixed amount per share.
+    PerShare(f64),
+    /// `impact` times the order's share of the bar volume, as a fraction of the price
+    /// (an order for 10% of the volume with an impact of 0.1 moves the price by 1%).
+    VolumeShare(f64),
+}
+
+impl Slippage {
+    fn slip(&self, price: f64, quantity: f64, bar: &IBApiBar) -> f64 {
+        let amount = match self {
+            Slippage::None => 0.0,
+            Slippage::PerShare(amount) => *amount,
+            Slippage::VolumeShare(impact) => {
+                let volume = bar.volume().into_inner();
+                if volume > 0.0 { impact * quantity.abs() / volume * price } else { 0.0 }
+            },
+        };
+        let slipped = price + amount * quantity.signum();
+        slipped.clamp(bar.low().into_inner(), bar.high().into_inner())
+    }
+}
+
+/// What a fill costs, in the style of IB's stock commissions (exchange and regulatory fees not included).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Commission {
+    None,
+    /// A rate per share, at least `minimum` per order and at most `maximum` as a fraction of the trade value.
+    PerShare { rate: f64, minimum: f64, maximum: f64 },
+    /// A rate per share that falls with the shares already traded this (calendar, UTC) month: `tiers` are
+    /// (monthly shares up to, rate) in increasing order. Minimum and maximum as for PerShare.
+    Tiered { tiers: Vec<(f64, f64)>, minimum: f64, maximum: f64 },
+}
+
+impl Commission {
+    /// IB's fixed pricing for US stocks: USD 0.005 a share, at least USD 1 and at most 1% of the trade value.
+    pub fn ib_fixed() -> Self {
+        Commission::PerShare { rate: 0.005, minimum: 1.0, maximum: 0.01 }
+    }
+
+    /// IB's tiered pricing for US stocks: USD 0.0035 a share up to 300,000 shares a month down to
+    /// USD 0.0005 beyond 100,000,000, at least USD 0.35 and at most 1% of the trade value.
+    pub fn ib_tiered() -> Self {
+        Commission::Tiered {
+            tiers: vec![
+                (300_000.0, 0.0035),
+                (3_000_000.0, 0.002),
+                (20_000_000.0, 0.0015),
+                (100_000_000.0, 0.001),
+                (f64::INFINITY, 0.0005),
+            ],
+            minimum: 0.35,
+            maximum: 0.01,
+        }
+    }
+
+    /// The commission for trading `shares` at `price` with `monthly_volume` shares traded before it this month.
+    pub fn cost(&self, shares: f64, price: f64, monthly_volume: f64) -> f64 {
+        let (rate, minimum, maximum) = match self {
+            Commission::None => return 0.0,
+            Commission::PerShare { rate, minimum, maximum } => (*rate, *minimum, *maximum),
+            Commission::Tiered { tiers, minimum, maximum } => {
+                let rate = tiers.iter().find(|(up_to, _)| monthly_volume < *up_to).or(tiers.last()).map_or(0.0, |(_, rate)| *rate);
+                (rate, *minimum, *maximum)
+            },
+        };
+        if shares == 0.0 {
+            return 0.0;
+        }
+        (shares.abs() * rate).max(minimum).min(maximum * shares.abs() * price)
+    }
+}
+
+/// A FillModel put together from a fill price, slippage, commissions and a cap on the share of a bar's volume
+/// an order can take. Without a cap, slippage and commissions it fills every order whole at the signal's price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedFills {
+    price: FillPrice,
+    slippage: Slippage,
+    commission: Commission,
+    volume_limit: Option<f64>,
+    month: Option<(i32, Month)>, // the month monthly_volume counts
+    monthly_volume: f64,
+}
+
+impl SimulatedFills {
+    pub fn new(price: FillPrice) -> Self {
+        SimulatedFills {
+            price,
+            slippage: Slippage::None,
+            commission: Commission::None,
+            volume_limit: None,
+            month: None,
+            monthly_volume: 0.0,
+        }
+    }
+
+    /// Fills at the close of the signal's bar without any costs.
+    pub fn at_signal_close() -> Self {
+        SimulatedFills::new(FillPrice::SignalClose)
+    }
+
+    pub fn with_slippage(mut self, slippage: Slippage) -> Self {
+        self.slippage = slippage;
+        self
+    }
+
+    pub fn with_commission(mut self, commission: Commission) -> Self {
+        self.commission = commission;
+        self
+    }
+
+    /// Fills at most `fraction` of the fill bar's volume (e.g. 0.1 for 10%); the rest of the order is cancelled.
+    pub fn with_volume_limit(mut self, fraction: f64) -> Self {
+        self.volume_limit = Some(fraction);
+        self
+    }
+
+    /// The shares traded so far in the month of the last fill.
+    pub fn monthly_volume(&self) -> f64 {
+        self.monthly_volume
+    }
+
+    fn base_price(&self, order: &Order, bar: &IBApiBar) -> Option<f64> {
+        let open = bar.open().into_inner();
+        match self.price {
+            FillPrice::SignalClose => Some(order.price),
+            FillPrice::NextOpen => Some(open),
+            FillPrice::NextLimit if order.quantity > 0.0 => (bar.low().into_inner() <= order.price).then(|| open.min(order.price)),
+            FillPrice::NextLimit => (bar.high().into_inner() >= order.price).then(|| open.max(order.price)),
+        }
+    }
+}
+
+impl FillModel for SimulatedFills {
+    fn next_bar(&self) -> bool {
+        self.price != FillPrice::SignalClose
+    }
+
+    fn fill(&mut self, order: &Order, bar: &IBApiBar, portfolio: &Portfolio) -> Option<Fill> {
+        let base_price = self.base_price(order, bar)?;
+        let mut quantity = order.quantity;
+        if let Some(fraction) = self.volume_limit {
+            let cap = (fraction * bar.volume().into_inner()).floor().max(0.0);
+            quantity = quantity.clamp(-cap, cap);
+        }
+        let price = self.slippage.slip(base_price, quantity, bar);
+
+        let month = OffsetDateTime::from_unix_timestamp(bar.date).ok().map(|date| (date.year(), date.month()));
+        if month != self.month {
+            self.month = month;
+            self.monthly_volume = 0.0;
+        }
+        let mut commission = self.commission.cost(quantity, price, self.monthly_volume);
+        let allowed = portfolio.allowed_quantity(&order.identifier, quantity, price, commission);
+        if allowed != quantity {
+            quantity = allowed;
+            commission = self.commission.cost(quantity, price, self.monthly_volume);
+        }
+        if quantity == 0.0 {
+            return None;
+        }
+        self.monthly_volume += quantity.abs();
+        Some(Fill { timestamp: bar.date, identifier: order.identifier.clone(), quantity, price, commission })
+    }
+
+    fn reset(&mut self) {
+        self.month = None;
+        self.monthly_volume = 0.0;
+    }
+}
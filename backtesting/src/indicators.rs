@@ -0,0 +1,141 @@
+use anyhow::{bail, Error};
+
+// Indicators over a series of values, one output per input.
+// Outputs are None until enough values have been seen (the warm-up), and only ever depend on the values
+// up to and including their own index, so running on a prefix of a series gives a prefix of the output.
+
+fn check_period(name: &str, period: usize) -> Result<(), Error> {
+    if period == 0 {
+        bail!("{} period must be at least 1", name);
+    }
+    Ok(())
+}
+
+/// Simple moving average, first defined at index `period - 1`.
+pub fn sma(values: &[f64], period: usize) -> Result<Vec<Option<f64>>, Error> {
+    check_period("SMA", period)?;
+    let mut sum = 0.0;
+    let mut result = Vec::with_capacity(values.len());
+    for i in 0..values.len() {
+        sum += values[i];
+        if i >= period {
+            sum -= values[i - period];
+        }
+        result.push(if i + 1 >= period { Some(sum / period as f64) } else { None });
+    }
+    Ok(result)
+}
+
+/// Exponential moving average with smoothing 2 / (period + 1), seeded with the simple average
+/// of the first `period` values, so first defined at index `period - 1`.
+pub fn ema(values: &[f64], period: usize) -> Result<Vec<Option<f64>>, Error> {
+    check_period("EMA", period)?;
+    let alpha = 2.0 / (period as f64 + 1.0);
+    let mut result = Vec::with_capacity(values.len());
+    let mut sum = 0.0;
+    let mut current: Option<f64> = None;
+    for (i, value) in values.iter().enumerate() {
+        current = match current {
+            Some(previous) => Some(previous + alpha * (value - previous)),
+            None => {
+                sum += value;
+                if i + 1 == period { Some(sum / period as f64) } else { None }
+            },
+        };
+        result.push(current);
+    }
+    Ok(result)
+}
+
+/// EMA of a series that has its own warm-up, starting with its first defined value.
+fn ema_of_defined(values: &[Option<f64>], period: usize) -> Result<Vec<Option<f64>>, Error> {
+    let first = values.iter().position(|value| value.is_some()).unwrap_or(values.len());
+    let defined: Vec<f64> = values[first..].iter().map(|value| value.unwrap_or(f64::NAN)).collect();
+    let mut result = vec![None; first];
+    result.extend(ema(&defined, period)?);
+    Ok(result)
+}
+
+/// Relative strength index with Wilder's smoothing: the first average gain and loss are the plain averages
+/// of the first `period` changes, so the first value is at index `period`.
+/// A window without losses is 100, one without any change 50.
+pub fn rsi(values: &[f64], period: usize) -> Result<Vec<Option<f64>>, Error> {
+    check_period("RSI", period)?;
+    let mut result = Vec::with_capacity(values.len());
+    let (mut gain, mut loss) = (0.0, 0.0);
+    for i in 0..values.len() {
+        if i == 0 {
+            result.push(None);
+            continue;
+        }
+        let change = values[i] - values[i - 1];
+        let (up, down) = (change.max(0.0), (-change).max(0.0));
+        if i <= period {
+            gain += up / period as f64;
+            loss += down / period as f64;
+            if i < period {
+                result.push(None);
+                continue;
+            }
+        } else {
+            gain = (gain * (period - 1) as f64 + up) / period as f64;
+            loss = (loss * (period - 1) as f64 + down) / period as f64;
+        }
+        let value = if loss == 0.0 {
+            if gain == 0.0 { 50.0 } else { 100.0 }
+        } else {
+            100.0 - 100.0 / (1.0 + gain / loss)
+        };
+        result.push(Some(value));
+    }
+    Ok(result)
+}
+
+/// The three series of a MACD.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macd {
+    /// EMA(fast) - EMA(slow), first defined at index `slow - 1`.
+    pub line: Vec<Option<f64>>,
+    /// EMA(signal) of the line, first defined `signal - 1` values after it.
+    pub signal: Vec<Option<f64>>,
+    /// line - signal.
+    pub histogram: Vec<Option<f64>>,
+}
+
+/// Moving average convergence/divergence, 12/26/9 being the usual periods.
+pub fn macd(values: &[f64], fast: usize, slow: usize, signal: usize) -> Result<Macd, Error> {
+    check_period("MACD signal", signal)?;
+    if fast >= slow {
+        bail!("MACD fast period {} must be shorter than the slow period {}", fast, slow);
+    }
+    let fast = ema(values, fast)?;
+    let slow = ema(values, slow)?;
+    let line: Vec<Option<f64>> = fast.iter().zip(&slow).map(|(fast, slow)| Some((*fast)? - (*slow)?)).collect();
+    let signal = ema_of_defined(&line, signal)?;
+    let histogram = line.iter().zip(&signal).map(|(line, signal)| Some((*line)? - (*signal)?)).collect();
+    Ok(Macd { line, signal, histogram })
+}
+
+/// Standard deviation of the log returns over the last `window` returns (sample deviation, n - 1),
+/// scaled by the square root of `periods_per_year` (e.g. 252 for daily bars, 1 to leave it per bar).
+/// First defined at index `window`.
+pub fn volatility(values: &[f64], window: usize, periods_per_year: f64) -> Result<Vec<Option<f64>>, Error> {
+    if window < 2 {
+        bail!("Volatility window must be at least 2 returns");
+    }
+    if periods_per_year <= 0.0 {
+        bail!("Volatility needs a positive number of periods per year");
+    }
+    let mut result = Vec::with_capacity(values.len());
+    for i in 0..values.len() {
+        if i < window {
+            result.push(None);
+            continue;
+        }
+        let returns: Vec<f64> = (i + 1 - window..=i).map(|j| (values[j] / values[j - 1]).ln()).collect();
+        let mean = returns.iter().sum::<f64>() / window as f64;
+        let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (window - 1) as f64;
+        result.push(Some((variance * periods_per_year).sqrt()));
+    }
+    Ok(result)
+}
@@ -1,65 +1,86 @@
 use ordered_float::OrderedFloat;
 use std::collections::{HashMap};
 use std::iter::Iterator;
-use anyhow::{bail, Error};
+use std::sync::Arc;
+use anyhow::{anyhow, bail, Error};
+use derivative::Derivative;
 use ibapi::{ market_data::historical::BarSize };
 use ibapi::contracts::Contract;
 use ibapi::orders::Order;
 use fq_data_broker::{DataBroker, HashedBarSize};
-use ibapi_handler::IBApiBar;
+use ibapi_handler::{IBApiBar, IbapiConfig};
 use time::OffsetDateTime;
 
+pub mod fills;
+pub mod indicators;
+pub mod measures;
+pub mod portfolio;
+pub mod strategy;
+
+pub use fills::{Commission, FillModel, FillPrice, SimulatedFills, Slippage};
+pub use measures::BacktestingMeasure;
+pub use portfolio::{BacktestResult, EquityPoint, Fill, Portfolio, Position, Trade};
+pub use strategy::{ActionFn, BarContext, MetricsMapFn, SignalRules, StrategyLogic};
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum BacktestingMetric {
-    SMA(usize), // period in days
-    EMA,
-    RSI,
-    MACD,
-    DpRatio,
-    Volatility,
-    Volume,
+    /// The average close over a period in bars, defined from index period - 1, the first full window.
+    SMA(usize),
+    EMA(usize), // period in bars
+    RSI(usize), // period in bars, 14 is the usual one
+    MACD { fast: usize, slow: usize, signal: usize }, // the MACD histogram, usually 12/26/9
+    /// A fixed yearly dividend per share over each close, an error for closes that are not positive.
+    DpRatio(OrderedFloat<f64>),
+    Volatility { window: usize, periods_per_year: usize }, // e.g. 20 daily returns, annualized with 252
+    Volume(usize), // average volume over a period in bars, 1 for the raw volume
 }
 
-// ignore signal sentinel
-static IGNORE_SENTINEL: OrderedFloat<f64> = OrderedFloat(f64::INFINITY);
+// ignore signal sentinel, marks the values of a metric that is still warming up
+pub static IGNORE_SENTINEL: OrderedFloat<f64> = OrderedFloat(f64::INFINITY);
 
 impl BacktestingMetric {
 
-    // this function takes bars and then returns a vector of signals (buy, sell, hold)
-    pub fn calculate_for_data(&self, data: &Vec<IBApiBar>, buy_signal: MetricsMapFn, sell_signal: MetricsMapFn, identifier: String) -> Result<Vec<Signal>, Error> {
-        match self {
-            BacktestingMetric::SMA(period) => {
-                match (data.first(), data.last()) {
-                    (Some(first), Some(last)) => {
-                        let mut sma = OrderedFloat(0.0);
-                        let mut sma_values = Vec::new();
-                        // generate sma values
-                        for i in 0..data.len() {
-                            if i < *period {
-                                sma = sma + data[i].close;
-                                sma_values.push(IGNORE_SENTINEL);
-                            } else {
-                                sma = sma - data[i - *period].close + data[i].close;
-                                sma_values.push(sma / *period as f64);
-                            }
-                        }
-                        // generate signals
-                        let signals = self.generate_signals(data, &sma_values, buy_signal, sell_signal, identifier)?;
-                        return Ok(signals);
-                    }
-                    _ => bail!("No data")
+    /// The metric's value at every bar, IGNORE_SENTINEL while there are not enough bars yet for it.
+    /// Each value only depends on the bars up to its own, as it would have been known at the time.
+    pub fn values(&self, data: &[IBApiBar]) -> Result<Vec<OrderedFloat<f64>>, Error> {
+        let closes: Vec<f64> = data.iter().map(|bar| bar.close.into_inner()).collect();
+        let values = match self {
+            BacktestingMetric::SMA(period) => indicators::sma(&closes, *period)?,
+            BacktestingMetric::EMA(period) => indicators::ema(&closes, *period)?,
+            BacktestingMetric::RSI(period) => indicators::rsi(&closes, *period)?,
+            BacktestingMetric::MACD { fast, slow, signal } => indicators::macd(&closes, *fast, *slow, *signal)?.histogram,
+            BacktestingMetric::DpRatio(dividend) => {
+                if let Some(bar) = data.iter().find(|bar| bar.close.into_inner() <= 0.0) {
+                    bail!("D/P ratio of a close of {} at {}", bar.close, bar.date);
                 }
+                closes.iter().map(|close| Some(dividend.into_inner() / close)).collect()
+            },
+            BacktestingMetric::Volatility { window, periods_per_year } => indicators::volatility(&closes, *window, *periods_per_year as f64)?,
+            BacktestingMetric::Volume(period) => {
+                let volumes: Vec<f64> = data.iter().map(|bar| bar.volume().into_inner()).collect();
+                indicators::sma(&volumes, *period)?
             },
-            _ => bail!("Metric not implemented")
+        };
+        Ok(values.into_iter().map(|value| value.map_or(IGNORE_SENTINEL, OrderedFloat)).collect())
+    }
+
+    // this function takes bars and then returns a vector of signals (buy, sell, hold)
+    pub fn calculate_for_data(&self, data: &Vec<IBApiBar>, buy_signal: &MetricsMap, sell_signal: &MetricsMap, identifier: String) -> Result<Vec<Signal>, Error> {
+        if data.is_empty() {
+            bail!("No data");
         }
+        let values = self.values(data)?;
+        // generate signals
+        self.generate_signals(data, &values, buy_signal, sell_signal, identifier)
     }
 
-    pub fn generate_signals(&self, data: &Vec<IBApiBar>, metrics: &Vec<OrderedFloat<f64>>, buy_signal: MetricsMapFn, sell_signal: MetricsMapFn, identifier: String) -> Result<Vec<Signal>, Error> {
+    pub fn generate_signals(&self, data: &Vec<IBApiBar>, metrics: &Vec<OrderedFloat<f64>>, buy_signal: &MetricsMap, sell_signal: &MetricsMap, identifier: String) -> Result<Vec<Signal>, Error> {
         let mut result = Vec::new();
         for i in 0..metrics.len() {
             if metrics[i] != IGNORE_SENTINEL {
-                let buy = buy_signal(&metrics, i as u32)?;
-                let sell = sell_signal(&metrics, i as u32)?;
+                // the functions only see the values up to the current one
+                let buy = buy_signal(&metrics[..=i], i as u32)?;
+                let sell = sell_signal(&metrics[..=i], i as u32)?;
                 if buy && sell {
                     bail!("Buy and sell signals generated at the same time");
                 }
@@ -81,28 +102,27 @@ impl BacktestingMetric {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub enum BacktestingMeasure {
-    NetProfit,
-    TotalReturn,
-    RiskAdjustedReturn,
-}
+// a buy or sell function, as generate_signals takes it
+type MetricsMap = dyn Fn(&[OrderedFloat<f64>], u32) -> Result<bool, Error>;
 
-// a function that takes some data and generates a vector of signals, the u32 is the current index in the data
-type MetricsMapFn = fn(&Vec<OrderedFloat<f64>>, u32) -> Result<bool, Error>;
-
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-/// A strategy that can be executed on a set of metrics
+#[derive(Derivative, Clone)]
+#[derivative(Debug, Eq, PartialEq, Hash)]
+/// A strategy that can be executed on a set of metrics.
+/// Strategies are told apart by their name, metrics, bar size, contract, dates and measures, not by their logic:
+/// strategies that only differ in what their closures capture (e.g. a parameter sweep) need different names.
+/// Every run starts out with a fresh clone of the logic the strategy was made with.
 pub struct Strategy {
+    name: String,
     context: Vec<BacktestingMetric>,
     bar_size: HashedBarSize,
     contract_name: String,
-    buy_signal: MetricsMapFn,
-    sell_signal: MetricsMapFn,
     start_date: OffsetDateTime,
     end_date: OffsetDateTime,
-    on_buy: fn() -> Result<(), Error>,
-    on_sell: fn() -> Result<(), Error>
+    measures: Vec<BacktestingMeasure>, // what BacktestExecutor::run reports for the strategy
+    #[derivative(Debug = "ignore", PartialEq = "ignore", Hash = "ignore")]
+    rules: Option<SignalRules>, // the buy and sell functions of Strategy::new, for execute
+    #[derivative(Debug = "ignore", PartialEq = "ignore", Hash = "ignore")]
+    logic: Arc<dyn Fn() -> Box<dyn StrategyLogic> + Send + Sync>, // makes the logic for a run
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -137,50 +157,117 @@ impl Signal {
             confidence: Some(confidence)
         }
     }
+
+    pub fn signal_type(&self) -> &SignalType {
+        &self.signal_type
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn price(&self) -> OrderedFloat<f64> {
+        self.price
+    }
+
+    pub fn identifier(&self) -> &String {
+        &self.identifier
+    }
+
+    pub fn quantity(&self) -> i32 {
+        self.quantity.unwrap_or(1)
+    }
 }
 
 impl Strategy {
 
     /// Creates a new strategy.
     /// You must provide contexts, bar_size, contract_name, buy_signal, sell_signal, start_date, end_date, on_buy, on_sell
+    /// The signal and action functions can be fns or closures, see SignalRules for how they are used.
     pub fn new(
         context: Vec<BacktestingMetric>,
         bar_size: HashedBarSize,
         contract_name: String,
-        buy_signal: MetricsMapFn,
-        sell_signal: MetricsMapFn,
+        buy_signal: impl Fn(&[OrderedFloat<f64>], u32) -> Result<bool, Error> + Send + Sync + 'static,
+        sell_signal: impl Fn(&[OrderedFloat<f64>], u32) -> Result<bool, Error> + Send + Sync + 'static,
         start_date: OffsetDateTime,
         end_date: OffsetDateTime,
-        on_buy: fn() -> Result<(), Error>,
-        on_sell: fn() -> Result<(), Error>,
+        on_buy: impl Fn() -> Result<(), Error> + Send + Sync + 'static,
+        on_sell: impl Fn() -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Self {
+        let rules = SignalRules {
+            buy_signal: Arc::new(buy_signal),
+            sell_signal: Arc::new(sell_signal),
+            on_buy: Arc::new(on_buy),
+            on_sell: Arc::new(on_sell),
+        };
+        let mut strategy = Self::with_logic(context, bar_size, contract_name, start_date, end_date, rules.clone());
+        strategy.rules = Some(rules);
+        strategy
+    }
+
+    /// Creates a strategy run by `logic`, which is handed the values of the `context` metrics bar by bar.
+    /// Each run gets its own clone of `logic`, state to be seen outside of runs has to be shared (e.g. in an Arc).
+    pub fn with_logic(
+        context: Vec<BacktestingMetric>,
+        bar_size: HashedBarSize,
+        contract_name: String,
+        start_date: OffsetDateTime,
+        end_date: OffsetDateTime,
+        logic: impl StrategyLogic + Clone + Sync + 'static,
     ) -> Self {
         Self {
+            name: String::new(),
             context,
             bar_size,
             contract_name,
-            buy_signal,
-            sell_signal,
             start_date,
             end_date,
-            on_buy,
-            on_sell,
+            measures: Vec::new(),
+            rules: None,
+            logic: Arc::new(move || Box::new(logic.clone())),
         }
     }
 
+    /// Names the strategy, e.g. after the parameters its closures capture. Empty by default.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Declares the measures BacktestExecutor::run computes for the strategy, e.g. `BacktestingMeasure::ALL`.
+    pub fn with_measures(mut self, measures: &[BacktestingMeasure]) -> Self {
+        self.measures = measures.to_vec();
+        self
+    }
+
+    pub fn measures(&self) -> &Vec<BacktestingMeasure> {
+        &self.measures
+    }
+
     pub fn should_buy(&self, context: Vec<OrderedFloat<f64>>, current: u32) -> Result<bool, Error> {
-        (self.buy_signal)(&context, current)
+        (self.rules()?.buy_signal)(&context, current)
     }
 
     pub fn should_sell(&self, context: Vec<OrderedFloat<f64>>, current:u32) -> Result<bool, Error> {
-        (self.sell_signal)(&context, current)
+        (self.rules()?.sell_signal)(&context, current)
+    }
+
+    fn rules(&self) -> Result<&SignalRules, Error> {
+        self.rules.as_ref().ok_or_else(|| anyhow!("Strategy {:?} has no buy and sell functions, run it with BacktestExecutor::run", self.name))
     }
 
     // iterates over data, generating signals when some condition for a metric is met
     pub fn execute(&self, data: &Vec<IBApiBar>, ) -> Result<HashMap<BacktestingMetric, Result<Vec<Signal>, Error>>, Error> {
+        let rules = self.rules()?;
         let mut return_signals = HashMap::new();
         for metric in &self.context {
             println!("Calculating metric {:?}", metric);
-            let signals_result = metric.calculate_for_data(data, self.buy_signal, self.sell_signal, self.contract_name.clone());
+            let signals_result = metric.calculate_for_data(data, &*rules.buy_signal, &*rules.sell_signal, self.contract_name.clone());
             match signals_result {
                 Ok(signals) => {
                     return_signals.insert(metric.clone(), Ok(signals));
@@ -196,20 +283,54 @@ impl Strategy {
 
 pub struct BacktestExecutor {
     broker: DataBroker,
+    initial_cash: f64, // what every strategy's portfolio starts out with
+    risk_free_rate: f64, // yearly, for the Sharpe and Sortino ratios
+    fill_model: Box<dyn FillModel>, // how the orders of every strategy are filled
 }
 
 impl BacktestExecutor {
-    pub fn new() -> Result<Self, Error> {
+    pub fn new(config: IbapiConfig) -> Result<Self, Error> {
         Ok(Self {
-            broker: DataBroker::new(None)?,
+            broker: DataBroker::new(None, config)?,
+            initial_cash: 100_000.0,
+            risk_free_rate: 0.0,
+            fill_model: Box::new(SimulatedFills::at_signal_close()),
         })
     }
 
+    /// Runs strategies on the bars of an existing broker, e.g. one backed by a SyntheticSource.
+    pub fn with_broker(broker: DataBroker) -> Self {
+        Self { broker, initial_cash: 100_000.0, risk_free_rate: 0.0, fill_model: Box::new(SimulatedFills::at_signal_close()) }
+    }
+
+    /// Sets the cash each strategy's portfolio starts with in `run` (100,000 by default).
+    pub fn with_initial_cash(mut self, initial_cash: f64) -> Self {
+        self.initial_cash = initial_cash;
+        self
+    }
+
+    /// Sets how `run` fills orders, e.g. `SimulatedFills::new(FillPrice::NextOpen).with_commission(Commission::ib_tiered())`.
+    /// By default orders are filled whole at their signal's price, without slippage or commissions.
+    pub fn with_fill_model(mut self, fill_model: Box<dyn FillModel>) -> Self {
+        self.fill_model = fill_model;
+        self
+    }
+
+    /// Sets the yearly risk-free rate the Sharpe and Sortino ratios are measured against (0 by default).
+    pub fn with_risk_free_rate(mut self, risk_free_rate: f64) -> Self {
+        self.risk_free_rate = risk_free_rate;
+        self
+    }
+
     /// Get data, and generate signals
     /// results is a hashmap mapping each passed strategy to its strategy.execute() result.
+    /// Fails if two strategies are equal, as they would share a result.
     pub fn execute(&mut self, strategies: Vec<Strategy>) -> Result<HashMap<Strategy, Result<HashMap<BacktestingMetric, Result<Vec<Signal>, Error>>, Error>>, Error> {
         let mut results = HashMap::new();
         for strategy in strategies {
+            if results.contains_key(&strategy) {
+                bail!("Strategy {:?} is given twice, strategies that only differ in their logic need different names", strategy.name);
+            }
             let data = self.broker.retrieve_data(
                 strategy.contract_name.clone(),
                 strategy.bar_size,
@@ -226,4 +347,40 @@ impl BacktestExecutor {
         }
         Ok(results)
     }
-}
\ No newline at end of file
+
+    /// Gets the data of each strategy and runs its logic over it bar by bar, trading its signals in a fresh Portfolio
+    /// and filling their orders with the executor's fill model. Each result carries the measures its strategy declared.
+    /// Fails if two strategies are equal, as they would share a result.
+    pub fn run(&mut self, strategies: Vec<Strategy>) -> Result<HashMap<Strategy, Result<BacktestResult, Error>>, Error> {
+        let mut results = HashMap::new();
+        for strategy in strategies {
+            if results.contains_key(&strategy) {
+                bail!("Strategy {:?} is given twice, strategies that only differ in their logic need different names", strategy.name);
+            }
+            let result = self.run_strategy(&strategy);
+            results.insert(strategy, result);
+        }
+        Ok(results)
+    }
+
+    fn run_strategy(&mut self, strategy: &Strategy) -> Result<BacktestResult, Error> {
+        let data = self.broker.retrieve_data(
+            strategy.contract_name.clone(),
+            strategy.bar_size,
+            strategy.start_date,
+            strategy.end_date)?;
+        let mut indicators = Vec::new();
+        for metric in &strategy.context {
+            indicators.push((metric.clone(), metric.values(&data)?));
+        }
+        let mut logic = (strategy.logic)();
+        logic.on_start()?;
+        let mut portfolio = Portfolio::new(self.initial_cash);
+        self.fill_model.reset();
+        let signals = portfolio.run_logic(&strategy.contract_name, &data, &indicators, logic.as_mut(), self.fill_model.as_mut())?;
+        let mut result = BacktestResult::new(signals, portfolio);
+        result.measure(&strategy.measures, self.risk_free_rate);
+        logic.on_finish(&result)?;
+        Ok(result)
+    }
+}
@@ -6,12 +6,13 @@ use ordered_float::OrderedFloat;
 use time::macros::datetime;
 use backtesting::*;
 use fq_data_broker::{DataBroker, HashedBarSize};
+use ibapi_handler::IbapiConfig;
 
-fn test_buy_signal(prices: &Vec<OrderedFloat<f64>>, index: u32) -> Result<bool, Error> {
+fn test_buy_signal(prices: &[OrderedFloat<f64>], index: u32) -> Result<bool, Error> {
     Ok(true)
 }
 
-fn test_sell_signal(prices: &Vec<OrderedFloat<f64>>, index: u32) -> Result<bool, Error> {
+fn test_sell_signal(prices: &[OrderedFloat<f64>], index: u32) -> Result<bool, Error> {
     Ok(false)
 }
 
@@ -32,7 +33,7 @@ fn main() {
     let start_date = datetime!(2021-01-01 00:00:00 UTC);
     let end_date = datetime!(2021-12-31 23:59:59 UTC);
     let bar_size = HashedBarSize::Min15;
-    let mut data_broker = DataBroker::new(None).unwrap();
+    let mut data_broker = DataBroker::new(None, IbapiConfig::from_env().unwrap()).unwrap();
     let data = data_broker.retrieve_data(
         chosen_security,
         bar_size,
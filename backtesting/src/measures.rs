@@ -0,0 +1,206 @@
+use crate::portfolio::{BacktestResult, EquityPoint, Trade};
+
+const SECONDS_PER_YEAR: f64 = 365.25 * 86400.0;
+
+/// Performance statistics of a backtest, computed from its equity curve and trades.
+/// Returns, ratios and rates are fractions (0.05 is 5%).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BacktestingMeasure {
+    /// Final equity minus the initial cash.
+    NetProfit,
+    /// Net profit over the initial cash.
+    TotalReturn,
+    /// The total return compounded per year of the backtest's calendar time.
+    AnnualizedReturn,
+    /// Mean excess return per bar over its standard deviation, annualized.
+    SharpeRatio,
+    /// Like Sharpe, with the downside deviation (returns below the risk-free rate) instead.
+    SortinoRatio,
+    /// Annualized return over the maximum drawdown.
+    CalmarRatio,
+    /// The largest fall of equity from a previous high, as a fraction of that high.
+    MaxDrawdown,
+    /// The longest time in days equity spent below a previous high.
+    MaxDrawdownDuration,
+    /// The share of closing trades that made money.
+    WinRate,
+    /// What the winning closing trades made over what the losing ones lost.
+    ProfitFactor,
+    /// The average P&L of a closing trade.
+    Expectancy,
+    /// The share of bars a position was held at the close.
+    Exposure,
+    /// The value traded over the average equity.
+    Turnover,
+    /// The measure from before there were several risk adjusted ones, computed as SharpeRatio.
+    /// Not in ALL, so it is not reported twice.
+    #[deprecated(note = "use SharpeRatio, or SortinoRatio / CalmarRatio")]
+    RiskAdjustedReturn,
+}
+
+impl BacktestingMeasure {
+    pub const ALL: [BacktestingMeasure; 13] = [
+        BacktestingMeasure::NetProfit, BacktestingMeasure::TotalReturn, BacktestingMeasure::AnnualizedReturn,
+        BacktestingMeasure::SharpeRatio, BacktestingMeasure::SortinoRatio, BacktestingMeasure::CalmarRatio,
+        BacktestingMeasure::MaxDrawdown, BacktestingMeasure::MaxDrawdownDuration, BacktestingMeasure::WinRate,
+        BacktestingMeasure::ProfitFactor, BacktestingMeasure::Expectancy, BacktestingMeasure::Exposure,
+        BacktestingMeasure::Turnover,
+    ];
+
+    /// The measure for a backtest, with `risk_free_rate` a yearly rate for Sharpe and Sortino.
+    /// None where it is undefined, e.g. ratios without any variation or trade statistics without closing trades.
+    pub fn compute(&self, result: &BacktestResult, risk_free_rate: f64) -> Option<f64> {
+        let curve = &result.equity_curve;
+        match self {
+            BacktestingMeasure::NetProfit => Some(result.net_profit()),
+            BacktestingMeasure::TotalReturn => finite(result.total_return()),
+            BacktestingMeasure::AnnualizedReturn => annualized_return(result.initial_cash, curve),
+            BacktestingMeasure::SharpeRatio => sharpe_ratio(result.initial_cash, curve, risk_free_rate),
+            BacktestingMeasure::SortinoRatio => sortino_ratio(result.initial_cash, curve, risk_free_rate),
+            BacktestingMeasure::CalmarRatio => {
+                let drawdown = max_drawdown(curve);
+                if drawdown > 0.0 { finite(annualized_return(result.initial_cash, curve)? / drawdown) } else { None }
+            },
+            BacktestingMeasure::MaxDrawdown => Some(max_drawdown(curve)),
+            BacktestingMeasure::MaxDrawdownDuration => Some(max_drawdown_duration(curve) as f64 / 86400.0),
+            BacktestingMeasure::WinRate => win_rate(&result.trades),
+            BacktestingMeasure::ProfitFactor => profit_factor(&result.trades),
+            BacktestingMeasure::Expectancy => expectancy(&result.trades),
+            BacktestingMeasure::Exposure => exposure(curve),
+            BacktestingMeasure::Turnover => turnover(&result.trades, curve),
+            #[allow(deprecated)]
+            BacktestingMeasure::RiskAdjustedReturn => sharpe_ratio(result.initial_cash, curve, risk_free_rate),
+        }
+    }
+}
+
+fn finite(value: f64) -> Option<f64> {
+    value.is_finite().then_some(value)
+}
+
+/// The return of every bar, the first one relative to the initial cash.
+pub fn returns(initial_cash: f64, curve: &[EquityPoint]) -> Vec<f64> {
+    let mut previous = initial_cash;
+    curve.iter().map(|point| {
+        let change = point.equity / previous - 1.0;
+        previous = point.equity;
+        change
+    }).collect()
+}
+
+/// The calendar time the curve covers, in years.
+fn years(curve: &[EquityPoint]) -> Option<f64> {
+    let (first, last) = (curve.first()?, curve.last()?);
+    let years = (last.timestamp - first.timestamp) as f64 / SECONDS_PER_YEAR;
+    (years > 0.0).then_some(years)
+}
+
+pub fn annualized_return(initial_cash: f64, curve: &[EquityPoint]) -> Option<f64> {
+    let growth = curve.last()?.equity / initial_cash;
+    if growth <= 0.0 {
+        return Some(-1.0);
+    }
+    finite(growth.powf(1.0 / years(curve)?) - 1.0)
+}
+
+/// How many bars the curve has per year, to annualize per-bar statistics whatever the bar size.
+fn periods_per_year(curve: &[EquityPoint]) -> Option<f64> {
+    let years = years(curve)?;
+    Some((curve.len() - 1) as f64 / years)
+}
+
+/// Per-bar returns in excess of the risk-free rate, and how many bars make a year.
+fn excess_returns(initial_cash: f64, curve: &[EquityPoint], risk_free_rate: f64) -> Option<(Vec<f64>, f64)> {
+    let periods = periods_per_year(curve)?;
+    let risk_free = (1.0 + risk_free_rate).powf(1.0 / periods) - 1.0;
+    let excess: Vec<f64> = returns(initial_cash, curve).iter().map(|r| r - risk_free).collect();
+    (excess.len() >= 2).then_some((excess, periods))
+}
+
+pub fn sharpe_ratio(initial_cash: f64, curve: &[EquityPoint], risk_free_rate: f64) -> Option<f64> {
+    let (excess, periods) = excess_returns(initial_cash, curve, risk_free_rate)?;
+    let mean = excess.iter().sum::<f64>() / excess.len() as f64;
+    let deviation = (excess.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (excess.len() - 1) as f64).sqrt();
+    if deviation == 0.0 { None } else { finite(mean / deviation * periods.sqrt()) }
+}
+
+pub fn sortino_ratio(initial_cash: f64, curve: &[EquityPoint], risk_free_rate: f64) -> Option<f64> {
+    let (excess, periods) = excess_returns(initial_cash, curve, risk_free_rate)?;
+    let mean = excess.iter().sum::<f64>() / excess.len() as f64;
+    let downside = (excess.iter().map(|r| r.min(0.0).powi(2)).sum::<f64>() / excess.len() as f64).sqrt();
+    if downside == 0.0 { None } else { finite(mean / downside * periods.sqrt()) }
+}
+
+/// The largest fall from a high of the curve, as a fraction of the high (0 if it never fell).
+pub fn max_drawdown(curve: &[EquityPoint]) -> f64 {
+    let mut high = f64::MIN;
+    let mut drawdown: f64 = 0.0;
+    for point in curve {
+        high = high.max(point.equity);
+        if high > 0.0 {
+            drawdown = drawdown.max((high - point.equity) / high);
+        }
+    }
+    drawdown
+}
+
+/// The longest time in seconds from a high of the curve until it was reached again (or the curve ended).
+pub fn max_drawdown_duration(curve: &[EquityPoint]) -> i64 {
+    let mut longest = 0;
+    let mut high: Option<(f64, i64)> = None;
+    let mut below = false;
+    for point in curve {
+        match high {
+            Some((equity, since)) if point.equity < equity || below => {
+                longest = longest.max(point.timestamp - since);
+                below = point.equity < equity;
+                if !below {
+                    high = Some((point.equity, point.timestamp));
+                }
+            },
+            _ => high = Some((point.equity, point.timestamp)),
+        }
+    }
+    longest
+}
+
+/// The P&L of every trade that closed (part of) a position, net of its own commission.
+fn closing_pnl(trades: &[Trade]) -> Vec<f64> {
+    trades.iter().filter(|trade| trade.closed_quantity > 0.0).map(|trade| trade.realized_pnl - trade.fill.commission).collect()
+}
+
+pub fn win_rate(trades: &[Trade]) -> Option<f64> {
+    let pnl = closing_pnl(trades);
+    if pnl.is_empty() {
+        return None;
+    }
+    Some(pnl.iter().filter(|pnl| **pnl > 0.0).count() as f64 / pnl.len() as f64)
+}
+
+pub fn profit_factor(trades: &[Trade]) -> Option<f64> {
+    let pnl = closing_pnl(trades);
+    let won: f64 = pnl.iter().filter(|pnl| **pnl > 0.0).sum();
+    let lost: f64 = -pnl.iter().filter(|pnl| **pnl < 0.0).sum::<f64>();
+    if lost == 0.0 { None } else { Some(won / lost) }
+}
+
+pub fn expectancy(trades: &[Trade]) -> Option<f64> {
+    let pnl = closing_pnl(trades);
+    if pnl.is_empty() { None } else { Some(pnl.iter().sum::<f64>() / pnl.len() as f64) }
+}
+
+pub fn exposure(curve: &[EquityPoint]) -> Option<f64> {
+    if curve.is_empty() {
+        return None;
+    }
+    Some(curve.iter().filter(|point| point.market_value != 0.0).count() as f64 / curve.len() as f64)
+}
+
+pub fn turnover(trades: &[Trade], curve: &[EquityPoint]) -> Option<f64> {
+    if curve.is_empty() {
+        return None;
+    }
+    let traded: f64 = trades.iter().map(|trade| (trade.fill.quantity * trade.fill.price).abs()).sum();
+    let average_equity = curve.iter().map(|point| point.equity).sum::<f64>() / curve.len() as f64;
+    if average_equity <= 0.0 { None } else { Some(traded / average_equity) }
+}
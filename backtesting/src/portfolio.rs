@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use anyhow::{bail, Error};
+use ordered_float::OrderedFloat;
+use ibapi_handler::IBApiBar;
+use crate::fills::{FillModel, Order, SimulatedFills};
+use crate::strategy::{BarContext, StrategyLogic};
+use crate::{BacktestingMeasure, BacktestingMetric, Signal};
+
+/// An executed trade: a positive quantity buys, a negative one sells.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    pub timestamp: i64,
+    pub identifier: String,
+    pub quantity: f64,
+    pub price: f64,
+    pub commission: f64,
+}
+
+/// A fill and the P&L it realized by reducing a position (before commission).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub fill: Fill,
+    pub realized_pnl: f64,
+    /// How much of the position the fill closed, 0 for fills that only opened or added to one.
+    pub closed_quantity: f64,
+}
+
+/// What is held of one contract. The quantity is negative for short positions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Position {
+    pub quantity: f64,
+    pub average_cost: f64,
+    /// P&L realized so far, net of commissions.
+    pub realized_pnl: f64,
+    /// The price the position was last marked at.
+    pub last_price: f64,
+}
+
+impl Position {
+    pub fn market_value(&self) -> f64 {
+        self.quantity * self.last_price
+    }
+
+    pub fn unrealized_pnl(&self) -> f64 {
+        self.quantity * (self.last_price - self.average_cost)
+    }
+}
+
+/// The value of a portfolio at the close of a bar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EquityPoint {
+    pub timestamp: i64,
+    pub cash: f64,
+    pub market_value: f64,
+    pub equity: f64,
+}
+
+/// Cash and positions, updated fill by fill and marked to market bar by bar.
+/// Long only by default: sells close at most what is held. Buys never spend more than the cash there is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Portfolio {
+    initial_cash: f64,
+    cash: f64,
+    allow_short: bool,
+    positions: HashMap<String, Position>,
+    trades: Vec<Trade>,
+    equity_curve: Vec<EquityPoint>,
+}
+
+impl Portfolio {
+    pub fn new(initial_cash: f64) -> Self {
+        Portfolio {
+            initial_cash,
+            cash: initial_cash,
+            allow_short: false,
+            positions: HashMap::new(),
+            trades: Vec::new(),
+            equity_curve: Vec::new(),
+        }
+    }
+
+    /// Lets sells open short positions.
+    pub fn with_short_selling(mut self, allow_short: bool) -> Self {
+        self.allow_short = allow_short;
+        self
+    }
+
+    pub fn initial_cash(&self) -> f64 {
+        self.initial_cash
+    }
+
+    pub fn cash(&self) -> f64 {
+        self.cash
+    }
+
+    pub fn positions(&self) -> &HashMap<String, Position> {
+        &self.positions
+    }
+
+    pub fn position(&self, identifier: &str) -> Option<&Position> {
+        self.positions.get(identifier)
+    }
+
+    pub fn trades(&self) -> &Vec<Trade> {
+        &self.trades
+    }
+
+    pub fn equity_curve(&self) -> &Vec<EquityPoint> {
+        &self.equity_curve
+    }
+
+    pub fn market_value(&self) -> f64 {
+        self.positions.values().map(|position| position.market_value()).sum()
+    }
+
+    /// Cash plus the positions at their last prices.
+    pub fn equity(&self) -> f64 {
+        self.cash + self.market_value()
+    }
+
+    pub fn realized_pnl(&self) -> f64 {
+        self.positions.values().map(|position| position.realized_pnl).sum()
+    }
+
+    pub fn unrealized_pnl(&self) -> f64 {
+        self.positions.values().map(|position| position.unrealized_pnl()).sum()
+    }
+
+    /// How much of an order for `quantity` at `price` the portfolio can take: buys are cut down to what the cash
+    /// pays for (including `commission`) and, unless short selling is allowed, sells to the position held.
+    /// Whole units only.
+    pub fn allowed_quantity(&self, identifier: &str, quantity: f64, price: f64, commission: f64) -> f64 {
+        let held = self.position(identifier).map_or(0.0, |position| position.quantity);
+        if quantity > 0.0 {
+            quantity.min(((self.cash - commission) / price).max(0.0).floor())
+        } else if !self.allow_short {
+            quantity.max(-held.max(0.0))
+        } else {
+            quantity
+        }
+    }
+
+    /// Turns a buy or sell signal into a fill at its price and applies as much of it as allowed_quantity lets through.
+    /// Returns the trade made, None for holds and orders that could not be filled at all.
+    pub fn apply_signal(&mut self, signal: &Signal) -> Result<Option<&Trade>, Error> {
+        let order = match Order::from_signal(signal) {
+            Some(order) => order,
+            None => return Ok(None),
+        };
+        let quantity = self.allowed_quantity(&order.identifier, order.quantity, order.price, 0.0);
+        if quantity == 0.0 {
+            return Ok(None);
+        }
+        self.apply_fill(Fill { timestamp: order.timestamp, identifier: order.identifier, quantity, price: order.price, commission: 0.0 })
+            .map(Some)
+    }
+
+    /// Books a fill as it is: moves the cash, updates the position's average cost and realizes the P&L of
+    /// whatever part of it reduces the position.
+    pub fn apply_fill(&mut self, fill: Fill) -> Result<&Trade, Error> {
+        if !fill.quantity.is_finite() || !fill.price.is_finite() || fill.quantity == 0.0 {
+            bail!("Invalid fill of {} at {}", fill.quantity, fill.price);
+        }
+        let position = self.positions.entry(fill.identifier.clone()).or_default();
+        let mut realized = 0.0;
+        let mut closed = 0.0;
+        if position.quantity == 0.0 || position.quantity.signum() == fill.quantity.signum() {
+            let quantity = position.quantity + fill.quantity;
+            position.average_cost = (position.average_cost * position.quantity + fill.price * fill.quantity) / quantity;
+            position.quantity = quantity;
+        } else {
+            closed = fill.quantity.abs().min(position.quantity.abs());
+            realized = closed * (fill.price - position.average_cost) * position.quantity.signum();
+            let quantity = position.quantity + fill.quantity;
+            if quantity == 0.0 {
+                position.average_cost = 0.0;
+            } else if quantity.signum() != position.quantity.signum() {
+                // went through zero, the rest opens a position the other way
+                position.average_cost = fill.price;
+            }
+            position.quantity = quantity;
+        }
+        position.realized_pnl += realized - fill.commission;
+        position.last_price = fill.price;
+        self.cash -= fill.quantity * fill.price + fill.commission;
+        self.trades.push(Trade { fill, realized_pnl: realized, closed_quantity: closed });
+        Ok(&self.trades[self.trades.len() - 1])
+    }
+
+    /// Marks the position in `identifier` to `price`.
+    pub fn mark(&mut self, identifier: &str, price: f64) {
+        if let Some(position) = self.positions.get_mut(identifier) {
+            position.last_price = price;
+        }
+    }
+
+    /// Adds the portfolio's current value to the equity curve.
+    pub fn record(&mut self, timestamp: i64) {
+        let market_value = self.market_value();
+        self.equity_curve.push(EquityPoint { timestamp, cash: self.cash, market_value, equity: self.cash + market_value });
+    }
+
+    /// Goes through the bars of `identifier` in order, acting on the signals dated up to each bar
+    /// (in time order, ties in the order given) and marking the portfolio to the bar's close afterwards.
+    /// Orders are filled whole at their signal's price, see run_with for other fills.
+    pub fn run(&mut self, identifier: &str, data: &[IBApiBar], signals: &[Signal]) -> Result<(), Error> {
+        self.run_with(identifier, data, signals, &mut SimulatedFills::at_signal_close())
+    }
+
+    /// Like run, with `fills` deciding how the orders of the signals are filled. Orders it fills on the next bar
+    /// are filled before that bar's own signals are looked at; those of the last bar are never filled.
+    pub fn run_with(&mut self, identifier: &str, data: &[IBApiBar], signals: &[Signal], fills: &mut dyn FillModel) -> Result<(), Error> {
+        let mut signals: Vec<&Signal> = signals.iter().collect();
+        signals.sort_by_key(|signal| signal.timestamp);
+        let mut replay = Replay { signals, next: 0 };
+        self.run_logic(identifier, data, &[], &mut replay, fills)?;
+        Ok(())
+    }
+
+    /// Like run_with, with the signals coming from `logic` as it goes: its on_bar is called at every bar's close with
+    /// the values of `indicators` so far, and its on_fill after every fill. Returns the signals it gave.
+    pub fn run_logic(
+        &mut self,
+        identifier: &str,
+        data: &[IBApiBar],
+        indicators: &[(BacktestingMetric, Vec<OrderedFloat<f64>>)],
+        logic: &mut dyn StrategyLogic,
+        fills: &mut dyn FillModel,
+    ) -> Result<Vec<Signal>, Error> {
+        let mut given = Vec::new();
+        let mut pending: Vec<Order> = Vec::new();
+        for (index, bar) in data.iter().enumerate() {
+            for order in std::mem::take(&mut pending) {
+                self.fill_order(&order, bar, fills, logic)?;
+            }
+            let signals = logic.on_bar(&BarContext::new(index, identifier, data, indicators, self))?;
+            for signal in signals {
+                if let Some(order) = Order::from_signal(&signal) {
+                    if fills.next_bar() {
+                        pending.push(order);
+                    } else {
+                        self.fill_order(&order, bar, fills, logic)?;
+                    }
+                }
+                given.push(signal);
+            }
+            self.mark(identifier, bar.close.into_inner());
+            self.record(bar.date);
+        }
+        Ok(given)
+    }
+
+    fn fill_order(&mut self, order: &Order, bar: &IBApiBar, fills: &mut dyn FillModel, logic: &mut dyn StrategyLogic) -> Result<(), Error> {
+        if let Some(fill) = fills.fill(order, bar, self) {
+            self.apply_fill(fill)?;
+            logic.on_fill(&self.trades[self.trades.len() - 1], self)?;
+        }
+        Ok(())
+    }
+}
+
+// the logic of run_with: hands out the signals dated up to each bar
+struct Replay<'a> {
+    signals: Vec<&'a Signal>,
+    next: usize,
+}
+
+impl StrategyLogic for Replay<'_> {
+    fn on_bar(&mut self, context: &BarContext) -> Result<Vec<Signal>, Error> {
+        let mut signals = Vec::new();
+        while self.next < self.signals.len() && self.signals[self.next].timestamp <= context.bar().date {
+            signals.push(self.signals[self.next].clone());
+            self.next += 1;
+        }
+        Ok(signals)
+    }
+}
+
+/// What running a strategy over its data came to: the signals it gave,
+/// the trades they led to, the portfolio at the end and the measures the strategy asked for.
+#[derive(Debug, Clone)]
+pub struct BacktestResult {
+    /// Every signal the strategy gave, in order.
+    pub signals: Vec<Signal>,
+    pub initial_cash: f64,
+    pub cash: f64,
+    pub positions: HashMap<String, Position>,
+    pub trades: Vec<Trade>,
+    /// The portfolio's value at the close of every bar.
+    pub equity_curve: Vec<EquityPoint>,
+    /// None where a measure is undefined, see BacktestingMeasure::compute.
+    pub measures: HashMap<BacktestingMeasure, Option<f64>>,
+}
+
+impl BacktestResult {
+    pub fn new(signals: Vec<Signal>, portfolio: Portfolio) -> Self {
+        BacktestResult {
+            signals,
+            initial_cash: portfolio.initial_cash,
+            cash: portfolio.cash,
+            positions: portfolio.positions,
+            trades: portfolio.trades,
+            equity_curve: portfolio.equity_curve,
+            measures: HashMap::new(),
+        }
+    }
+
+    /// Computes `measures` and keeps them in `self.measures`.
+    pub fn measure(&mut self, measures: &[BacktestingMeasure], risk_free_rate: f64) {
+        for measure in measures {
+            let value = measure.compute(self, risk_free_rate);
+            self.measures.insert(*measure, value);
+        }
+    }
+
+    /// Cash plus open positions at their last prices.
+    pub fn final_equity(&self) -> f64 {
+        self.cash + self.positions.values().map(|position| position.market_value()).sum::<f64>()
+    }
+
+    pub fn realized_pnl(&self) -> f64 {
+        self.positions.values().map(|position| position.realized_pnl).sum()
+    }
+
+    pub fn unrealized_pnl(&self) -> f64 {
+        self.positions.values().map(|position| position.unrealized_pnl()).sum()
+    }
+
+    /// Final equity minus the initial cash, i.e. realized plus unrealized P&L net of commissions.
+    pub fn net_profit(&self) -> f64 {
+        self.final_equity() - self.initial_cash
+    }
+
+    /// Net profit as a fraction of the initial cash.
+    pub fn total_return(&self) -> f64 {
+        self.net_profit() / self.initial_cash
+    }
+}
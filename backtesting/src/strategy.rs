@@ -0,0 +1,176 @@
+use std::sync::Arc;
+use anyhow::{bail, Error};
+use ordered_float::OrderedFloat;
+use ibapi_handler::IBApiBar;
+use crate::portfolio::{BacktestResult, Portfolio, Trade};
+use crate::{BacktestingMetric, Signal, SignalType, IGNORE_SENTINEL};
+
+// a function that takes the values of a metric up to the current bar and decides on a signal,
+// the u32 is the current index in the data (the last value's).
+// Closures can capture their parameters, e.g. `move |values, i| Ok(values[i as usize] > threshold)`.
+pub type MetricsMapFn = Arc<dyn Fn(&[OrderedFloat<f64>], u32) -> Result<bool, Error> + Send + Sync>;
+
+// called when a buy or sell is filled
+pub type ActionFn = Arc<dyn Fn() -> Result<(), Error> + Send + Sync>;
+
+/// What a strategy decides on while it is run, bar by bar. Implementations can keep whatever state they need
+/// between calls; BacktestExecutor::run starts each run with a fresh clone of the strategy's logic.
+pub trait StrategyLogic: Send {
+    /// Called before the first bar of a run.
+    fn on_start(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called at the close of every bar, after the fills of the orders waiting for it.
+    /// Returns the signals to act on, in order.
+    fn on_bar(&mut self, context: &BarContext) -> Result<Vec<Signal>, Error>;
+
+    /// Called after every fill, with the trade it made and the portfolio it was booked in.
+    fn on_fill(&mut self, _trade: &Trade, _portfolio: &Portfolio) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called with the result of a run, its measures included.
+    fn on_finish(&mut self, _result: &BacktestResult) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Closures of a bar can be strategies of their own, e.g. ones keeping a counter they capture.
+impl<F> StrategyLogic for F
+where
+    F: FnMut(&BarContext) -> Result<Vec<Signal>, Error> + Send,
+{
+    fn on_bar(&mut self, context: &BarContext) -> Result<Vec<Signal>, Error> {
+        self(context)
+    }
+}
+
+/// The bar a strategy is deciding on and what is known at its close: the bars so far,
+/// the values of the strategy's metrics so far and the portfolio.
+pub struct BarContext<'a> {
+    index: usize,
+    identifier: &'a str,
+    bars: &'a [IBApiBar],
+    indicators: &'a [(BacktestingMetric, Vec<OrderedFloat<f64>>)],
+    portfolio: &'a Portfolio,
+}
+
+impl<'a> BarContext<'a> {
+    /// `indicators` are the values of every metric over all of `bars`, only those up to `index` are handed out.
+    pub fn new(
+        index: usize,
+        identifier: &'a str,
+        bars: &'a [IBApiBar],
+        indicators: &'a [(BacktestingMetric, Vec<OrderedFloat<f64>>)],
+        portfolio: &'a Portfolio,
+    ) -> Self {
+        BarContext { index, identifier, bars, indicators, portfolio }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn identifier(&self) -> &str {
+        self.identifier
+    }
+
+    pub fn bar(&self) -> &IBApiBar {
+        &self.bars[self.index]
+    }
+
+    /// The bars up to and including this one.
+    pub fn bars(&self) -> &[IBApiBar] {
+        &self.bars[..=self.index]
+    }
+
+    /// The metrics the strategy was run with, in its order.
+    pub fn metrics(&self) -> impl Iterator<Item = &BacktestingMetric> {
+        self.indicators.iter().map(|(metric, _)| metric)
+    }
+
+    /// The values of `metric` up to and including this bar, IGNORE_SENTINEL while it warms up.
+    /// None if the strategy was not run with it.
+    pub fn values(&self, metric: &BacktestingMetric) -> Option<&[OrderedFloat<f64>]> {
+        self.all_values(metric).map(|values| &values[..=self.index])
+    }
+
+    /// The value of `metric` at this bar, None while it warms up or if the strategy was not run with it.
+    pub fn value(&self, metric: &BacktestingMetric) -> Option<f64> {
+        let value = *self.values(metric)?.last()?;
+        (value != IGNORE_SENTINEL).then(|| value.into_inner())
+    }
+
+    pub fn portfolio(&self) -> &Portfolio {
+        self.portfolio
+    }
+
+    /// The quantity held of the contract, negative when short.
+    pub fn position(&self) -> f64 {
+        self.portfolio.position(self.identifier).map_or(0.0, |position| position.quantity)
+    }
+
+    /// A signal to buy `quantity` at this bar's close.
+    pub fn buy(&self, quantity: i32) -> Signal {
+        self.signal(SignalType::Buy, Some(quantity))
+    }
+
+    /// A signal to sell `quantity` at this bar's close.
+    pub fn sell(&self, quantity: i32) -> Signal {
+        self.signal(SignalType::Sell, Some(quantity))
+    }
+
+    fn signal(&self, signal_type: SignalType, quantity: Option<i32>) -> Signal {
+        let bar = self.bar();
+        Signal::new(signal_type, bar.date, bar.close, &self.identifier.to_string(), quantity, None)
+    }
+
+    // every value of the metric, including those after this bar
+    fn all_values(&self, metric: &BacktestingMetric) -> Option<&Vec<OrderedFloat<f64>>> {
+        self.indicators.iter().find(|(m, _)| m == metric).map(|(_, values)| values)
+    }
+}
+
+/// The logic of Strategy::new: every metric that is past its warm-up gives a buy, sell or hold signal on every bar,
+/// decided by the same buy and sell functions, and on_buy / on_sell are called as buys and sells are filled.
+#[derive(Clone)]
+pub struct SignalRules {
+    pub buy_signal: MetricsMapFn,
+    pub sell_signal: MetricsMapFn,
+    pub on_buy: ActionFn,
+    pub on_sell: ActionFn,
+}
+
+impl SignalRules {
+    /// The signal for the last of a metric's `values`, as BacktestingMetric::generate_signals gives it.
+    pub(crate) fn signal_type(&self, values: &[OrderedFloat<f64>]) -> Result<SignalType, Error> {
+        let index = values.len() as u32 - 1;
+        let buy = (self.buy_signal)(values, index)?;
+        let sell = (self.sell_signal)(values, index)?;
+        if buy && sell {
+            bail!("Buy and sell signals generated at the same time");
+        }
+        Ok(if buy { SignalType::Buy } else if sell { SignalType::Sell } else { SignalType::Hold })
+    }
+}
+
+impl StrategyLogic for SignalRules {
+    fn on_bar(&mut self, context: &BarContext) -> Result<Vec<Signal>, Error> {
+        let mut signals = Vec::new();
+        for metric in context.metrics() {
+            // only what is known at this bar is handed to the functions
+            let values = match context.values(metric) {
+                Some(values) if values.last() != Some(&IGNORE_SENTINEL) => values,
+                _ => continue,
+            };
+            let signal_type = self.signal_type(values)?;
+            signals.push(context.signal(signal_type, None));
+        }
+        Ok(signals)
+    }
+
+    fn on_fill(&mut self, trade: &Trade, _portfolio: &Portfolio) -> Result<(), Error> {
+        if trade.fill.quantity > 0.0 { (self.on_buy)() } else { (self.on_sell)() }
+    }
+}
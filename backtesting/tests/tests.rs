@@ -1,3 +1,38 @@
+/// What the test modules below share: brokers and executors over synthetic data, and signal functions.
+#[cfg(test)]
+mod fixtures {
+    use anyhow::Error;
+    use ordered_float::OrderedFloat;
+    use backtesting::BacktestExecutor;
+    use fq_data_broker::{DataBroker, PriceModel, SyntheticSource};
+
+    /// A broker caching in a fresh temp directory, serving made up AAPL-like bars instead of TWS data.
+    pub fn synthetic_broker(name: &str) -> DataBroker {
+        let storage = std::env::temp_dir().join(format!("fq_backtesting_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&storage);
+        let source = SyntheticSource::new(42, PriceModel::GeometricBrownian { drift: 0.1, volatility: 0.25 })
+            .with_initial_price(130.0);
+        DataBroker::with_source(Some(storage.to_str().unwrap().to_string()), Box::new(source)).unwrap()
+    }
+
+    /// An executor over a synthetic_broker, starting with 10,000 in cash.
+    pub fn executor(name: &str) -> BacktestExecutor {
+        BacktestExecutor::with_broker(synthetic_broker(name)).with_initial_cash(10_000.0)
+    }
+
+    pub fn buy_every_tenth(_values: &[OrderedFloat<f64>], index: u32) -> Result<bool, Error> {
+        Ok(index % 10 == 0)
+    }
+
+    pub fn sell_later(_values: &[OrderedFloat<f64>], index: u32) -> Result<bool, Error> {
+        Ok(index % 10 == 5)
+    }
+
+    pub fn nothing() -> Result<(), Error> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Error;
@@ -5,19 +40,15 @@ mod tests {
     use ibapi::market_data::historical::BarSize;
     use ordered_float::OrderedFloat;
     use time::macros::datetime;
-    use backtesting::{BacktestingMeasure, BacktestingMetric, Strategy};
-    use fq_data_broker::{DataBroker, HashedBarSize};
-    fn test_setup() {
-        // move working directory up one
-        let res = std::env::set_current_dir("..");
-        assert!(res.is_ok());
-    }
+    use backtesting::{BacktestExecutor, BacktestingMeasure, BacktestingMetric, SignalType, Strategy};
+    use fq_data_broker::HashedBarSize;
+    use crate::fixtures::synthetic_broker;
 
-    fn test_buy_signal(prices: &Vec<OrderedFloat<f64>>, index: u32) -> Result<bool, Error> {
+    fn test_buy_signal(prices: &[OrderedFloat<f64>], index: u32) -> Result<bool, Error> {
         Ok(true)
     }
 
-    fn test_sell_signal(prices: &Vec<OrderedFloat<f64>>, index: u32) -> Result<bool, Error> {
+    fn test_sell_signal(prices: &[OrderedFloat<f64>], index: u32) -> Result<bool, Error> {
         Ok(false)
     }
 
@@ -33,14 +64,13 @@ mod tests {
 
     #[test]
     pub fn one_year_15min_test() {
-        test_setup();
         let chosen_security = "AAPL".to_string();
         let metric = BacktestingMetric::SMA(50); // 50 day SMA for AAPL
         let measure = BacktestingMeasure::NetProfit;
         let start_date = datetime!(2021-01-01 00:00:00 UTC);
         let end_date = datetime!(2021-12-31 23:59:59 UTC);
         let bar_size = HashedBarSize::Min15;
-        let mut data_broker = DataBroker::new(Some("@test_data".to_string())).unwrap();
+        let mut data_broker = synthetic_broker("one_year_15min");
         let data = data_broker.retrieve_data(
             chosen_security,
             bar_size,
@@ -99,4 +129,753 @@ mod tests {
             println!("{:?}", signal);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn executor_test() {
+        let start_date = datetime!(2021-01-04 00:00:00 UTC);
+        let end_date = datetime!(2021-03-31 23:59:59 UTC);
+        let mut executor = BacktestExecutor::with_broker(synthetic_broker("executor"));
+        let strat = Strategy::new(
+            vec![BacktestingMetric::SMA(20)],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            test_buy_signal,
+            test_sell_signal,
+            start_date,
+            end_date,
+            test_on_buy,
+            test_on_sell,
+        );
+        let results = executor.execute(vec![strat.clone()]).unwrap();
+        let signals = results.get(&strat).unwrap().as_ref().unwrap();
+        let sma20 = signals.get(&BacktestingMetric::SMA(20)).unwrap().as_ref().unwrap();
+        // the synthetic source trades on weekdays, 63 of them from January 4 to March 31:
+        // one signal per day from the 20th (January 29), when the 20 day window is filled
+        assert_eq!(sma20.len(), 44);
+        assert_eq!(sma20[0].timestamp(), datetime!(2021-01-29 00:00:00 UTC).unix_timestamp());
+        assert_eq!(sma20[43].timestamp(), datetime!(2021-03-31 00:00:00 UTC).unix_timestamp());
+        // test_buy_signal always buys
+        assert!(sma20.iter().all(|signal| *signal.signal_type() == SignalType::Buy));
+    }
+}
+
+#[cfg(test)]
+mod metric_tests {
+    use ordered_float::OrderedFloat;
+    use backtesting::{BacktestingMetric, IGNORE_SENTINEL};
+    use ibapi_handler::IBApiBar;
+
+    fn bars(closes: &[f64]) -> Vec<IBApiBar> {
+        closes.iter().enumerate()
+            .map(|(i, close)| IBApiBar::builder().date(i as i64 * 86400).ohlc(*close, *close, *close, *close).volume(100.0 * (i + 1) as f64).count(1).wap(*close).build().unwrap())
+            .collect()
+    }
+
+    /// Compares the defined values of a metric with `expected`, which starts at the first one.
+    fn assert_values(values: &[OrderedFloat<f64>], warm_up: usize, expected: &[f64], tolerance: f64) {
+        assert!(values[..warm_up].iter().all(|value| *value == IGNORE_SENTINEL), "warm-up of {} expected", warm_up);
+        for (i, expected) in expected.iter().enumerate() {
+            let value = values[warm_up + i].into_inner();
+            assert!((value - expected).abs() < tolerance, "value {} is {}, expected {}", warm_up + i, value, expected);
+        }
+    }
+
+    // the closes of StockCharts' EMA and RSI examples
+    const EMA_CLOSES: [f64; 30] = [
+        22.27, 22.19, 22.08, 22.17, 22.18, 22.13, 22.23, 22.43, 22.24, 22.29, 22.15, 22.39, 22.38, 22.61, 23.36,
+        24.05, 23.75, 23.83, 23.95, 23.63, 23.82, 23.87, 23.65, 23.19, 23.10, 23.33, 22.68, 23.10, 22.40, 22.17,
+    ];
+    const RSI_CLOSES: [f64; 33] = [
+        44.34, 44.09, 44.15, 43.61, 44.33, 44.83, 45.10, 45.42, 45.84, 46.08, 45.89, 46.03, 45.61, 46.28, 46.28,
+        46.00, 46.03, 46.41, 46.22, 45.64, 46.21, 46.25, 45.71, 46.45, 45.78, 45.35, 44.03, 44.18, 44.22, 44.57,
+        43.42, 42.66, 43.13,
+    ];
+
+    #[test]
+    fn sma_test() {
+        let values = BacktestingMetric::SMA(10).values(&bars(&EMA_CLOSES)).unwrap();
+        assert_values(&values, 9, &[22.221, 22.209, 22.229, 22.259, 22.303, 22.421, 22.613, 22.765, 22.905, 23.076, 23.210, 23.377], 1e-9);
+        assert!(BacktestingMetric::SMA(0).values(&bars(&EMA_CLOSES)).is_err());
+    }
+
+    #[test]
+    fn ema_test() {
+        let values = BacktestingMetric::EMA(10).values(&bars(&EMA_CLOSES)).unwrap();
+        assert_values(&values, 9, &[
+            22.22, 22.21, 22.24, 22.27, 22.33, 22.52, 22.80, 22.97, 23.13, 23.28, 23.34,
+            23.43, 23.51, 23.53, 23.47, 23.40, 23.39, 23.26, 23.23, 23.08, 22.92,
+        ], 0.006);
+    }
+
+    #[test]
+    fn rsi_test() {
+        let values = BacktestingMetric::RSI(14).values(&bars(&RSI_CLOSES)).unwrap();
+        assert_values(&values, 14, &[
+            70.46, 66.25, 66.48, 69.35, 66.29, 57.92, 62.88, 63.21, 56.01, 62.34,
+            54.67, 50.39, 40.02, 41.49, 41.90, 45.50, 37.32, 33.09, 37.79,
+        ], 0.006);
+
+        let rising = BacktestingMetric::RSI(3).values(&bars(&[1.0, 2.0, 3.0, 4.0, 5.0])).unwrap();
+        assert_eq!(rising[4], OrderedFloat(100.0));
+        let flat = BacktestingMetric::RSI(3).values(&bars(&[1.0, 1.0, 1.0, 1.0])).unwrap();
+        assert_eq!(flat[3], OrderedFloat(50.0));
+    }
+
+    #[test]
+    fn macd_test() {
+        use backtesting::indicators::macd;
+
+        // worked out by hand: EMA(2) and EMA(3) seeded with their simple averages
+        let closes = [10.0, 11.0, 12.0, 11.0, 13.0, 14.0, 13.0, 15.0];
+        let macd = macd(&closes, 2, 3, 2).unwrap();
+        assert_eq!(macd.line[1], None);
+        let line = [1.0 / 2.0, 1.0 / 6.0, 7.0 / 18.0, 25.0 / 54.0];
+        for (i, expected) in line.iter().enumerate() {
+            assert!((macd.line[i + 2].unwrap() - expected).abs() < 1e-12);
+        }
+        assert_eq!(macd.signal[2], None);
+        let signal = [1.0 / 3.0, 10.0 / 27.0, 35.0 / 81.0];
+        for (i, expected) in signal.iter().enumerate() {
+            assert!((macd.signal[i + 3].unwrap() - expected).abs() < 1e-12);
+        }
+
+        let histogram = BacktestingMetric::MACD { fast: 2, slow: 3, signal: 2 }.values(&bars(&closes)).unwrap();
+        assert_values(&histogram, 3, &[-1.0 / 6.0, 1.0 / 54.0, 5.0 / 162.0], 1e-12);
+        assert!(BacktestingMetric::MACD { fast: 26, slow: 12, signal: 9 }.values(&bars(&closes)).is_err());
+    }
+
+    #[test]
+    fn volatility_test() {
+        // log returns alternate between +ln(1.1) and -ln(1.1)
+        let closes = [100.0, 110.0, 100.0, 110.0, 100.0];
+        let per_bar = (2.0f64).sqrt() * (1.1f64).ln();
+        let values = BacktestingMetric::Volatility { window: 2, periods_per_year: 1 }.values(&bars(&closes)).unwrap();
+        assert_values(&values, 2, &[per_bar, per_bar, per_bar], 1e-12);
+        let annualized = BacktestingMetric::Volatility { window: 2, periods_per_year: 252 }.values(&bars(&closes)).unwrap();
+        assert_values(&annualized, 2, &[per_bar * (252.0f64).sqrt()], 1e-12);
+        assert!(BacktestingMetric::Volatility { window: 1, periods_per_year: 252 }.values(&bars(&closes)).is_err());
+    }
+
+    #[test]
+    fn dp_ratio_and_volume_test() {
+        let data = bars(&[50.0, 100.0, 200.0]);
+        let dp = BacktestingMetric::DpRatio(OrderedFloat(2.0)).values(&data).unwrap();
+        assert_eq!(dp, vec![OrderedFloat(0.04), OrderedFloat(0.02), OrderedFloat(0.01)]);
+        // a zero close would give infinity, which reads as a value still warming up
+        assert!(BacktestingMetric::DpRatio(OrderedFloat(2.0)).values(&bars(&[50.0, 0.0, 200.0])).is_err());
+        assert!(BacktestingMetric::DpRatio(OrderedFloat(2.0)).values(&bars(&[50.0, -1.0])).is_err());
+        let volume = BacktestingMetric::Volume(2).values(&data).unwrap();
+        assert_eq!(volume, vec![IGNORE_SENTINEL, OrderedFloat(150.0), OrderedFloat(250.0)]);
+        assert_eq!(BacktestingMetric::Volume(1).values(&data).unwrap()[0], OrderedFloat(100.0));
+    }
+
+    #[test]
+    fn streaming_test() {
+        // values computed on a prefix of the bars are the prefix of the values, nothing looks ahead
+        let data = bars(&RSI_CLOSES);
+        for metric in [
+            BacktestingMetric::SMA(5),
+            BacktestingMetric::EMA(5),
+            BacktestingMetric::RSI(14),
+            BacktestingMetric::MACD { fast: 3, slow: 6, signal: 4 },
+            BacktestingMetric::Volatility { window: 5, periods_per_year: 252 },
+            BacktestingMetric::Volume(3),
+        ] {
+            let all = metric.values(&data).unwrap();
+            for length in [1, 5, 15, 20, data.len()] {
+                assert_eq!(metric.values(&data[..length]).unwrap(), all[..length], "{:?} on {} bars", metric, length);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod portfolio_tests {
+    use ordered_float::OrderedFloat;
+    use time::macros::datetime;
+    use backtesting::{BacktestExecutor, BacktestingMeasure, BacktestingMetric, Fill, Portfolio, Signal, SignalType, Strategy};
+    use fq_data_broker::HashedBarSize;
+    use ibapi_handler::IBApiBar;
+    use crate::fixtures::{buy_every_tenth, nothing, sell_later, synthetic_broker};
+
+    fn fill(timestamp: i64, quantity: f64, price: f64) -> Fill {
+        Fill { timestamp, identifier: "AAPL".to_string(), quantity, price, commission: 0.0 }
+    }
+
+    fn signal(signal_type: SignalType, timestamp: i64, price: f64, quantity: i32) -> Signal {
+        Signal::new(signal_type, timestamp, OrderedFloat(price), &"AAPL".to_string(), Some(quantity), None)
+    }
+
+    #[test]
+    fn average_cost_and_pnl_test() {
+        let mut portfolio = Portfolio::new(10_000.0);
+        portfolio.apply_fill(fill(0, 10.0, 100.0)).unwrap();
+        portfolio.apply_fill(fill(1, 30.0, 120.0)).unwrap();
+        let position = portfolio.position("AAPL").unwrap();
+        assert_eq!(position.quantity, 40.0);
+        assert_eq!(position.average_cost, 115.0);
+        assert_eq!(portfolio.cash(), 10_000.0 - 1000.0 - 3600.0);
+
+        let trade = portfolio.apply_fill(Fill { commission: 5.0, ..fill(2, -15.0, 125.0) }).unwrap();
+        assert_eq!(trade.realized_pnl, 150.0);
+        portfolio.mark("AAPL", 110.0);
+        let position = portfolio.position("AAPL").unwrap();
+        assert_eq!(position.quantity, 25.0);
+        assert_eq!(position.average_cost, 115.0);
+        assert_eq!(position.realized_pnl, 145.0);
+        assert_eq!(portfolio.unrealized_pnl(), -125.0);
+        // what was made minus what is down minus commission
+        assert_eq!(portfolio.equity(), 10_000.0 + 150.0 - 125.0 - 5.0);
+
+        portfolio.apply_fill(fill(3, -25.0, 110.0)).unwrap();
+        assert_eq!(portfolio.position("AAPL").unwrap().quantity, 0.0);
+        assert_eq!(portfolio.realized_pnl(), 145.0 - 125.0);
+        assert_eq!(portfolio.equity(), portfolio.cash());
+        assert!(portfolio.apply_fill(fill(4, 0.0, 110.0)).is_err());
+    }
+
+    #[test]
+    fn short_positions_test() {
+        let mut portfolio = Portfolio::new(1_000.0).with_short_selling(true);
+        portfolio.apply_fill(fill(0, 5.0, 10.0)).unwrap();
+        // sells through zero into a short of 5 at 12
+        let trade = portfolio.apply_fill(fill(1, -10.0, 12.0)).unwrap();
+        assert_eq!(trade.realized_pnl, 10.0);
+        let position = portfolio.position("AAPL").unwrap();
+        assert_eq!(position.quantity, -5.0);
+        assert_eq!(position.average_cost, 12.0);
+        portfolio.mark("AAPL", 8.0);
+        assert_eq!(portfolio.unrealized_pnl(), 20.0);
+        assert_eq!(portfolio.equity(), 1_000.0 + 10.0 + 20.0);
+    }
+
+    #[test]
+    fn signal_limits_test() {
+        let mut portfolio = Portfolio::new(250.0);
+        // long only: nothing to sell yet
+        assert!(portfolio.apply_signal(&signal(SignalType::Sell, 0, 100.0, 1)).unwrap().is_none());
+        assert!(portfolio.apply_signal(&signal(SignalType::Hold, 0, 100.0, 1)).unwrap().is_none());
+        // only two fit in the cash
+        let trade = portfolio.apply_signal(&signal(SignalType::Buy, 1, 100.0, 5)).unwrap().unwrap();
+        assert_eq!(trade.fill.quantity, 2.0);
+        let trade = portfolio.apply_signal(&signal(SignalType::Sell, 2, 110.0, 5)).unwrap().unwrap();
+        assert_eq!(trade.fill.quantity, -2.0);
+        assert_eq!(portfolio.cash(), 270.0);
+    }
+
+    #[test]
+    fn equity_curve_test() {
+        let bars: Vec<IBApiBar> = [10.0, 11.0, 12.0, 9.0].iter().enumerate()
+            .map(|(i, close)| IBApiBar::builder().date(i as i64 * 60).ohlc(*close, *close, *close, *close).volume(100.0).count(1).wap(*close).build().unwrap())
+            .collect();
+        // out of order on purpose, they are acted on by time
+        let signals = vec![
+            signal(SignalType::Sell, 120, 12.0, 10),
+            signal(SignalType::Buy, 0, 10.0, 10),
+        ];
+        let mut portfolio = Portfolio::new(100.0);
+        portfolio.run("AAPL", &bars, &signals).unwrap();
+        let equity: Vec<f64> = portfolio.equity_curve().iter().map(|point| point.equity).collect();
+        assert_eq!(equity, vec![100.0, 110.0, 120.0, 120.0]);
+        assert_eq!(portfolio.equity_curve()[1].market_value, 110.0);
+        assert_eq!(portfolio.trades().len(), 2);
+        assert_eq!(portfolio.realized_pnl(), 20.0);
+    }
+
+    #[test]
+    fn executor_run_test() {
+        let mut executor = BacktestExecutor::with_broker(synthetic_broker("run")).with_initial_cash(10_000.0);
+        let strategy = Strategy::new(
+            vec![BacktestingMetric::SMA(5)],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            buy_every_tenth,
+            sell_later,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-06-30 00:00:00 UTC),
+            nothing,
+            nothing,
+        );
+        let results = executor.run(vec![strategy.clone()]).unwrap();
+        let result = results.get(&strategy).unwrap().as_ref().unwrap();
+        assert!(!result.signals.is_empty());
+        assert!(result.measures.is_empty());
+        assert!(!result.trades.is_empty());
+        // every buy is sold five bars later
+        assert_eq!(result.trades.iter().filter(|trade| trade.fill.quantity > 0.0).count(), result.trades.iter().filter(|trade| trade.fill.quantity < 0.0).count());
+        let last = result.equity_curve.last().unwrap();
+        assert!((last.equity - result.final_equity()).abs() < 1e-9);
+        assert!((result.net_profit() - result.realized_pnl() - result.unrealized_pnl()).abs() < 1e-9);
+        assert!((result.total_return() - result.net_profit() / 10_000.0).abs() < 1e-12);
+
+        // measures are reported for the strategies that ask for them
+        let measured = strategy.with_measures(&[BacktestingMeasure::NetProfit, BacktestingMeasure::WinRate, BacktestingMeasure::MaxDrawdown]);
+        let results = executor.run(vec![measured.clone()]).unwrap();
+        let measures = &results.get(&measured).unwrap().as_ref().unwrap().measures;
+        assert_eq!(measures.len(), 3);
+        assert!((measures[&BacktestingMeasure::NetProfit].unwrap() - result.net_profit()).abs() < 1e-9);
+        assert!(measures[&BacktestingMeasure::WinRate].is_some());
+    }
+}
+
+#[cfg(test)]
+mod measure_tests {
+    use std::collections::HashMap;
+    use backtesting::measures::{max_drawdown, max_drawdown_duration, returns};
+    use backtesting::{BacktestResult, BacktestingMeasure, EquityPoint, Fill, Trade};
+
+    const DAY: i64 = 86400;
+
+    fn curve(equity: &[f64], step: i64) -> Vec<EquityPoint> {
+        equity.iter().enumerate()
+            .map(|(i, equity)| EquityPoint { timestamp: i as i64 * step, cash: *equity, market_value: 0.0, equity: *equity })
+            .collect()
+    }
+
+    fn closing_trade(pnl: f64, commission: f64) -> Trade {
+        let fill = Fill { timestamp: 0, identifier: "AAPL".to_string(), quantity: -10.0, price: 100.0, commission };
+        Trade { fill, realized_pnl: pnl, closed_quantity: 10.0 }
+    }
+
+    fn backtest_result(equity_curve: Vec<EquityPoint>, trades: Vec<Trade>) -> BacktestResult {
+        BacktestResult {
+            signals: Vec::new(),
+            initial_cash: 100.0,
+            cash: equity_curve.last().map_or(100.0, |point| point.equity),
+            positions: HashMap::new(),
+            trades,
+            equity_curve,
+            measures: HashMap::new(),
+        }
+    }
+
+    fn measure(result: &BacktestResult, measure: BacktestingMeasure) -> f64 {
+        measure.compute(result, 0.0).unwrap()
+    }
+
+    #[test]
+    fn drawdown_test() {
+        let points = curve(&[100.0, 120.0, 90.0, 110.0, 130.0, 117.0], DAY);
+        // 120 down to 90
+        assert!((max_drawdown(&points) - 0.25).abs() < 1e-12);
+        // below 120 from day 1 until day 4
+        assert_eq!(max_drawdown_duration(&points), 3 * DAY);
+        let result = backtest_result(points, Vec::new());
+        assert_eq!(measure(&result, BacktestingMeasure::MaxDrawdownDuration), 3.0);
+        assert_eq!(max_drawdown(&curve(&[100.0, 101.0, 102.0], DAY)), 0.0);
+        assert_eq!(max_drawdown_duration(&curve(&[100.0, 90.0, 80.0], DAY)), 2 * DAY);
+    }
+
+    #[test]
+    fn return_measures_test() {
+        // doubles over exactly two years
+        let year = (365.25 * DAY as f64) as i64;
+        let points = curve(&[100.0, 150.0, 200.0], year);
+        let result = backtest_result(points, Vec::new());
+        assert_eq!(measure(&result, BacktestingMeasure::NetProfit), 100.0);
+        assert_eq!(measure(&result, BacktestingMeasure::TotalReturn), 1.0);
+        assert!((measure(&result, BacktestingMeasure::AnnualizedReturn) - (2.0f64.sqrt() - 1.0)).abs() < 1e-12);
+        // never fell, so there is nothing to divide by
+        assert_eq!(BacktestingMeasure::CalmarRatio.compute(&result, 0.0), None);
+        assert_eq!(BacktestingMeasure::SortinoRatio.compute(&result, 0.0), None);
+    }
+
+    #[test]
+    fn risk_adjusted_measures_test() {
+        let year = (365.25 * DAY as f64) as i64;
+        // four bars a year: +10%, -10%, +10%, -10% (the first return is relative to the initial cash)
+        let equity = [110.0, 99.0, 108.9, 98.01];
+        let points = curve(&equity, year / 3);
+        let per_bar = returns(100.0, &points);
+        assert!((per_bar[1] + 0.1).abs() < 1e-12);
+
+        let result = backtest_result(points, Vec::new());
+        let periods: f64 = 3.0;
+        let mean: f64 = per_bar.iter().sum::<f64>() / 4.0;
+        let deviation = (per_bar.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / 3.0).sqrt();
+        assert!((measure(&result, BacktestingMeasure::SharpeRatio) - mean / deviation * periods.sqrt()).abs() < 1e-9);
+        let downside = (2.0 * 0.01 / 4.0f64).sqrt();
+        assert!((measure(&result, BacktestingMeasure::SortinoRatio) - mean / downside * periods.sqrt()).abs() < 1e-9);
+        // a risk-free rate lowers the Sharpe ratio
+        assert!(BacktestingMeasure::SharpeRatio.compute(&result, 0.05).unwrap() < measure(&result, BacktestingMeasure::SharpeRatio));
+        let annualized = measure(&result, BacktestingMeasure::AnnualizedReturn);
+        assert!((measure(&result, BacktestingMeasure::CalmarRatio) - annualized / 0.109).abs() < 1e-9);
+        #[allow(deprecated)]
+        let deprecated = BacktestingMeasure::RiskAdjustedReturn;
+        assert_eq!(deprecated.compute(&result, 0.0), BacktestingMeasure::SharpeRatio.compute(&result, 0.0));
+    }
+
+    #[test]
+    fn trade_measures_test() {
+        let mut opening = closing_trade(0.0, 1.0);
+        opening.closed_quantity = 0.0;
+        opening.fill.quantity = 10.0;
+        let trades = vec![opening, closing_trade(30.0, 1.0), closing_trade(-10.0, 1.0), closing_trade(5.0, 1.0)];
+        let mut points = curve(&[100.0, 100.0, 100.0, 100.0], DAY);
+        points[1].market_value = 50.0;
+        let result = backtest_result(points, trades);
+        // net of commission: 29, -11, 4
+        assert!((measure(&result, BacktestingMeasure::WinRate) - 2.0 / 3.0).abs() < 1e-12);
+        assert!((measure(&result, BacktestingMeasure::ProfitFactor) - 33.0 / 11.0).abs() < 1e-12);
+        assert!((measure(&result, BacktestingMeasure::Expectancy) - 22.0 / 3.0).abs() < 1e-12);
+        assert_eq!(measure(&result, BacktestingMeasure::Exposure), 0.25);
+        // four fills of 1000 each against an average equity of 100
+        assert_eq!(measure(&result, BacktestingMeasure::Turnover), 40.0);
+
+        let no_trades = backtest_result(curve(&[100.0], DAY), Vec::new());
+        assert_eq!(BacktestingMeasure::WinRate.compute(&no_trades, 0.0), None);
+        assert_eq!(BacktestingMeasure::SharpeRatio.compute(&no_trades, 0.0), None);
+    }
+}
+
+#[cfg(test)]
+mod fill_tests {
+    use ordered_float::OrderedFloat;
+    use time::macros::datetime;
+    use backtesting::fills::Order;
+    use backtesting::{BacktestingMeasure, BacktestingMetric, Commission, FillModel, FillPrice, Portfolio, Signal, SignalType, SimulatedFills, Slippage, Strategy};
+    use fq_data_broker::HashedBarSize;
+    use ibapi_handler::IBApiBar;
+    use crate::fixtures::{buy_every_tenth, executor, nothing, sell_later};
+
+    fn bars() -> Vec<IBApiBar> {
+        vec![
+            IBApiBar::builder().date(0).ohlc(9.8, 10.2, 9.7, 10.0).volume(100.0).count(10).wap(10.0).build().unwrap(),
+            IBApiBar::builder().date(60).ohlc(10.5, 11.0, 9.5, 10.8).volume(100.0).count(10).wap(10.4).build().unwrap(),
+            IBApiBar::builder().date(120).ohlc(10.8, 10.9, 10.1, 10.2).volume(100.0).count(10).wap(10.5).build().unwrap(),
+        ]
+    }
+
+    fn order(quantity: f64, price: f64) -> Order {
+        Order { timestamp: 0, identifier: "AAPL".to_string(), quantity, price }
+    }
+
+    #[test]
+    fn commission_test() {
+        let fixed = Commission::ib_fixed();
+        assert_eq!(fixed.cost(100.0, 50.0, 0.0), 1.0);
+        assert_eq!(fixed.cost(1000.0, 50.0, 0.0), 5.0);
+        // never more than 1% of the trade value
+        assert_eq!(fixed.cost(100.0, 0.5, 0.0), 0.5);
+
+        let tiered = Commission::ib_tiered();
+        assert!((tiered.cost(1000.0, 50.0, 0.0) - 3.5).abs() < 1e-12);
+        assert!((tiered.cost(1000.0, 50.0, 500_000.0) - 2.0).abs() < 1e-12);
+        assert!((tiered.cost(1000.0, 50.0, 200_000_000.0) - 0.5).abs() < 1e-12);
+        assert_eq!(tiered.cost(50.0, 50.0, 0.0), 0.35);
+        assert_eq!(Commission::None.cost(1000.0, 50.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn fill_price_test() {
+        let bar = &bars()[1];
+        let portfolio = Portfolio::new(1_000.0);
+
+        let fill = SimulatedFills::new(FillPrice::NextOpen).fill(&order(5.0, 10.0), bar, &portfolio).unwrap();
+        assert_eq!((fill.timestamp, fill.quantity, fill.price), (60, 5.0, 10.5));
+
+        // the limit is inside the bar's range, so it is reached after the open
+        let mut limit = SimulatedFills::new(FillPrice::NextLimit);
+        assert_eq!(limit.fill(&order(5.0, 10.0), bar, &portfolio).unwrap().price, 10.0);
+        // opening above the limit fills a buy at the open straight away
+        assert_eq!(limit.fill(&order(5.0, 10.6), bar, &portfolio).unwrap().price, 10.5);
+        assert!(limit.fill(&order(5.0, 9.0), bar, &portfolio).is_none());
+        let mut short = Portfolio::new(1_000.0).with_short_selling(true);
+        assert_eq!(limit.fill(&order(-5.0, 10.0), bar, &short).unwrap().price, 10.5);
+        assert!(limit.fill(&order(-5.0, 11.5), bar, &short).is_none());
+
+        // long only by default, so selling what is not held fills nothing
+        assert!(limit.fill(&order(-5.0, 10.0), bar, &portfolio).is_none());
+        short.apply_fill(limit.fill(&order(-5.0, 10.0), bar, &short).unwrap()).unwrap();
+        assert_eq!(short.position("AAPL").unwrap().quantity, -5.0);
+    }
+
+    #[test]
+    fn slippage_and_volume_test() {
+        let bar = &bars()[1];
+        let portfolio = Portfolio::new(1_000.0);
+        let mut fixed = SimulatedFills::new(FillPrice::NextOpen).with_slippage(Slippage::PerShare(0.2));
+        assert!((fixed.fill(&order(5.0, 10.0), bar, &portfolio).unwrap().price - 10.7).abs() < 1e-12);
+        // never beyond the bar's high or low
+        let mut large = SimulatedFills::new(FillPrice::NextOpen).with_slippage(Slippage::PerShare(2.0));
+        assert_eq!(large.fill(&order(5.0, 10.0), bar, &portfolio).unwrap().price, 11.0);
+
+        // 10 shares of a 100 share bar move the price by 10% of the impact
+        let mut impact = SimulatedFills::new(FillPrice::NextOpen).with_slippage(Slippage::VolumeShare(0.1));
+        assert!((impact.fill(&order(10.0, 10.0), bar, &portfolio).unwrap().price - 10.5 * 1.01).abs() < 1e-12);
+
+        let mut capped = SimulatedFills::new(FillPrice::NextOpen).with_volume_limit(0.05);
+        assert_eq!(capped.fill(&order(20.0, 10.0), bar, &portfolio).unwrap().quantity, 5.0);
+        let empty = IBApiBar::builder().date(60).ohlc(10.5, 10.5, 10.5, 10.5).volume(0.0).count(0).wap(10.5).build().unwrap();
+        assert!(capped.fill(&order(20.0, 10.0), &empty, &portfolio).is_none());
+
+        // the cash is cut down with the commission of the whole order in mind: (1000 - 20) / 10.5
+        let mut costly = SimulatedFills::new(FillPrice::NextOpen).with_commission(Commission::PerShare { rate: 0.1, minimum: 0.0, maximum: 1.0 });
+        let fill = costly.fill(&order(200.0, 10.0), bar, &portfolio).unwrap();
+        assert_eq!(fill.quantity, 93.0);
+        assert!(fill.quantity * fill.price + fill.commission <= 1_000.0);
+    }
+
+    #[test]
+    fn monthly_volume_test() {
+        let portfolio = Portfolio::new(1e9);
+        let mut fills = SimulatedFills::new(FillPrice::NextOpen).with_commission(Commission::ib_tiered());
+        let january = IBApiBar::builder().date(datetime!(2021-01-29 15:00:00 UTC).unix_timestamp()).ohlc(10.0, 10.0, 10.0, 10.0).volume(1e9).count(1).wap(10.0).build().unwrap();
+        let february = IBApiBar::builder().date(datetime!(2021-02-01 15:00:00 UTC).unix_timestamp()).ohlc(10.0, 10.0, 10.0, 10.0).volume(1e9).count(1).wap(10.0).build().unwrap();
+        fills.fill(&order(400_000.0, 10.0), &january, &portfolio).unwrap();
+        // past the first tier for the rest of January
+        assert!((fills.fill(&order(1000.0, 10.0), &january, &portfolio).unwrap().commission - 2.0).abs() < 1e-12);
+        assert!((fills.fill(&order(1000.0, 10.0), &february, &portfolio).unwrap().commission - 3.5).abs() < 1e-12);
+        assert_eq!(fills.monthly_volume(), 1000.0);
+        fills.reset();
+        assert_eq!(fills.monthly_volume(), 0.0);
+    }
+
+    #[test]
+    fn next_bar_run_test() {
+        let signals = vec![
+            Signal::new(SignalType::Buy, 0, OrderedFloat(10.0), &"AAPL".to_string(), Some(10), None),
+            // given on the last bar, so never filled
+            Signal::new(SignalType::Sell, 120, OrderedFloat(10.2), &"AAPL".to_string(), Some(10), None),
+        ];
+        let mut portfolio = Portfolio::new(1_000.0);
+        let mut fills = SimulatedFills::new(FillPrice::NextOpen).with_commission(Commission::ib_fixed());
+        portfolio.run_with("AAPL", &bars(), &signals, &mut fills).unwrap();
+        assert_eq!(portfolio.trades().len(), 1);
+        let fill = &portfolio.trades()[0].fill;
+        assert_eq!((fill.timestamp, fill.price, fill.commission), (60, 10.5, 1.0));
+        // nothing held at the first close yet, bought at the second bar's open
+        let equity: Vec<f64> = portfolio.equity_curve().iter().map(|point| point.equity).collect();
+        assert_eq!(equity, vec![1_000.0, 1_000.0 - 105.0 - 1.0 + 108.0, 1_000.0 - 105.0 - 1.0 + 102.0]);
+    }
+
+    #[test]
+    fn executor_costs_test() {
+        let strategy = Strategy::new(
+            vec![BacktestingMetric::SMA(5)],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            buy_every_tenth,
+            sell_later,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-06-30 00:00:00 UTC),
+            nothing,
+            nothing,
+        ).with_measures(&[BacktestingMeasure::NetProfit]);
+
+        let mut executor = executor("costs");
+        let free = executor.run(vec![strategy.clone()]).unwrap().remove(&strategy).unwrap().unwrap();
+        let mut executor = executor.with_fill_model(Box::new(SimulatedFills::at_signal_close().with_commission(Commission::ib_fixed())));
+        let costly = executor.run(vec![strategy.clone()]).unwrap().remove(&strategy).unwrap().unwrap();
+
+        // same trades, each paying the USD 1 minimum
+        assert_eq!(free.trades.len(), costly.trades.len());
+        assert!(costly.trades.iter().all(|trade| trade.fill.commission == 1.0));
+        let commissions = costly.trades.len() as f64;
+        assert!((free.net_profit() - costly.net_profit() - commissions).abs() < 1e-9);
+        assert!((costly.measures[&BacktestingMeasure::NetProfit].unwrap() - costly.net_profit()).abs() < 1e-9);
+    }
+}
+
+#[cfg(test)]
+mod strategy_tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use anyhow::Error;
+    use time::macros::datetime;
+    use backtesting::{BacktestResult, BacktestingMeasure, BacktestingMetric, BarContext, Portfolio, Signal, Strategy, StrategyLogic, Trade};
+    use fq_data_broker::HashedBarSize;
+    use crate::fixtures::executor;
+
+    // buys when the RSI falls below `threshold`, sells again above 100 - threshold
+    fn rsi_strategy(threshold: f64) -> Strategy {
+        Strategy::new(
+            vec![BacktestingMetric::RSI(14)],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            move |values, i| Ok(values[i as usize].into_inner() < threshold),
+            move |values, i| Ok(values[i as usize].into_inner() > 100.0 - threshold),
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-12-30 00:00:00 UTC),
+            || Ok(()),
+            || Ok(()),
+        ).with_name(&format!("RSI below {}", threshold))
+    }
+
+    #[test]
+    fn parameter_sweep_test() {
+        let mut executor = executor("sweep");
+        let strategies: Vec<Strategy> = [30.0, 40.0, 50.0].iter().map(|threshold| rsi_strategy(*threshold)).collect();
+        let results = executor.run(strategies.clone()).unwrap();
+        assert_eq!(results.len(), 3);
+        let trades: Vec<usize> = strategies.iter().map(|strategy| results[strategy].as_ref().unwrap().trades.len()).collect();
+        // a looser threshold trades at least as often
+        assert!(trades[0] <= trades[1] && trades[1] <= trades[2]);
+        assert!(trades[2] > 0);
+
+        // the same strategy twice, or two that only differ in their closures, would share a result
+        let unnamed = vec![rsi_strategy(30.0).with_name(""), rsi_strategy(40.0).with_name("")];
+        assert!(executor.run(unnamed.clone()).is_err());
+        assert!(executor.execute(unnamed).is_err());
+        assert_eq!(executor.execute(strategies.clone()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn no_lookahead_test() {
+        // the signal functions only ever get the values up to the bar they decide on
+        let checked = |values: &[ordered_float::OrderedFloat<f64>], i: u32| -> Result<bool, Error> {
+            assert_eq!(values.len(), i as usize + 1);
+            Ok(false)
+        };
+        let strategy = Strategy::new(
+            vec![BacktestingMetric::SMA(5), BacktestingMetric::RSI(14)],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            checked,
+            checked,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-03-31 00:00:00 UTC),
+            || Ok(()),
+            || Ok(()),
+        );
+        let mut executor = executor("lookahead");
+        let result = executor.run(vec![strategy.clone()]).unwrap().remove(&strategy).unwrap().unwrap();
+        assert!(!result.signals.is_empty());
+        let executed = executor.execute(vec![strategy.clone()]).unwrap().remove(&strategy).unwrap().unwrap();
+        assert!(executed.values().all(|signals| signals.as_ref().is_ok_and(|signals| !signals.is_empty())));
+    }
+
+    #[test]
+    fn closure_actions_test() {
+        let buys = Arc::new(AtomicUsize::new(0));
+        let counted = buys.clone();
+        let strategy = Strategy::new(
+            vec![BacktestingMetric::SMA(5)],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            |_, i| Ok(i % 10 == 0),
+            |_, i| Ok(i % 10 == 5),
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-06-30 00:00:00 UTC),
+            move || {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+            || Ok(()),
+        );
+        let result = executor("actions").run(vec![strategy.clone()]).unwrap().remove(&strategy).unwrap().unwrap();
+        let bought = result.trades.iter().filter(|trade| trade.fill.quantity > 0.0).count();
+        assert!(bought > 0);
+        assert_eq!(buys.load(Ordering::SeqCst), bought);
+    }
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Counts {
+        bars: usize,
+        fills: usize,
+        net_profit: f64,
+    }
+
+    // goes long when the close crosses above the SMA and flat when it crosses below, counting what it sees
+    #[derive(Clone)]
+    struct Crossover {
+        metric: BacktestingMetric,
+        above: Option<bool>,
+        counts: Arc<Mutex<Counts>>,
+    }
+
+    impl StrategyLogic for Crossover {
+        fn on_start(&mut self) -> Result<(), Error> {
+            self.above = None;
+            *self.counts.lock().unwrap() = Counts::default();
+            Ok(())
+        }
+
+        fn on_bar(&mut self, context: &BarContext) -> Result<Vec<Signal>, Error> {
+            self.counts.lock().unwrap().bars += 1;
+            let average = match context.value(&self.metric) {
+                Some(average) => average,
+                None => return Ok(vec![]),
+            };
+            let above = context.bar().close.into_inner() > average;
+            let crossed = self.above.is_some_and(|was| was != above);
+            self.above = Some(above);
+            if crossed && above && context.position() == 0.0 {
+                Ok(vec![context.buy(10)])
+            } else if crossed && !above && context.position() > 0.0 {
+                Ok(vec![context.sell(context.position() as i32)])
+            } else {
+                Ok(vec![])
+            }
+        }
+
+        fn on_fill(&mut self, trade: &Trade, portfolio: &Portfolio) -> Result<(), Error> {
+            assert_eq!(portfolio.trades().last(), Some(trade));
+            self.counts.lock().unwrap().fills += 1;
+            Ok(())
+        }
+
+        fn on_finish(&mut self, result: &BacktestResult) -> Result<(), Error> {
+            self.counts.lock().unwrap().net_profit = result.measures[&BacktestingMeasure::NetProfit].unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stateful_logic_test() {
+        let counts = Arc::new(Mutex::new(Counts::default()));
+        let logic = Crossover { metric: BacktestingMetric::SMA(10), above: None, counts: counts.clone() };
+        let strategy = Strategy::with_logic(
+            vec![BacktestingMetric::SMA(10)],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-12-30 00:00:00 UTC),
+            logic,
+        ).with_name("SMA crossover").with_measures(&[BacktestingMeasure::NetProfit]);
+        let mut executor = executor("stateful");
+        let result = executor.run(vec![strategy.clone()]).unwrap().remove(&strategy).unwrap().unwrap();
+        assert!(!result.trades.is_empty());
+        assert!(result.trades.iter().all(|trade| trade.fill.quantity.abs() == 10.0));
+        assert_eq!(result.signals.len(), result.trades.len());
+        let first = std::mem::take(&mut *counts.lock().unwrap());
+        assert_eq!(first, Counts { bars: result.equity_curve.len(), fills: result.trades.len(), net_profit: result.net_profit() });
+
+        // each run starts from the logic as it was given, so a second run sees the same
+        executor.run(vec![strategy.clone()]).unwrap();
+        assert_eq!(*counts.lock().unwrap(), first);
+        // without buy and sell functions there are no per-metric signals
+        assert!(strategy.execute(&vec![]).is_err());
+    }
+
+    #[test]
+    fn bar_context_test() {
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counted = seen.clone();
+        let metric = BacktestingMetric::SMA(5);
+        let checked = metric.clone();
+        let logic = move |context: &BarContext| -> Result<Vec<Signal>, Error> {
+            let index = context.index();
+            // nothing after the bar is handed out
+            assert_eq!(context.bars().len(), index + 1);
+            assert_eq!(context.bars().last(), Some(context.bar()));
+            assert_eq!(context.values(&checked).unwrap().len(), index + 1);
+            assert_eq!(context.value(&checked).is_some(), index >= 4);
+            assert!(context.value(&BacktestingMetric::SMA(50)).is_none());
+            assert_eq!(context.metrics().collect::<Vec<_>>(), vec![&checked]);
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![])
+        };
+        let strategy = Strategy::with_logic(
+            vec![metric],
+            HashedBarSize::Day,
+            "AAPL".to_string(),
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-03-31 00:00:00 UTC),
+            logic,
+        );
+        let result = executor("context").run(vec![strategy.clone()]).unwrap().remove(&strategy).unwrap().unwrap();
+        assert!(result.trades.is_empty());
+        assert_eq!(seen.load(Ordering::SeqCst), result.equity_curve.len());
+    }
+}
@@ -0,0 +1,44 @@
+use fq_data_broker::migrate::migrate_storage;
+use fq_data_broker::StorageFormat;
+
+/// Converts a broker storage directory between the JSON and binary formats.
+/// usage: migrate_storage <storage_directory> <json|binary> [--remove-old]
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        println!("usage: {} <storage_directory> <json|binary> [--remove-old]", args[0]);
+        std::process::exit(2);
+    }
+    let format = match args[2].as_str() {
+        "json" => StorageFormat::Json,
+        "binary" | "bin" => StorageFormat::Binary,
+        other => {
+            println!("Unknown format '{}', expected json or binary", other);
+            std::process::exit(2);
+        }
+    };
+    let remove_old = match args.get(3).map(|arg| arg.as_str()) {
+        None => false,
+        Some("--remove-old") => true,
+        Some(other) => {
+            println!("Unknown option '{}'", other);
+            std::process::exit(2);
+        }
+    };
+
+    match migrate_storage(&args[1], format, remove_old) {
+        Ok(report) => {
+            for (old_location, new_location) in &report.converted {
+                println!("Migrated {} to {}", old_location, new_location);
+            }
+            for (location, existing) in &report.skipped {
+                println!("Skipped {}: {} already exists", location, existing);
+            }
+            println!("Migrated {} files, skipped {}", report.converted.len(), report.skipped.len());
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use anyhow::{bail, Context, Error};
+use serde::{Deserialize, Serialize};
+use time::macros::{date, format_description, time};
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time, Weekday};
+use time_tz::{timezones, PrimitiveDateTimeExt, Tz};
+use ibapi_handler::{Instrument, InstrumentType};
+use crate::resample::intraday_seconds;
+use crate::HashedBarSize;
+
+const DAY: i64 = 86400;
+
+/// Opening and closing time of a session in the exchange's time zone.
+/// An `open` after `close` means the session opens on the evening before its trade date (CME Globex, 17:00-16:00).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TradingHours {
+    pub open: Time,
+    pub close: Time,
+}
+
+impl TradingHours {
+    pub fn new(open: Time, close: Time) -> Self {
+        TradingHours { open, close }
+    }
+}
+
+/// The holiday rules a calendar follows, on top of the dates in its holiday tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HolidayRules {
+    /// Only the holiday tables.
+    None,
+    /// NYSE and NASDAQ: closed on the US market holidays, 13:00 closes around July 4th,
+    /// Thanksgiving and Christmas, and the unscheduled closures since 2001.
+    Nyse,
+    /// CME Globex equity and rate products: closed on New Year's Day, Good Friday and Christmas,
+    /// early closes on the other US holidays and their eves.
+    Cme,
+}
+
+/// One trading session: the trade date it belongs to and when it opens and closes (unix timestamps).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub date: Date,
+    pub open: i64,
+    pub close: i64,
+}
+
+impl Session {
+    /// Whether `timestamp` lies in the session; the close itself is not part of it.
+    pub fn contains(&self, timestamp: i64) -> bool {
+        timestamp >= self.open && timestamp < self.close
+    }
+}
+
+/// Extra closures and early closes for a calendar, loaded from TOML, e.g.
+/// `holidays = ["2025-01-09"]` and `early_closes = ["2025-07-03"]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HolidayTable {
+    #[serde(default)]
+    pub holidays: Vec<String>,
+    #[serde(default)]
+    pub early_closes: Vec<String>,
+}
+
+impl HolidayTable {
+    pub fn from_toml_str(toml: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Could not read {}", path.as_ref().display()))?;
+        Self::from_toml_str(&contents).with_context(|| format!("Invalid holiday table {}", path.as_ref().display()))
+    }
+}
+
+/// When an exchange trades: its time zone, session hours, and which weekdays are holidays or half days.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TradingCalendar {
+    pub name: String,
+    /// IANA name of the exchange's time zone, e.g. `America/New_York`.
+    pub time_zone: String,
+    pub regular: TradingHours,
+    /// Pre- and post-market (or the whole Globex session), for bars requested outside regular hours.
+    pub extended: TradingHours,
+    /// The regular and extended close on half days.
+    pub early_close: Time,
+    pub early_extended_close: Time,
+    pub rules: HolidayRules,
+    holidays: BTreeSet<Date>,
+    early_closes: BTreeSet<Date>,
+}
+
+impl TradingCalendar {
+    /// A calendar trading every weekday in `time_zone`, without holidays until some are added.
+    pub fn new(name: &str, time_zone: &str, regular: TradingHours, extended: TradingHours) -> Result<Self, Error> {
+        if timezones::get_by_name(time_zone).is_none() {
+            bail!("Unknown time zone '{}'", time_zone);
+        }
+        Ok(TradingCalendar {
+            name: name.to_string(),
+            time_zone: time_zone.to_string(),
+            regular,
+            extended,
+            early_close: regular.close,
+            early_extended_close: extended.close,
+            rules: HolidayRules::None,
+            holidays: BTreeSet::new(),
+            early_closes: BTreeSet::new(),
+        })
+    }
+
+    /// New York Stock Exchange: 9:30-16:00 ET, 4:00-20:00 ET with pre- and post-market, 13:00 on half days.
+    pub fn nyse() -> Self {
+        TradingCalendar {
+            name: "NYSE".to_string(),
+            time_zone: "America/New_York".to_string(),
+            regular: TradingHours::new(time!(9:30), time!(16:00)),
+            extended: TradingHours::new(time!(4:00), time!(20:00)),
+            early_close: time!(13:00),
+            early_extended_close: time!(17:00),
+            rules: HolidayRules::Nyse,
+            holidays: BTreeSet::new(),
+            early_closes: BTreeSet::new(),
+        }
+    }
+
+    /// NASDAQ keeps the same hours and holidays as the NYSE.
+    pub fn nasdaq() -> Self {
+        TradingCalendar { name: "NASDAQ".to_string(), ..TradingCalendar::nyse() }
+    }
+
+    /// CME Globex: 17:00 CT the evening before to 16:00 CT, with the pit hours 8:30-15:00 CT as regular hours
+    /// and a 12:00 CT close on half days.
+    pub fn cme() -> Self {
+        TradingCalendar {
+            name: "CME".to_string(),
+            time_zone: "America/Chicago".to_string(),
+            regular: TradingHours::new(time!(8:30), time!(15:00)),
+            extended: TradingHours::new(time!(17:00), time!(16:00)),
+            early_close: time!(12:00),
+            early_extended_close: time!(12:00),
+            rules: HolidayRules::Cme,
+            holidays: BTreeSet::new(),
+            early_closes: BTreeSet::new(),
+        }
+    }
+
+    /// The built-in calendar called `name` (NYSE, NASDAQ or CME).
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "NYSE" => Some(TradingCalendar::nyse()),
+            "NASDAQ" => Some(TradingCalendar::nasdaq()),
+            "CME" => Some(TradingCalendar::cme()),
+            _ => None,
+        }
+    }
+
+    /// Adds the closures and early closes of `table` (dates as `YYYY-MM-DD`).
+    pub fn with_table(mut self, table: &HolidayTable) -> Result<Self, Error> {
+        let format = format_description!("[year]-[month]-[day]");
+        for holiday in &table.holidays {
+            let date = Date::parse(holiday, format).with_context(|| format!("Invalid holiday '{}'", holiday))?;
+            self.holidays.insert(date);
+        }
+        for early_close in &table.early_closes {
+            let date = Date::parse(early_close, format).with_context(|| format!("Invalid early close '{}'", early_close))?;
+            self.early_closes.insert(date);
+        }
+        Ok(self)
+    }
+
+    /// Adds the holiday table in the TOML file at `path`.
+    pub fn with_table_file<P: AsRef<Path>>(self, path: P) -> Result<Self, Error> {
+        self.with_table(&HolidayTable::from_toml_file(path)?)
+    }
+
+    fn tz(&self) -> Result<&'static Tz, Error> {
+        match timezones::get_by_name(&self.time_zone) {
+            Some(tz) => Ok(tz),
+            None => bail!("Unknown time zone '{}'", self.time_zone),
+        }
+    }
+
+    /// Whether the exchange is closed all day on a weekday.
+    pub fn is_holiday(&self, date: Date) -> bool {
+        self.holidays.contains(&date) || match self.rules {
+            HolidayRules::None => false,
+            HolidayRules::Nyse => nyse_holiday(date),
+            HolidayRules::Cme => cme_holiday(date),
+        }
+    }
+
+    /// Whether the exchange trades on `date`, but closes early.
+    pub fn is_early_close(&self, date: Date) -> bool {
+        self.is_trading_day(date) && (self.early_closes.contains(&date) || match self.rules {
+            HolidayRules::None => false,
+            HolidayRules::Nyse => nyse_early_close(date),
+            HolidayRules::Cme => cme_early_close(date),
+        })
+    }
+
+    pub fn is_trading_day(&self, date: Date) -> bool {
+        !matches!(date.weekday(), Weekday::Saturday | Weekday::Sunday) && !self.is_holiday(date)
+    }
+
+    /// The session traded for `date`, regular hours or extended ones, None if the exchange is closed.
+    pub fn session(&self, date: Date, extended: bool) -> Result<Option<Session>, Error> {
+        if !self.is_trading_day(date) {
+            return Ok(None);
+        }
+        let hours = if extended { self.extended } else { self.regular };
+        let close = match (self.is_early_close(date), extended) {
+            (true, false) => self.early_close,
+            (true, true) => self.early_extended_close,
+            (false, _) => hours.close,
+        };
+        let open_date = if hours.open > hours.close { date.previous_day().unwrap_or(date) } else { date };
+        Ok(Some(Session {
+            date,
+            open: self.local_timestamp(open_date, hours.open)?,
+            close: self.local_timestamp(date, close)?,
+        }))
+    }
+
+    /// The sessions overlapping `start..=end` (unix timestamps), in order.
+    pub fn sessions(&self, start: i64, end: i64, extended: bool) -> Result<Vec<Session>, Error> {
+        let tz = self.tz()?;
+        let first = time_tz::OffsetDateTimeExt::to_timezone(&OffsetDateTime::from_unix_timestamp(start)?, tz).date();
+        let last = time_tz::OffsetDateTimeExt::to_timezone(&OffsetDateTime::from_unix_timestamp(end)?, tz).date();
+        let mut sessions = Vec::new();
+        // a session opening the evening before belongs to the next day
+        let mut date = first;
+        while date <= last + Duration::days(1) {
+            if let Some(session) = self.session(date, extended)? {
+                if session.close > start && session.open <= end {
+                    sessions.push(session);
+                }
+            }
+            date += Duration::days(1);
+        }
+        Ok(sessions)
+    }
+
+    /// The session `timestamp` lies in, if any.
+    pub fn session_at(&self, timestamp: i64, extended: bool) -> Result<Option<Session>, Error> {
+        Ok(self.sessions(timestamp, timestamp, extended)?.into_iter().find(|session| session.contains(timestamp)))
+    }
+
+    /// Whether bars of `bar_size` can exist in `start..=end`: for intraday bars whether a session overlaps it,
+    /// for day bars whether a trading day is dated inside it (IB dates them at midnight UTC),
+    /// and for week and month bars whether a trading day falls in a week or month the window touches.
+    pub fn expects_bars(&self, bar_size: &HashedBarSize, start: i64, end: i64, extended: bool) -> Result<bool, Error> {
+        if intraday_seconds(bar_size).is_some() {
+            return Ok(!self.sessions(start, end, extended)?.is_empty());
+        }
+        let (first, last) = match bar_size {
+            HashedBarSize::Day => {
+                let first = OffsetDateTime::from_unix_timestamp(start.div_euclid(DAY) * DAY)?.date();
+                // only days whose midnight is inside the window
+                let first = if start.rem_euclid(DAY) == 0 { first } else { first + Duration::days(1) };
+                (first, OffsetDateTime::from_unix_timestamp(end)?.date())
+            },
+            _ => (period_start(bar_size, OffsetDateTime::from_unix_timestamp(start)?.date())?,
+                  period_end(bar_size, OffsetDateTime::from_unix_timestamp(end)?.date())?),
+        };
+        let mut date = first;
+        while date <= last {
+            if self.is_trading_day(date) {
+                return Ok(true);
+            }
+            date += Duration::days(1);
+        }
+        Ok(false)
+    }
+
+    fn local_timestamp(&self, date: Date, time: Time) -> Result<i64, Error> {
+        match PrimitiveDateTime::new(date, time).assume_timezone(self.tz()?).take_first() {
+            Some(datetime) => Ok(datetime.unix_timestamp()),
+            None => bail!("{} {} does not exist in {}", date, time, self.time_zone),
+        }
+    }
+}
+
+/// The name of the built-in calendar an instrument trades on, None for markets that never close
+/// (forex, crypto) or exchanges without a calendar yet.
+pub fn calendar_name(instrument: &Instrument) -> Option<&'static str> {
+    if matches!(instrument.security_type, InstrumentType::Forex | InstrumentType::Crypto) {
+        return None;
+    }
+    match instrument.exchange.to_uppercase().as_str() {
+        "SMART" | "NYSE" | "ARCA" | "AMEX" | "BATS" | "IEX" => Some("NYSE"),
+        "NASDAQ" | "ISLAND" => Some("NASDAQ"),
+        "CME" | "GLOBEX" | "CBOT" | "ECBOT" | "NYMEX" | "COMEX" => Some("CME"),
+        _ => None,
+    }
+}
+
+/// The first day of the week (Monday) or month of `date`.
+pub(crate) fn period_start(bar_size: &HashedBarSize, date: Date) -> Result<Date, Error> {
+    Ok(match bar_size {
+        HashedBarSize::Week => date - Duration::days(date.weekday().number_days_from_monday() as i64),
+        HashedBarSize::Month => Date::from_calendar_date(date.year(), date.month(), 1)?,
+        _ => date,
+    })
+}
+
+/// The last day of the week (Sunday) or month of `date`.
+pub(crate) fn period_end(bar_size: &HashedBarSize, date: Date) -> Result<Date, Error> {
+    Ok(match bar_size {
+        HashedBarSize::Week => period_start(bar_size, date)? + Duration::days(6),
+        HashedBarSize::Month => {
+            let next = match date.month() {
+                Month::December => Date::from_calendar_date(date.year() + 1, Month::January, 1)?,
+                month => Date::from_calendar_date(date.year(), month.next(), 1)?,
+            };
+            next - Duration::days(1)
+        },
+        _ => date,
+    })
+}
+
+/// The `n`th (1 based) `weekday` of a month.
+fn nth_weekday(year: i32, month: Month, weekday: Weekday, n: u8) -> Option<Date> {
+    let first = Date::from_calendar_date(year, month, 1).ok()?;
+    let offset = (weekday.number_days_from_monday() + 7 - first.weekday().number_days_from_monday()) % 7;
+    let date = first + Duration::days(offset as i64 + 7 * (n as i64 - 1));
+    (date.month() == month).then_some(date)
+}
+
+fn last_weekday(year: i32, month: Month, weekday: Weekday) -> Option<Date> {
+    nth_weekday(year, month, weekday, 5).or_else(|| nth_weekday(year, month, weekday, 4))
+}
+
+/// Easter Sunday (Gregorian), by the anonymous algorithm.
+fn easter(year: i32) -> Option<Date> {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = Month::try_from(((h + l - 7 * m + 114) / 31) as u8).ok()?;
+    let day = ((h + l - 7 * m + 114) % 31 + 1) as u8;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// A fixed holiday moved to Friday when it falls on a Saturday and to Monday when it falls on a Sunday.
+fn observed(date: Date) -> Date {
+    match date.weekday() {
+        Weekday::Saturday => date - Duration::days(1),
+        Weekday::Sunday => date + Duration::days(1),
+        _ => date,
+    }
+}
+
+fn fixed(year: i32, month: Month, day: u8) -> Option<Date> {
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// New Year's Day moves to Monday when it falls on a Sunday, but not to the Friday before when it falls on a Saturday.
+fn new_years_day(year: i32) -> Option<Date> {
+    let date = fixed(year, Month::January, 1)?;
+    match date.weekday() {
+        Weekday::Saturday => None,
+        _ => Some(observed(date)),
+    }
+}
+
+fn good_friday(year: i32) -> Option<Date> {
+    easter(year).map(|easter| easter - Duration::days(2))
+}
+
+/// The US market holidays other than New Year's Day, Good Friday and Christmas.
+fn us_holidays(year: i32) -> Vec<Option<Date>> {
+    vec![
+        (year >= 1998).then(|| nth_weekday(year, Month::January, Weekday::Monday, 3)).flatten(),
+        nth_weekday(year, Month::February, Weekday::Monday, 3),
+        last_weekday(year, Month::May, Weekday::Monday),
+        (year >= 2022).then(|| fixed(year, Month::June, 19).map(observed)).flatten(),
+        fixed(year, Month::July, 4).map(observed),
+        nth_weekday(year, Month::September, Weekday::Monday, 1),
+        nth_weekday(year, Month::November, Weekday::Thursday, 4),
+    ]
+}
+
+/// Days the NYSE closed outside of its holiday schedule: 9/11, the national days of mourning for
+/// Presidents Reagan, Ford, Bush and Carter, and Hurricane Sandy.
+const NYSE_CLOSURES: [Date; 10] = [
+    date!(2001-09-11), date!(2001-09-12), date!(2001-09-13), date!(2001-09-14),
+    date!(2004-06-11), date!(2007-01-02), date!(2012-10-29), date!(2012-10-30),
+    date!(2018-12-05), date!(2025-01-09),
+];
+
+fn nyse_holiday(date: Date) -> bool {
+    let year = date.year();
+    // New Year's Day of the next year is never observed in this one
+    let fixed_holidays = [new_years_day(year), good_friday(year), fixed(year, Month::December, 25).map(observed)];
+    NYSE_CLOSURES.contains(&date) || fixed_holidays.iter().chain(us_holidays(year).iter()).any(|holiday| *holiday == Some(date))
+}
+
+/// July 3rd and Christmas Eve on Monday to Thursday, and the day after Thanksgiving.
+fn us_half_day(date: Date) -> bool {
+    let year = date.year();
+    let weekday_eve = |eve: Option<Date>| eve == Some(date) && date.weekday().number_days_from_monday() < 4;
+    weekday_eve(fixed(year, Month::July, 3))
+        || weekday_eve(fixed(year, Month::December, 24))
+        || nth_weekday(year, Month::November, Weekday::Thursday, 4).map(|thanksgiving| thanksgiving + Duration::days(1)) == Some(date)
+}
+
+fn nyse_early_close(date: Date) -> bool {
+    us_half_day(date)
+}
+
+fn cme_holiday(date: Date) -> bool {
+    let year = date.year();
+    [new_years_day(year), good_friday(year), fixed(year, Month::December, 25).map(observed)].contains(&Some(date))
+}
+
+/// Globex stays open on the other US holidays, but halts at noon.
+fn cme_early_close(date: Date) -> bool {
+    us_holidays(date.year()).contains(&Some(date)) || us_half_day(date)
+}
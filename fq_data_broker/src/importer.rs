@@ -0,0 +1,252 @@
+use std::path::Path;
+use anyhow::{bail, Context, Error};
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time};
+use time::format_description::{self, OwnedFormatItem};
+use time_tz::{timezones, PrimitiveDateTimeExt};
+use ibapi_handler::IBApiBar;
+use range_data_storage::range_data_storage::StorageFormat;
+use crate::resample::bar_window;
+use crate::{merge_bars, open_data_store, BarStorage, HashedBarSize};
+
+/// How the timestamp column of a CSV file is written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CsvTimestampFormat {
+    /// Seconds since the unix epoch.
+    UnixSeconds,
+    /// Milliseconds since the unix epoch.
+    UnixMillis,
+    /// A `time` format description, e.g. `[year]-[month]-[day] [hour]:[minute]:[second]`.
+    /// Descriptions without a time component are read as midnight of that date.
+    Pattern(String),
+}
+
+/// Which windows an imported file marks as cached, i.e. never to be fetched again by `retrieve_data`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum CsvCoverage {
+    /// The window of each bar (its length, or its day, week or month), so holes in the file are still fetched.
+    #[default]
+    Bars,
+    /// Everything from the first bar to the last one, for files known to have every bar in between.
+    Span,
+}
+
+/// Maps the columns of a vendor CSV file (by header name) onto the fields of an IBApiBar.
+/// `volume`, `count` and `wap` are optional since most vendor dumps do not carry them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvColumnMapping {
+    pub date: String,
+    pub open: String,
+    pub high: String,
+    pub low: String,
+    pub close: String,
+    pub volume: Option<String>,
+    pub count: Option<String>,
+    pub wap: Option<String>,
+}
+
+impl Default for CsvColumnMapping {
+    fn default() -> Self {
+        CsvColumnMapping {
+            date: "date".to_string(),
+            open: "open".to_string(),
+            high: "high".to_string(),
+            low: "low".to_string(),
+            close: "close".to_string(),
+            volume: Some("volume".to_string()),
+            count: None,
+            wap: None,
+        }
+    }
+}
+
+/// Everything needed to turn one vendor's CSV layout into bars.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvImportConfig {
+    pub columns: CsvColumnMapping,
+    pub timestamp_format: CsvTimestampFormat,
+    /// IANA name of the timezone the timestamps are written in (e.g. `America/New_York`).
+    /// Ignored for unix timestamps.
+    pub timezone: String,
+    pub delimiter: u8,
+    pub coverage: CsvCoverage,
+}
+
+impl Default for CsvImportConfig {
+    fn default() -> Self {
+        CsvImportConfig {
+            columns: CsvColumnMapping::default(),
+            timestamp_format: CsvTimestampFormat::Pattern("[year]-[month]-[day] [hour]:[minute]:[second]".to_string()),
+            timezone: "UTC".to_string(),
+            delimiter: b',',
+            coverage: CsvCoverage::Bars,
+        }
+    }
+}
+
+/// Parses timestamps according to a CsvImportConfig, compiling the pattern only once.
+struct TimestampParser {
+    format: CsvTimestampFormat,
+    pattern: Option<OwnedFormatItem>,
+    timezone: String,
+}
+
+impl TimestampParser {
+    fn new(config: &CsvImportConfig) -> Result<Self, Error> {
+        let pattern = match &config.timestamp_format {
+            CsvTimestampFormat::Pattern(pattern) => Some(format_description::parse_owned::<2>(pattern)
+                .with_context(|| format!("Invalid timestamp pattern '{}'", pattern))?),
+            _ => None,
+        };
+        if config.timezone != "UTC" && timezones::get_by_name(&config.timezone).is_none() {
+            bail!("Unknown timezone '{}'", config.timezone);
+        }
+        Ok(TimestampParser {
+            format: config.timestamp_format.clone(),
+            pattern,
+            timezone: config.timezone.clone(),
+        })
+    }
+
+    fn parse(&self, value: &str) -> Result<i64, Error> {
+        let value = value.trim();
+        match (&self.format, &self.pattern) {
+            (CsvTimestampFormat::UnixSeconds, _) => Ok(value.parse::<i64>()?),
+            (CsvTimestampFormat::UnixMillis, _) => Ok(value.parse::<i64>()?.div_euclid(1000)),
+            (CsvTimestampFormat::Pattern(_), Some(pattern)) => {
+                let local = match PrimitiveDateTime::parse(value, pattern) {
+                    Ok(datetime) => datetime,
+                    Err(_) => PrimitiveDateTime::new(Date::parse(value, pattern)?, Time::MIDNIGHT),
+                };
+                Ok(self.localize(local)?.unix_timestamp())
+            },
+            _ => bail!("Timestamp pattern was not compiled"),
+        }
+    }
+
+    fn localize(&self, local: PrimitiveDateTime) -> Result<OffsetDateTime, Error> {
+        if self.timezone == "UTC" {
+            return Ok(local.assume_utc());
+        }
+        // the timezone was validated in new()
+        let tz = timezones::get_by_name(&self.timezone).unwrap();
+        // during the DST fall-back hour a local time is ambiguous, take the first occurrence
+        match local.assume_timezone(tz).take_first() {
+            Some(datetime) => Ok(datetime),
+            None => bail!("'{}' does not exist in timezone {}", local, self.timezone),
+        }
+    }
+}
+
+fn parse_column<T: std::str::FromStr>(record: &csv::StringRecord, index: usize, name: &str, line: u64) -> Result<T, Error>
+    where T::Err: std::error::Error + Send + Sync + 'static {
+    let value = match record.get(index) {
+        Some(value) => value.trim(),
+        None => bail!("Line {}: missing column '{}'", line, name),
+    };
+    value.parse::<T>().with_context(|| format!("Line {}: could not parse '{}' in column '{}'", line, value, name))
+}
+
+fn column_index(headers: &csv::StringRecord, name: &str) -> Result<usize, Error> {
+    match headers.iter().position(|header| header.trim() == name) {
+        Some(index) => Ok(index),
+        None => bail!("CSV file has no column named '{}'", name),
+    }
+}
+
+/// Reads every row of the CSV file at `path` into an IBApiBar, sorted by date.
+/// Missing volume/count default to 0, a missing wap defaults to the typical price (high + low + close) / 3.
+/// Rows that do not make a valid bar (e.g. a high below the close) are rejected.
+pub fn read_csv_bars<P: AsRef<Path>>(path: P, config: &CsvImportConfig) -> Result<Vec<IBApiBar>, Error> {
+    let parser = TimestampParser::new(config)?;
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(config.delimiter)
+        .from_path(path.as_ref())
+        .with_context(|| format!("Could not open {}", path.as_ref().display()))?;
+
+    let headers = reader.headers()?.clone();
+    let columns = &config.columns;
+    let date = column_index(&headers, &columns.date)?;
+    let open = column_index(&headers, &columns.open)?;
+    let high = column_index(&headers, &columns.high)?;
+    let low = column_index(&headers, &columns.low)?;
+    let close = column_index(&headers, &columns.close)?;
+    let volume = columns.volume.as_ref().map(|name| column_index(&headers, name)).transpose()?;
+    let count = columns.count.as_ref().map(|name| column_index(&headers, name)).transpose()?;
+    let wap = columns.wap.as_ref().map(|name| column_index(&headers, name)).transpose()?;
+
+    let mut bars = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        // header is line 1
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let timestamp = match record.get(date) {
+            Some(value) => parser.parse(value).with_context(|| format!("Line {}: could not parse timestamp '{}'", line, value))?,
+            None => bail!("Line {}: missing column '{}'", line, columns.date),
+        };
+        let open_value: f64 = parse_column(&record, open, &columns.open, line)?;
+        let high_value: f64 = parse_column(&record, high, &columns.high, line)?;
+        let low_value: f64 = parse_column(&record, low, &columns.low, line)?;
+        let close_value: f64 = parse_column(&record, close, &columns.close, line)?;
+        let volume_value: f64 = match (volume, &columns.volume) {
+            (Some(index), Some(name)) => parse_column(&record, index, name, line)?,
+            _ => 0.0,
+        };
+        let count_value: i32 = match (count, &columns.count) {
+            (Some(index), Some(name)) => parse_column(&record, index, name, line)?,
+            _ => 0,
+        };
+        let mut builder = IBApiBar::builder()
+            .date(timestamp)
+            .ohlc(open_value, high_value, low_value, close_value)
+            .volume(volume_value)
+            .count(count_value);
+        if let (Some(index), Some(name)) = (wap, &columns.wap) {
+            builder = builder.wap(parse_column(&record, index, name, line)?);
+        }
+        bars.push(builder.build().with_context(|| format!("Line {}: invalid bar", line))?);
+    }
+    bars.sort_by_key(|bar| bar.date);
+    Ok(bars)
+}
+
+/// Reads a CSV file and merges its bars into `<storage_directory>/<ticker>/<barsize>.<json|bin>`
+/// (written in `format`), creating the file if needed. Bars already on disk with the same timestamp
+/// are replaced, and the windows given by the config's `coverage` are marked as cached.
+/// Returns the number of bars read from the file.
+pub fn import_csv<P: AsRef<Path>>(storage_directory: &str, ticker: &str, timeframe: HashedBarSize, csv_path: P, config: &CsvImportConfig, format: StorageFormat) -> Result<usize, Error> {
+    let bars = read_csv_bars(csv_path, config)?;
+    let (first, last) = match (bars.first(), bars.last()) {
+        (Some(first), Some(last)) => (first.date, last.date),
+        _ => return Ok(0),
+    };
+    let mut data_store = open_data_store(storage_directory, ticker, &timeframe, format)?;
+    let count = bars.len();
+    match config.coverage {
+        CsvCoverage::Bars => merge_bar_windows(&mut data_store, &timeframe, bars)?,
+        CsvCoverage::Span => merge_bars(&mut data_store, first, last, bars),
+    }
+    data_store.flush()?;
+    Ok(count)
+}
+
+/// Merges sorted `bars` with the windows they stand for, one merge per run of touching windows.
+fn merge_bar_windows(data_store: &mut BarStorage, timeframe: &HashedBarSize, bars: Vec<IBApiBar>) -> Result<(), Error> {
+    let mut run = Vec::new();
+    let mut covered: Option<(i64, i64)> = None;
+    for bar in bars {
+        let window = bar_window(timeframe, bar.date)?;
+        covered = match covered {
+            Some((start, end)) if *window.start() <= end + 1 => Some((start, end.max(*window.end()))),
+            Some((start, end)) => {
+                merge_bars(data_store, start, end, std::mem::take(&mut run));
+                Some((*window.start(), *window.end()))
+            },
+            None => Some((*window.start(), *window.end())),
+        };
+        run.push(bar);
+    }
+    if let Some((start, end)) = covered {
+        merge_bars(data_store, start, end, run);
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use ibapi_handler::{HistoricalSeries, Instrument};
+
+/// What the broker caches bars under: an instrument plus the series (what to show, trading hours)
+/// requested for it, so e.g. bid bars never overwrite trade bars.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SeriesKey {
+    pub instrument: Instrument,
+    pub series: HistoricalSeries,
+}
+
+impl SeriesKey {
+    pub fn new<I: Into<Instrument>>(instrument: I, series: HistoricalSeries) -> Self {
+        SeriesKey {
+            instrument: instrument.into(),
+            series,
+        }
+    }
+
+    /// The directory the bars are cached in: the instrument's directory for regular-hours trades
+    /// (`AAPL`), with the series appended otherwise (`AAPL@bid_rth`, `AAPL@trades_eth`).
+    /// Instrument directory names escape `@`, so no instrument's directory looks like a series of another.
+    pub fn directory_name(&self) -> String {
+        let instrument = self.instrument.directory_name();
+        if self.series == HistoricalSeries::default() {
+            instrument
+        } else {
+            format!("{}@{}", instrument, self.series.name())
+        }
+    }
+}
+
+/// Instruments and plain tickers stand for their regular-hours trades.
+impl From<Instrument> for SeriesKey {
+    fn from(instrument: Instrument) -> Self {
+        SeriesKey::new(instrument, HistoricalSeries::default())
+    }
+}
+
+impl From<&Instrument> for SeriesKey {
+    fn from(instrument: &Instrument) -> Self {
+        SeriesKey::new(instrument, HistoricalSeries::default())
+    }
+}
+
+impl From<&str> for SeriesKey {
+    fn from(ticker: &str) -> Self {
+        SeriesKey::new(ticker, HistoricalSeries::default())
+    }
+}
+
+impl From<String> for SeriesKey {
+    fn from(ticker: String) -> Self {
+        SeriesKey::new(ticker, HistoricalSeries::default())
+    }
+}
+
+impl From<&String> for SeriesKey {
+    fn from(ticker: &String) -> Self {
+        SeriesKey::new(ticker, HistoricalSeries::default())
+    }
+}
+
+impl From<&SeriesKey> for SeriesKey {
+    fn from(key: &SeriesKey) -> Self {
+        key.clone()
+    }
+}
@@ -1,11 +1,28 @@
 use ibapi;
-use range_data_storage::range_data_storage::RangeDataStorage;
+use range_data_storage::range_data_storage::{backup_location, PointDataStorage, RangeDataStorage};
 use std::collections::HashMap;
 use anyhow::{bail, Error};
 use std::path::Path;
+use std::ops::RangeInclusive;
 use ibapi::market_data::historical::BarSize;
 use time::OffsetDateTime;
-use ibapi_handler::{IBApiBar, IbapiHandler};
+use ibapi_handler::{IBApiBar, IbapiConfig, IbapiHandler};
+
+pub mod calendar;
+pub mod importer;
+pub mod key;
+pub mod migrate;
+pub mod resample;
+pub mod source;
+pub mod synthetic;
+
+pub use calendar::{HolidayRules, HolidayTable, Session, TradingCalendar, TradingHours};
+pub use importer::{CsvColumnMapping, CsvCoverage, CsvImportConfig, CsvTimestampFormat};
+pub use source::HistoricalDataSource;
+pub use synthetic::{PriceModel, Regime, SyntheticSource, VolumeProfile};
+pub use range_data_storage::range_data_storage::StorageFormat;
+pub use ibapi_handler::{HashedWhatToShow, HistoricalSeries, Instrument, InstrumentType, OfflineError, OptionRight};
+pub use key::SeriesKey;
 
 #[derive(Clone, Debug, Copy, Eq, PartialEq, Hash)]
 pub enum HashedBarSize {
@@ -79,71 +96,159 @@ impl HashedBarSize {
         }
     }
 
+    /// Parses a data file name such as `min15.json` or `min15.bin`.
     pub fn from_filename(filename: &String) -> Result<Self, Error> {
-        match filename.as_str() {
-            "sec.json" => Ok(HashedBarSize::Sec),
-            "sec5.json" => Ok(HashedBarSize::Sec5),
-            "sec15.json" => Ok(HashedBarSize::Sec15),
-            "sec30.json" => Ok(HashedBarSize::Sec30),
-            "min.json" => Ok(HashedBarSize::Min),
-            "min2.json" => Ok(HashedBarSize::Min2),
-            "min3.json" => Ok(HashedBarSize::Min3),
-            "min5.json" => Ok(HashedBarSize::Min5),
-            "min15.json" => Ok(HashedBarSize::Min15),
-            "min20.json" => Ok(HashedBarSize::Min20),
-            "min30.json" => Ok(HashedBarSize::Min30),
-            "hour.json" => Ok(HashedBarSize::Hour),
-            "hour2.json" => Ok(HashedBarSize::Hour2),
-            "hour3.json" => Ok(HashedBarSize::Hour3),
-            "hour4.json" => Ok(HashedBarSize::Hour4),
-            "hour8.json" => Ok(HashedBarSize::Hour8),
-            "day.json" => Ok(HashedBarSize::Day),
-            "week.json" => Ok(HashedBarSize::Week),
-            "month.json" => Ok(HashedBarSize::Month),
+        let stem = match filename.rsplit_once('.') {
+            Some((stem, "json")) | Some((stem, "bin")) => stem,
+            _ => bail!("Could not get HashedBarSize from '{}'", filename)
+        };
+        match stem {
+            "sec" => Ok(HashedBarSize::Sec),
+            "sec5" => Ok(HashedBarSize::Sec5),
+            "sec15" => Ok(HashedBarSize::Sec15),
+            "sec30" => Ok(HashedBarSize::Sec30),
+            "min" => Ok(HashedBarSize::Min),
+            "min2" => Ok(HashedBarSize::Min2),
+            "min3" => Ok(HashedBarSize::Min3),
+            "min5" => Ok(HashedBarSize::Min5),
+            "min15" => Ok(HashedBarSize::Min15),
+            "min20" => Ok(HashedBarSize::Min20),
+            "min30" => Ok(HashedBarSize::Min30),
+            "hour" => Ok(HashedBarSize::Hour),
+            "hour2" => Ok(HashedBarSize::Hour2),
+            "hour3" => Ok(HashedBarSize::Hour3),
+            "hour4" => Ok(HashedBarSize::Hour4),
+            "hour8" => Ok(HashedBarSize::Hour8),
+            "day" => Ok(HashedBarSize::Day),
+            "week" => Ok(HashedBarSize::Week),
+            "month" => Ok(HashedBarSize::Month),
             _ => bail!("Could not get HashedBarSize from '{}'", filename)
         }
     }
 
-    pub fn to_location(&self) -> String {
+    /// The file name without extension, e.g. `min15`.
+    pub fn file_stem(&self) -> &'static str {
         match self {
-            HashedBarSize::Sec => "sec.json".to_string(),
-            HashedBarSize::Sec5 => "sec5.json".to_string(),
-            HashedBarSize::Sec15 => "sec15.json".to_string(),
-            HashedBarSize::Sec30 => "sec30.json".to_string(),
-            HashedBarSize::Min => "min.json".to_string(),
-            HashedBarSize::Min2 => "min2.json".to_string(),
-            HashedBarSize::Min3 => "min3.json".to_string(),
-            HashedBarSize::Min5 => "min5.json".to_string(),
-            HashedBarSize::Min15 => "min15.json".to_string(),
-            HashedBarSize::Min20 => "min20.json".to_string(),
-            HashedBarSize::Min30 => "min30.json".to_string(),
-            HashedBarSize::Hour => "hour.json".to_string(),
-            HashedBarSize::Hour2 => "hour2.json".to_string(),
-            HashedBarSize::Hour3 => "hour3.json".to_string(),
-            HashedBarSize::Hour4 => "hour4.json".to_string(),
-            HashedBarSize::Hour8 => "hour8.json".to_string(),
-            HashedBarSize::Day => "day.json".to_string(),
-            HashedBarSize::Week => "week.json".to_string(),
-            HashedBarSize::Month => "month.json".to_string(),
+            HashedBarSize::Sec => "sec",
+            HashedBarSize::Sec5 => "sec5",
+            HashedBarSize::Sec15 => "sec15",
+            HashedBarSize::Sec30 => "sec30",
+            HashedBarSize::Min => "min",
+            HashedBarSize::Min2 => "min2",
+            HashedBarSize::Min3 => "min3",
+            HashedBarSize::Min5 => "min5",
+            HashedBarSize::Min15 => "min15",
+            HashedBarSize::Min20 => "min20",
+            HashedBarSize::Min30 => "min30",
+            HashedBarSize::Hour => "hour",
+            HashedBarSize::Hour2 => "hour2",
+            HashedBarSize::Hour3 => "hour3",
+            HashedBarSize::Hour4 => "hour4",
+            HashedBarSize::Hour8 => "hour8",
+            HashedBarSize::Day => "day",
+            HashedBarSize::Week => "week",
+            HashedBarSize::Month => "month",
+        }
+    }
+
+    /// The JSON data file name, e.g. `min15.json`.
+    pub fn to_location(&self) -> String {
+        self.to_location_for(StorageFormat::Json)
+    }
+
+    /// The data file name for a storage format, e.g. `min15.bin`.
+    pub fn to_location_for(&self, format: StorageFormat) -> String {
+        format!("{}.{}", self.file_stem(), format.extension())
+    }
+}
+
+/// The per ticker/barsize storage: one bar per timestamp, plus the windows that have been fetched.
+pub type BarStorage = PointDataStorage<i64, IBApiBar>;
+
+/// Stores `bars` fetched for `start..=end` in `store`, marking the whole window as cached.
+/// Bars sharing a timestamp with stored ones replace them, nothing else is touched.
+pub(crate) fn merge_bars(store: &mut BarStorage, start: i64, end: i64, bars: Vec<IBApiBar>) {
+    store.insert(start, end, bars.into_iter().map(|bar| (bar.date, bar)));
+}
+
+/// Loads the BarStorage at `location` (empty if there is no file yet).
+/// Files written before bars were stored one by one, as a RangeDataStorage of bar vectors,
+/// are converted on the fly and rewritten in the new layout on the next flush.
+pub(crate) fn load_bar_storage(location: String) -> Result<BarStorage, Error> {
+    match BarStorage::new(Some(location.clone())) {
+        Ok(data) => Ok(data),
+        Err(e) => {
+            let legacy: RangeDataStorage<i64, Vec<IBApiBar>> = match RangeDataStorage::new(Some(location.clone())) {
+                Ok(legacy) => legacy,
+                Err(_) => return Err(e),
+            };
+            log::info!("Converting {} from range chunks to per-bar storage", location);
+            Ok(BarStorage::from_range_storage(&legacy, |bar| bar.date))
+        }
+    }
+}
+
+/// Whether a data file, or the backup RangeDataStorage would recover it from, exists.
+fn data_file_exists(location: &str) -> bool {
+    Path::new(location).exists() || Path::new(&backup_location(location)).exists()
+}
+
+/// Opens the BarStorage of a ticker/barsize pair under `storage_directory`, backed by its file in `format`.
+/// If the pair has only been stored in another format so far (not migrated yet), that file is loaded
+/// instead and the data is written in `format` on the next flush.
+pub(crate) fn open_data_store(storage_directory: &str, ticker: &str, timeframe: &HashedBarSize, format: StorageFormat) -> Result<BarStorage, Error> {
+    let directory = Path::new(storage_directory).join(ticker);
+    let location = DataBroker::convert_osstr_to_string(Some(directory.join(timeframe.to_location_for(format)).as_os_str()))?;
+    if !data_file_exists(&location) {
+        for other in [StorageFormat::Json, StorageFormat::Binary] {
+            let other_location = DataBroker::convert_osstr_to_string(Some(directory.join(timeframe.to_location_for(other)).as_os_str()))?;
+            if other != format && data_file_exists(&other_location) {
+                let mut data = load_bar_storage(other_location)?;
+                data.set_location(location);
+                data.set_format(format);
+                return Ok(data);
+            }
         }
     }
+    let mut data = load_bar_storage(location)?;
+    data.set_format(format);
+    Ok(data)
+}
+
+/// The built-in exchange calendars, by name.
+fn default_calendars() -> HashMap<String, TradingCalendar> {
+    [TradingCalendar::nyse(), TradingCalendar::nasdaq(), TradingCalendar::cme()]
+        .into_iter()
+        .map(|calendar| (calendar.name.clone(), calendar))
+        .collect()
 }
 
 pub struct DataBroker{
     storage_directory: String, // the root directory of the data
-    ticker_map: HashMap<String, Option<HashMap<HashedBarSize, Option<RangeDataStorage<i64, Vec<IBApiBar>>>>>>, // map from tickers to bar sizes to data
-    ibapi_handler: IbapiHandler,
+    storage_format: StorageFormat, // the format data files are written in
+    ticker_map: HashMap<String, Option<HashMap<HashedBarSize, Option<BarStorage>>>>, // map from instrument directory names to bar sizes to data
+    source: Box<dyn HistoricalDataSource>, // where cache misses are fetched from
+    calendars: HashMap<String, TradingCalendar>, // exchange calendars by name, see calendar::calendar_name
+    use_calendars: bool, // whether gaps outside of trading sessions are skipped
 }
 
 /// lazily maps available tickers in storage directory to bar sizes.
 /// initially the bar sizes point to None until the data is requested,
 /// after which the data is retrieved from disk.
 impl DataBroker {
-    pub fn new(storage_location: Option<String>) -> Result<Self, Error> {
+    /// Creates a broker rooted at `storage_location` (defaults to `@data`) that fetches
+    /// anything it does not have cached from the TWS/Gateway described by `config`.
+    /// The connection is only made on the first cache miss, so cached data is served while TWS is down;
+    /// misses then fail with an `OfflineError`.
+    pub fn new(storage_location: Option<String>, config: IbapiConfig) -> Result<Self, Error> {
+        Self::with_source(storage_location, Box::new(IbapiHandler::lazy(config)))
+    }
+
+    /// Creates a broker rooted at `storage_location` (defaults to `@data`) that fetches
+    /// anything it does not have cached from `source`.
+    pub fn with_source(storage_location: Option<String>, source: Box<dyn HistoricalDataSource>) -> Result<Self, Error> {
         let loc = storage_location.unwrap_or_else(|| "@data".to_string());
         let path = Path::new(&loc);
-        // TODO: Do i really need to initialize this before I need it? Could be an Option instead.
-        let handler = IbapiHandler::new()?;
 
         let exists = path.try_exists()?;
         return if !exists {
@@ -151,8 +256,11 @@ impl DataBroker {
             std::fs::create_dir_all(path)?;
             Ok(DataBroker {
                 storage_directory: loc,
+                storage_format: StorageFormat::Json,
                 ticker_map: HashMap::new(),
-                ibapi_handler: handler,
+                source,
+                calendars: default_calendars(),
+                use_calendars: true,
             })
         } else { // file exists, need to lazily evaluate hashmap
             let mut ticker_map = HashMap::new();
@@ -170,12 +278,60 @@ impl DataBroker {
             }
             Ok(DataBroker {
                 storage_directory: loc,
+                storage_format: StorageFormat::Json,
                 ticker_map,
-                ibapi_handler: handler,
+                source,
+                calendars: default_calendars(),
+                use_calendars: true,
             })
         }
     }
 
+    /// Replaces the calendar called `name` (NYSE, NASDAQ or CME), e.g. with one that has extra holidays loaded.
+    pub fn set_calendar(&mut self, name: &str, calendar: TradingCalendar) {
+        self.calendars.insert(name.to_uppercase(), calendar);
+    }
+
+    /// The calendar `key`'s instrument trades on, if the broker knows one and calendars are in use.
+    pub fn calendar_for<K: Into<SeriesKey>>(&self, key: K) -> Option<&TradingCalendar> {
+        if !self.use_calendars {
+            return None;
+        }
+        calendar::calendar_name(&key.into().instrument).and_then(|name| self.calendars.get(name))
+    }
+
+    /// Whether parts of a window in which the exchange does not trade are marked as cached without asking
+    /// the data source, and resampling follows the exchange's sessions (on by default).
+    /// Without calendars every gap is requested, weekends included.
+    pub fn set_use_calendars(&mut self, use_calendars: bool) {
+        self.use_calendars = use_calendars;
+    }
+
+    /// The format the broker writes its data files in.
+    pub fn storage_format(&self) -> StorageFormat {
+        self.storage_format
+    }
+
+    /// Selects the format the broker writes its data files in (JSON by default).
+    /// Files in the other format are still read, and rewritten in this format when they next change.
+    pub fn set_storage_format(&mut self, format: StorageFormat) -> Result<(), Error> {
+        self.storage_format = format;
+        for (ticker, bar_size_map) in self.ticker_map.iter_mut() {
+            let bar_size_map = match bar_size_map {
+                Some(bar_map) => bar_map,
+                None => continue,
+            };
+            for (timeframe, data_store) in bar_size_map.iter_mut() {
+                if let Some(data_store) = data_store {
+                    let path = Path::new(&self.storage_directory).join(ticker).join(timeframe.to_location_for(format));
+                    data_store.set_location(Self::convert_osstr_to_string(Some(path.as_os_str()))?);
+                    data_store.set_format(format);
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Returning None on this means that "this directory exists but i dont know what's in it".
     /// Returning Some(None) is not a valid state.
     /// Returning Some(
@@ -185,17 +341,23 @@ impl DataBroker {
 
     /// For a ticker/barsize pair, checks if the data has been realized
     /// (loaded into memory from disk)
-    fn pull_range_data_store_from_disk(&self, ticker: &String, timeframe: &HashedBarSize) -> Result<RangeDataStorage<i64, Vec<IBApiBar>>, Error> {
-        let path =
-            Path::new(&self.storage_directory)
-            .join(ticker)
-            .join(timeframe.to_location());
-        if !path.exists() {
-            bail!("Timeframe data does not exist for ticker: {} at path: {}", ticker, path.display());
+    fn pull_range_data_store_from_disk(&self, ticker: &String, timeframe: &HashedBarSize) -> Result<BarStorage, Error> {
+        let directory = Path::new(&self.storage_directory).join(ticker);
+        let mut exists = false;
+        for format in [StorageFormat::Json, StorageFormat::Binary] {
+            let path_str = Self::convert_osstr_to_string(Some(directory.join(timeframe.to_location_for(format)).as_os_str()))?;
+            exists = exists || data_file_exists(&path_str);
+        }
+        if !exists {
+            bail!("Timeframe data does not exist for ticker: {} in: {}", ticker, directory.display());
         }
-        let data = std::fs::read_to_string(path)?;
-        let data: RangeDataStorage<i64, Vec<IBApiBar>> = serde_json::from_str(&data)?;
-        Ok(data)
+        open_data_store(&self.storage_directory, ticker, timeframe, self.storage_format)
+    }
+
+    /// Starts an empty BarStorage for a ticker/barsize pair that has nothing on disk yet,
+    /// backed by the file it will be flushed to.
+    fn new_range_data_store(&self, ticker: &String, timeframe: &HashedBarSize) -> Result<BarStorage, Error> {
+        open_data_store(&self.storage_directory, ticker, timeframe, self.storage_format)
     }
 
     pub fn convert_osstr_to_string(osstr: Option<&std::ffi::OsStr>) -> Result<String, Error> {
@@ -210,7 +372,7 @@ impl DataBroker {
         }
     }
 
-    fn realize_ticker_dir(&self, ticker: &String) -> Result<HashMap<HashedBarSize, Option<RangeDataStorage<i64, Vec<IBApiBar>>>>, Error> {
+    fn realize_ticker_dir(&self, ticker: &String) -> Result<HashMap<HashedBarSize, Option<BarStorage>>, Error> {
         // check if the ticker directory exists, else create it
         let path = Path::new(&self.storage_directory).join(ticker);
         if !path.exists() {
@@ -223,6 +385,12 @@ impl DataBroker {
             let path = entry.path();
             if path.is_file() {
                 let file_name = Self::convert_osstr_to_string(path.file_name())?;
+                // leftovers of an interrupted save, the data file itself is still intact
+                if file_name.ends_with(".tmp") {
+                    continue;
+                }
+                // a backup on its own still counts, the storage recovers from it
+                let file_name = file_name.strip_suffix(".bak").unwrap_or(&file_name).to_string();
                 let bar_size = HashedBarSize::from_filename(&file_name)?;
                 bar_size_map.insert(bar_size, None);
             }
@@ -230,31 +398,10 @@ impl DataBroker {
         Ok(bar_size_map)
     }
 
-    /// Given a ticker, bar size, start date, and end date, retrieves the data from ibapi and saves it to disk.
-    fn get_data_and_save(&mut self, ticker: &String, timeframe: &HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<RangeDataStorage<i64, Vec<IBApiBar>>, Error> {
-        println!("In get_data_and_save");
-        println!("Getting data for ticker: {} and timeframe: {:?}", ticker, timeframe);
-        let contract = ibapi::contracts::Contract::stock(ticker.as_str());
-        let data = self.ibapi_handler.get_historical_data(&contract, timeframe.to_bar_size(), start_date, end_date)?;
-        let path = Path::new(&self.storage_directory).join(ticker).join(timeframe.to_location());
-        let path_str = Self::convert_osstr_to_string(Some(path.as_os_str()))?;
-        let mut range_data_storage = RangeDataStorage::new(Some(path_str))?;
-        let vec_data = Vec::from(data);
-        range_data_storage.insert(start_date.unix_timestamp(), end_date.unix_timestamp(), vec_data);
-        let str_path = Self::convert_osstr_to_string(Some(path.as_os_str()))?;
-        println!("Saving data to path: {}", str_path);
-        let save_result = range_data_storage.save(str_path);
-        match save_result {
-            Ok(_) => Ok(range_data_storage),
-            Err(e) => bail!(e)
-        }
-    }
-
-    /// Given some ticker, retrieves the data for that ticker and timeframe.
-    /// Checks if data exists in the ticker_map/range_data_storage, if so, retrieves from disk.
-    /// Otherwise, retrieves the data using ibapi.
+    /// Makes sure the BarStorage for a ticker/barsize pair is realized in memory,
+    /// pulling it from disk if it exists there or starting an empty one otherwise.
     /// The ticker_map is:
-    /// map of tickers -> Option<Map of bar sizes -> Option<RangeDataStorage>>
+    /// map of tickers -> Option<Map of bar sizes -> Option<BarStorage>>
     /// ticker_map.get(ticker) -> None, ticker does not exist in the map
     /// ticker_map.get(ticker) -> Some(None), ticker exists but has not been fully evaluated
     /// ticker_map.get(ticker) -> Some(Some(bar_size_map)), ticker exists, and has at least
@@ -262,114 +409,235 @@ impl DataBroker {
     ///
     /// bar_size_map.get(BarSize) -> None, bar size does not exist in the map
     /// bar_size_map.get(BarSize) -> Some(None), bar size exists but has not been fully evaluated
-    /// bar_size_map.get(BarSize) -> Some(Some(RangeDataStorage)), bar size exists and has been fully evaluated
-    ///
-    /// TODO: THIS FUNCTION SHOULD ONLY BE ALLOWED TO FAIL IF WE EXHAUST ALL POSSIBLE DATA SOURCES
-    pub fn retrieve_data(&mut self, ticker: String, timeframe: HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
-        println!("start of retrieve_data");
+    /// bar_size_map.get(BarSize) -> Some(Some(BarStorage)), bar size exists and has been fully evaluated
+    fn realize_data_store(&mut self, ticker: &String, timeframe: &HashedBarSize) -> Result<&mut BarStorage, Error> {
         // step 1. check if the bar size map exists
-        match self.ticker_map.get(&ticker) {
-            // We do not have knowledge of this ticker, so we need request it using
-            // the ibapi_handler.
+        match self.ticker_map.get(ticker) {
             None | Some(None) => {
-                let bar_size_map = self.realize_ticker_dir(&ticker)?;
+                let bar_size_map = self.realize_ticker_dir(ticker)?;
                 self.ticker_map.insert(ticker.clone(), Some(bar_size_map));
             },
             _ => {}
         }
 
-        let bar_size_map = self.ticker_map.get_mut(&ticker);
-        let bar_size_map = match bar_size_map {
+        // step 2. check if the range data storage exists
+        // bar size map maps the bar size (15 seconds, 30 seconds, etc) to the BarStorage containing it
+        let realized = match self.ticker_map.get(ticker) {
+            Some(Some(bar_map)) => bar_map.get(timeframe).map(|data| data.is_some()),
+            _ => bail!("Could not realize data for ticker: {} and timeframe: {:?}", ticker, timeframe)
+        };
+        let loaded = match realized {
+            // nothing on disk for this bar size yet
+            None => Some(self.new_range_data_store(ticker, timeframe)?),
+            // on disk, but not loaded yet
+            Some(false) => Some(self.pull_range_data_store_from_disk(ticker, timeframe)?),
+            Some(true) => None,
+        };
+
+        let bar_size_map = match self.ticker_map.get_mut(ticker) {
             Some(Some(bar_map)) => bar_map,
-            _ => bail!("Could not retrieve data for ticker: {} and timeframe: {:?}", ticker, timeframe)
+            _ => bail!("Could not realize data for ticker: {} and timeframe: {:?}", ticker, timeframe)
         };
+        if let Some(data) = loaded {
+            bar_size_map.insert(*timeframe, Some(data));
+        }
+        match bar_size_map.get_mut(timeframe) {
+            Some(Some(data)) => Ok(data),
+            _ => bail!("Could not realize data for ticker: {} and timeframe: {:?}", ticker, timeframe)
+        }
+    }
 
-        let mut owned_bar_size_map = bar_size_map.clone();
+    /// Flushes the realized BarStorage for a ticker/barsize pair to `<storage>/<ticker>/<barsize>.<json|bin>`.
+    fn save_data_store(&mut self, ticker: &String, timeframe: &HashedBarSize) -> Result<(), Error> {
+        let data_store = self.realize_data_store(ticker, timeframe)?;
+        log::debug!("Saving data to path: {:?}", data_store.location());
+        data_store.flush()
+    }
 
-        // step 2. check if the range data storage exists
-        // bar size map maps the bar size (15 seconds, 30 seconds, etc) to the RangeDataStorage containing it
-
-        match owned_bar_size_map.get(&timeframe) {
-            // We do not have knowledge of this bar size, so we need to request it using
-            // the ibapi_handler.
-            None => {
-                println!("Data does not exist in bar size map");
-                let data = self.get_data_and_save(&ticker, &timeframe, start_date, end_date)?;
-                owned_bar_size_map.insert(timeframe, Some(data));
-                println!("Data inserted into bar size map");
-            },
-            Some(None) => {
-                println!("Data partially realized");
-                let data = self.pull_range_data_store_from_disk(&ticker, &timeframe)?;
-                owned_bar_size_map.insert(timeframe, Some(data));
-            },
-            Some(Some(_)) => {
-                println!("Data already instantiated")
+    /// Asks the data source for the bars of a series/barsize pair between two unix timestamps.
+    fn fetch_from_source(&mut self, key: &SeriesKey, timeframe: &HashedBarSize, start: i64, end: i64) -> Result<Vec<IBApiBar>, Error> {
+        log::debug!("Getting data for: {} and timeframe: {:?} from {} to {}", key.directory_name(), timeframe, start, end);
+        let start_date = OffsetDateTime::from_unix_timestamp(start)?;
+        let end_date = OffsetDateTime::from_unix_timestamp(end)?;
+        self.source.fetch_bars(&key.instrument, &key.series, *timeframe, start_date, end_date)
+    }
+
+    /// Builds the bars of `gap` by resampling a finer bar size of the same series, if one is cached
+    /// for everything the gap needs (see resample::source_window). Returns None if none is.
+    /// With a calendar, bars are aligned to its sessions (see resample::resample_in_sessions).
+    fn resample_from_cache(&mut self, key: &SeriesKey, calendar: Option<&TradingCalendar>, timeframe: &HashedBarSize, gap: &RangeInclusive<i64>) -> Result<Option<Vec<IBApiBar>>, Error> {
+        let ticker = key.directory_name();
+        let available: Vec<HashedBarSize> = match self.ticker_map.get(&ticker) {
+            Some(Some(bar_map)) => resample::finer_sizes(timeframe).into_iter().filter(|size| bar_map.contains_key(size)).collect(),
+            _ => return Ok(None),
+        };
+        let extended = !key.series.use_rth;
+        let window = match calendar {
+            Some(calendar) => resample::source_window_in_sessions(timeframe, *gap.start(), *gap.end(), calendar, extended)?,
+            None => resample::source_window(timeframe, *gap.start(), *gap.end())?,
+        };
+        for source in available {
+            let data_store = self.realize_data_store(&ticker, &source)?;
+            if !data_store.contains_range(window.start(), window.end()) {
+                continue;
             }
+            log::debug!("Resampling {:?} bars of {} into {:?} from {} to {}", source, ticker, timeframe, gap.start(), gap.end());
+            let bars: Vec<IBApiBar> = data_store.range(window.clone()).map(|(_, bar)| bar.clone()).collect();
+            let resampled = match calendar {
+                Some(calendar) => resample::resample_in_sessions(&bars, &source, timeframe, calendar, extended)?,
+                None => resample::resample(&bars, &source, timeframe)?,
+            };
+            return Ok(Some(resampled.into_iter().filter(|bar| gap.contains(&bar.date)).collect()));
         }
+        Ok(None)
+    }
 
-        let data_store = owned_bar_size_map.get_mut(&timeframe);
-        let data_store = match data_store {
-            Some(Some(data)) => data,
-            _ => bail!("Could not retrieve data for ticker: {} and timeframe: {:?}", ticker, timeframe)
-        };
+    /// Given some instrument (or a plain stock ticker), retrieves its bars for a timeframe inside [start_date, end_date].
+    /// Instruments and tickers stand for regular-hours trades, pass a SeriesKey for other series.
+    /// Only the sub-intervals of the window that are not cached yet are looked for: they are built from
+    /// cached finer bars where possible and requested from the broker's data source otherwise,
+    /// then merged into the cached data and written back to disk.
+    /// Sub-intervals in which the instrument's exchange does not trade (weekends, holidays, nights for
+    /// regular-hours series) are marked as cached without bars instead, see calendar_for.
+    ///
+    /// If a sub-interval can not be filled, the ones filled before it are still cached and saved
+    /// before the error is returned, so a retry only asks for what is left.
+    pub fn retrieve_data<K: Into<SeriesKey>>(&mut self, key: K, timeframe: HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+        let key = key.into();
+        let ticker = key.directory_name();
+        let start = start_date.unix_timestamp();
+        let end = end_date.unix_timestamp();
+        if start > end {
+            bail!("Start date {} is after end date {}", start_date, end_date);
+        }
 
-        let mut owned_data_store = data_store.clone();
-        println!("cloned data store size: {}", owned_data_store.len());
-        // step 3. check if the data exists in the range data storage
-        if let Some(value) = owned_data_store.get(start_date.unix_timestamp()) {
-            println!("Data exists in range data storage");
-            // before returning, we need to save the data store into the bar map, and the bar map into the ticker map
-            let cloned_val = value.clone();
-            owned_bar_size_map.insert(timeframe, Some(owned_data_store));
-            self.ticker_map.insert(ticker.clone(), Some(owned_bar_size_map));
-            return Ok(cloned_val);
+        // step 1. find out which parts of the window we do not have yet
+        let missing = self.realize_data_store(&ticker, &timeframe)?.missing_intervals(&start, &end);
+
+        // step 2. fetch only those, keeping whatever we managed to get even if a later fetch fails
+        if !missing.is_empty() {
+            let calendar = self.calendar_for(&key).cloned();
+            let mut fetch_result = Ok(());
+            for gap in missing {
+                let expected = match &calendar {
+                    Some(calendar) => calendar.expects_bars(&timeframe, *gap.start(), *gap.end(), !key.series.use_rth),
+                    None => Ok(true),
+                };
+                let fetched = match expected {
+                    Ok(false) => {
+                        log::debug!("{} does not trade from {} to {}, nothing to fetch", key.directory_name(), gap.start(), gap.end());
+                        Ok(Vec::new())
+                    },
+                    Ok(true) => match self.resample_from_cache(&key, calendar.as_ref(), &timeframe, &gap) {
+                        Ok(Some(bars)) => Ok(bars),
+                        Ok(None) => self.fetch_from_source(&key, &timeframe, *gap.start(), *gap.end()),
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                };
+                match fetched {
+                    Ok(bars) => {
+                        let data_store = self.realize_data_store(&ticker, &timeframe)?;
+                        merge_bars(data_store, *gap.start(), *gap.end(), bars);
+                    },
+                    Err(e) => {
+                        fetch_result = Err(e);
+                        break;
+                    }
+                }
+            }
+            self.save_data_store(&ticker, &timeframe)?;
+            fetch_result?;
+        }
+
+        // step 3. collect exactly the bars inside the window
+        let data_store = self.realize_data_store(&ticker, &timeframe)?;
+        Ok(data_store.range(start..=end).map(|(_, bar)| bar.clone()).collect())
+    }
+
+    /// Checks whether every timestamp in [start_date, end_date] is cached for a ticker/timeframe pair.
+    /// Only looks at what is in memory or on disk, the data source is never asked.
+    pub fn has_range<K: Into<SeriesKey>>(&mut self, key: K, timeframe: HashedBarSize, start_date: i64, end_date: i64) -> Result<bool, Error>{
+        let ticker = key.into().directory_name();
+        if start_date > end_date {
+            bail!("Start date {} is after end date {}", start_date, end_date);
         }
-        else {
-            println!("Data does not exist in range data storage");
+        // don't create directories for tickers we have never seen just to answer a question
+        let ticker_path = Path::new(&self.storage_directory).join(&ticker);
+        if !self.ticker_exists(ticker.clone()) && !ticker_path.exists() {
+            return Ok(false);
         }
+        let data_store = self.realize_data_store(&ticker, &timeframe)?;
+        Ok(data_store.missing_intervals(&start_date, &end_date).is_empty())
+    }
 
-        // step 4. if the data does not exist in the range data storage, we need to retrieve it from ibapi
-        let data = self.get_data_and_save(&ticker, &timeframe, start_date, end_date)?;
-        owned_data_store = data.clone();
-        let cloned_val = data.get(start_date.unix_timestamp()).unwrap().clone();
-        owned_bar_size_map.insert(timeframe, Some(owned_data_store));
-        self.ticker_map.insert(ticker.clone(), Some(owned_bar_size_map));
-        Ok(cloned_val)
-    }
-
-    // TODO
-    pub fn has_range(&mut self, ticker: String, timeframe: HashedBarSize, start_date: i64, end_date: i64) -> Result<bool, Error>{
-        !todo!("has_range not implemented yet");
-        if let Some(None) = self.ticker_map.get(&ticker) {
-            // ticker exists but hasn't been fully serialized yet
-            Ok(false)
-        } else {
-            Ok(false)
+    /// The windows cached for a ticker/timeframe pair, in order, as unix timestamp ranges.
+    /// Like has_range, this never asks the data source.
+    pub fn cached_intervals<K: Into<SeriesKey>>(&mut self, key: K, timeframe: HashedBarSize) -> Result<Vec<RangeInclusive<i64>>, Error> {
+        let ticker = key.into().directory_name();
+        let ticker_path = Path::new(&self.storage_directory).join(&ticker);
+        if !self.ticker_exists(ticker.clone()) && !ticker_path.exists() {
+            return Ok(Vec::new());
         }
+        Ok(self.realize_data_store(&ticker, &timeframe)?.covered_intervals())
     }
 
-    pub fn insert(&mut self, timeframe: HashedBarSize, start_date: i64, end_date: i64, value: IBApiBar) -> Result<(), Error>{
-        Ok(())
+    /// Stores a batch of bars for a ticker/timeframe pair and writes it to disk.
+    /// [start_date, end_date] is the window the bars were taken from and is marked as cached,
+    /// even where it has no bars (weekends, halts), so it is never requested again.
+    pub fn insert<K: Into<SeriesKey>>(&mut self, key: K, timeframe: HashedBarSize, start_date: i64, end_date: i64, bars: Vec<IBApiBar>) -> Result<(), Error>{
+        let ticker = key.into().directory_name();
+        if start_date > end_date {
+            bail!("Start date {} is after end date {}", start_date, end_date);
+        }
+        if let Some(bar) = bars.iter().find(|bar| bar.date < start_date || bar.date > end_date) {
+            bail!("Bar at {} lies outside of the inserted range [{}, {}]", bar.date, start_date, end_date);
+        }
+        let data_store = self.realize_data_store(&ticker, &timeframe)?;
+        merge_bars(data_store, start_date, end_date, bars);
+        self.save_data_store(&ticker, &timeframe)
+    }
+
+    /// Reads a vendor CSV file and merges its bars into the stored data for `instrument`/`timeframe`.
+    /// Returns the number of bars imported.
+    pub fn import_csv<K: Into<SeriesKey>, P: AsRef<Path>>(&mut self, key: K, timeframe: HashedBarSize, csv_path: P, config: &CsvImportConfig) -> Result<usize, Error> {
+        let ticker = key.into().directory_name();
+        let count = importer::import_csv(&self.storage_directory, &ticker, timeframe, csv_path, config, self.storage_format)?;
+        // whatever we had in memory for this ticker is stale now, let it be realized from disk again
+        self.ticker_map.insert(ticker, None);
+        Ok(count)
     }
 
     pub fn get_num_tickers(&self) -> usize {
         self.ticker_map.len()
     }
 
-    pub fn get_num_entries(&self, ticker:String, timeframe: HashedBarSize) -> usize {
-        let bar_size_map = self.ticker_map.get(&ticker);
+    /// The number of disjoint windows cached in memory for a ticker/timeframe pair.
+    pub fn get_num_entries<K: Into<SeriesKey>>(&self, key: K, timeframe: HashedBarSize) -> usize {
+        match self.realized_data_store(&key.into().directory_name(), &timeframe) {
+            Some(data) => data.covered_intervals().len(),
+            None => 0
+        }
+    }
+
+    /// The number of bars cached in memory for a ticker/timeframe pair.
+    pub fn get_num_bars<K: Into<SeriesKey>>(&self, key: K, timeframe: HashedBarSize) -> usize {
+        match self.realized_data_store(&key.into().directory_name(), &timeframe) {
+            Some(data) => data.len(),
+            None => 0
+        }
+    }
+
+    fn realized_data_store(&self, ticker: &String, timeframe: &HashedBarSize) -> Option<&BarStorage> {
+        let bar_size_map = self.ticker_map.get(ticker);
         let bar_size_map = match bar_size_map {
             Some(Some(bar_map)) => bar_map,
-            _ => return 0
+            _ => return None
         };
 
-        let data_store = bar_size_map.get(&timeframe);
-        let data_store = match data_store {
-            Some(Some(data)) => data,
-            _ => return 0
-        };
-
-        data_store.len()
+        match bar_size_map.get(timeframe) {
+            Some(Some(data)) => Some(data),
+            _ => None
+        }
     }
 }
\ No newline at end of file
@@ -3,7 +3,7 @@ use anyhow::Error;
 use ordered_float::OrderedFloat;
 use time::macros::datetime;
 use fq_data_broker::{DataBroker, HashedBarSize};
-use ibapi_handler::IBApiBar;
+use ibapi_handler::{IBApiBar, IbapiConfig};
 fn print_data(res: Result<Vec<IBApiBar>, Error>) {
     match res {
         Ok(d) => {
@@ -31,7 +31,7 @@ fn main() {
     assert!(dir_create_res.is_ok());
 
     // instantiate data broker
-    let broker = DataBroker::new(Some("@test_data".to_string()));
+    let broker = DataBroker::new(Some("@test_data".to_string()), IbapiConfig::from_env().unwrap());
     assert!(broker.is_ok());
 
     let mut broker = broker.unwrap();
@@ -0,0 +1,83 @@
+use std::path::Path;
+use anyhow::{bail, Error};
+use ibapi_handler::IBApiBar;
+use range_data_storage::range_data_storage::{backup_location, StorageFormat};
+use crate::{load_bar_storage, BarStorage, DataBroker, HashedBarSize};
+
+/// Every bar of a storage along with its covered windows, for comparing two of them.
+fn entries(data: &BarStorage) -> (Vec<std::ops::RangeInclusive<i64>>, Vec<IBApiBar>) {
+    let bars = data.range(i64::MIN..=i64::MAX)
+        .map(|(_, bar)| bar.clone())
+        .collect();
+    (data.covered_intervals(), bars)
+}
+
+/// What migrate_storage did, by file location.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// The files converted, as (old location, new location).
+    pub converted: Vec<(String, String)>,
+    /// The files left alone because their pair already has a file in the target format,
+    /// as (location, existing location).
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Converts every data file under `storage_directory` (`<ticker>/<barsize>.<json|bin>`) to `format`.
+/// Each converted file is read back and compared with the original before anything is removed;
+/// with `remove_old` the original file and its backup are deleted afterwards.
+/// Pairs that already have a file in `format` are left alone. Files still holding range chunks
+/// are written in the per-bar layout. Returns which files were converted and which were skipped.
+pub fn migrate_storage(storage_directory: &str, format: StorageFormat, remove_old: bool) -> Result<MigrationReport, Error> {
+    let root = Path::new(storage_directory);
+    if !root.is_dir() {
+        bail!("Storage directory {} does not exist", root.display());
+    }
+
+    let mut report = MigrationReport::default();
+    for ticker_entry in std::fs::read_dir(root)? {
+        let ticker_path = ticker_entry?.path();
+        if !ticker_path.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&ticker_path)? {
+            let path = entry?.path();
+            let file_name = DataBroker::convert_osstr_to_string(path.file_name())?;
+            if !path.is_file() || file_name.ends_with(".tmp") || file_name.ends_with(".bak") {
+                continue;
+            }
+            let timeframe = match HashedBarSize::from_filename(&file_name) {
+                Ok(timeframe) => timeframe,
+                Err(_) => continue,
+            };
+            let old_location = DataBroker::convert_osstr_to_string(Some(path.as_os_str()))?;
+            if StorageFormat::from_location(&old_location) == format {
+                continue;
+            }
+            let new_path = ticker_path.join(timeframe.to_location_for(format));
+            let new_location = DataBroker::convert_osstr_to_string(Some(new_path.as_os_str()))?;
+            if new_path.exists() {
+                report.skipped.push((old_location, new_location));
+                continue;
+            }
+
+            let mut data = load_bar_storage(old_location.clone())?;
+            data.set_format(format);
+            data.save(new_location.clone())?;
+
+            let converted = BarStorage::new(Some(new_location.clone()))?;
+            if converted.format() != format || entries(&converted) != entries(&data) {
+                bail!("{} does not match {} after conversion", new_location, old_location);
+            }
+
+            if remove_old {
+                std::fs::remove_file(&old_location)?;
+                let backup = backup_location(&old_location);
+                if Path::new(&backup).exists() {
+                    std::fs::remove_file(backup)?;
+                }
+            }
+            report.converted.push((old_location, new_location));
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,227 @@
+use std::ops::RangeInclusive;
+use anyhow::{bail, Error};
+use time::{Date, Duration, Month, OffsetDateTime};
+use ibapi_handler::IBApiBar;
+use crate::calendar::TradingCalendar;
+use crate::HashedBarSize;
+
+const DAY: i64 = 86400;
+
+/// The length of a bar in seconds, for the bar sizes that have a fixed length.
+/// Day, week and month bars follow the calendar instead.
+pub(crate) fn intraday_seconds(bar_size: &HashedBarSize) -> Option<i64> {
+    match bar_size {
+        HashedBarSize::Sec => Some(1),
+        HashedBarSize::Sec5 => Some(5),
+        HashedBarSize::Sec15 => Some(15),
+        HashedBarSize::Sec30 => Some(30),
+        HashedBarSize::Min => Some(60),
+        HashedBarSize::Min2 => Some(120),
+        HashedBarSize::Min3 => Some(180),
+        HashedBarSize::Min5 => Some(300),
+        HashedBarSize::Min15 => Some(900),
+        HashedBarSize::Min20 => Some(1200),
+        HashedBarSize::Min30 => Some(1800),
+        HashedBarSize::Hour => Some(3600),
+        HashedBarSize::Hour2 => Some(7200),
+        HashedBarSize::Hour3 => Some(10800),
+        HashedBarSize::Hour4 => Some(14400),
+        HashedBarSize::Hour8 => Some(28800),
+        HashedBarSize::Day | HashedBarSize::Week | HashedBarSize::Month => None,
+    }
+}
+
+/// Every bar size, finest first.
+const BAR_SIZES: [HashedBarSize; 19] = [
+    HashedBarSize::Sec, HashedBarSize::Sec5, HashedBarSize::Sec15, HashedBarSize::Sec30,
+    HashedBarSize::Min, HashedBarSize::Min2, HashedBarSize::Min3, HashedBarSize::Min5,
+    HashedBarSize::Min15, HashedBarSize::Min20, HashedBarSize::Min30,
+    HashedBarSize::Hour, HashedBarSize::Hour2, HashedBarSize::Hour3, HashedBarSize::Hour4, HashedBarSize::Hour8,
+    HashedBarSize::Day, HashedBarSize::Week, HashedBarSize::Month,
+];
+
+/// The bar sizes `target` can be resampled from, coarsest (fewest bars to fold) first.
+pub fn finer_sizes(target: &HashedBarSize) -> Vec<HashedBarSize> {
+    BAR_SIZES.iter().rev().filter(|source| can_resample(source, target)).copied().collect()
+}
+
+/// Whether bars of size `target` can be built exactly out of bars of size `source`:
+/// intraday targets need a multiple of the source length, day bars can be built from any intraday size,
+/// week and month bars from anything up to a day (weeks straddle months, so never months from weeks).
+pub fn can_resample(source: &HashedBarSize, target: &HashedBarSize) -> bool {
+    if source == target {
+        return false;
+    }
+    match (intraday_seconds(source), intraday_seconds(target)) {
+        (Some(source), Some(target)) => target > source && target % source == 0,
+        (Some(_), None) => true,
+        (None, None) => *source == HashedBarSize::Day,
+        (None, Some(_)) => false,
+    }
+}
+
+fn midnight(timestamp: i64) -> i64 {
+    timestamp.div_euclid(DAY) * DAY
+}
+
+fn date_of(timestamp: i64) -> Result<Date, Error> {
+    Ok(OffsetDateTime::from_unix_timestamp(timestamp)?.date())
+}
+
+/// The calendar period (UTC) a timestamp falls into for day, week (Monday based) and month bars,
+/// as the first and last second of the period.
+fn calendar_period(target: &HashedBarSize, timestamp: i64) -> Result<RangeInclusive<i64>, Error> {
+    let date = date_of(timestamp)?;
+    let (first, last) = match target {
+        HashedBarSize::Week => {
+            let monday = date - Duration::days(date.weekday().number_days_from_monday() as i64);
+            (monday, monday + Duration::days(6))
+        },
+        HashedBarSize::Month => {
+            let first = Date::from_calendar_date(date.year(), date.month(), 1)?;
+            let next = match date.month() {
+                Month::December => Date::from_calendar_date(date.year() + 1, Month::January, 1)?,
+                month => Date::from_calendar_date(date.year(), month.next(), 1)?,
+            };
+            (first, next - Duration::days(1))
+        },
+        _ => (date, date),
+    };
+    let start = first.midnight().assume_utc().unix_timestamp();
+    let end = last.midnight().assume_utc().unix_timestamp() + DAY - 1;
+    Ok(start..=end)
+}
+
+/// The window a bar of `bar_size` starting at `date` stands for: its length for intraday bars,
+/// the calendar period (in UTC) it starts in for day, week and month bars.
+pub(crate) fn bar_window(bar_size: &HashedBarSize, date: i64) -> Result<RangeInclusive<i64>, Error> {
+    match intraday_seconds(bar_size) {
+        Some(length) => Ok(date..=date + length - 1),
+        None => calendar_period(bar_size, date),
+    }
+}
+
+/// The window of `source` bars needed to build every `target` bar starting in `start..=end`.
+/// Intraday bars are aligned to sessions, taken to be UTC days, so the window is widened to whole days;
+/// day, week and month bars need their whole calendar period.
+pub fn source_window(target: &HashedBarSize, start: i64, end: i64) -> Result<RangeInclusive<i64>, Error> {
+    match intraday_seconds(target) {
+        Some(_) => Ok(midnight(start)..=midnight(end) + DAY - 1),
+        None => Ok(*calendar_period(target, start)?.start()..=*calendar_period(target, end)?.end()),
+    }
+}
+
+/// Folds consecutive bars into one: first open, last close, extreme high/low, summed volume and count,
+/// and the volume weighted average of the waps (a plain average if there was no volume).
+fn aggregate(date: i64, bars: &[IBApiBar]) -> Result<IBApiBar, Error> {
+    let open = bars[0].open().into_inner();
+    let close = bars[bars.len() - 1].close().into_inner();
+    let high = bars.iter().map(|bar| bar.high().into_inner()).fold(f64::MIN, f64::max);
+    let low = bars.iter().map(|bar| bar.low().into_inner()).fold(f64::MAX, f64::min);
+    let volume: f64 = bars.iter().map(|bar| bar.volume().into_inner()).sum();
+    let count: i32 = bars.iter().map(|bar| bar.count()).sum();
+    let wap = if volume > 0.0 {
+        bars.iter().map(|bar| bar.wap().into_inner() * bar.volume().into_inner()).sum::<f64>() / volume
+    } else {
+        bars.iter().map(|bar| bar.wap().into_inner()).sum::<f64>() / bars.len() as f64
+    };
+    // the source bars were taken as they are, so are the bars built out of them
+    IBApiBar::builder().date(date).ohlc(open, high, low, close).volume(volume).count(count).wap(wap).build_unchecked()
+}
+
+/// Builds `target` bars out of `source` bars (sorted by date, as the broker returns them).
+///
+/// Intraday targets are session aligned: every UTC day is a session that opens with its first bar,
+/// and buckets of the target length are counted from there (so hourly bars of a 9:30 open
+/// cover 9:30-10:30, 10:30-11:30, ...). A bucket is dated by its start.
+/// Day, week and month bars are dated at midnight UTC of the first day of their calendar period.
+/// Empty buckets produce no bar.
+pub fn resample(bars: &[IBApiBar], source: &HashedBarSize, target: &HashedBarSize) -> Result<Vec<IBApiBar>, Error> {
+    resample_with(bars, source, target, None)
+}
+
+/// Like resample, but with the sessions of `calendar` (extended or regular hours) instead of UTC days:
+/// intraday buckets are counted from the session's open and end at its close, and day, week and month
+/// bars are made of the bars of their trade dates, so an evening Globex session counts towards the next day.
+/// Bars outside of every session are grouped by UTC day as resample does.
+pub fn resample_in_sessions(bars: &[IBApiBar], source: &HashedBarSize, target: &HashedBarSize, calendar: &TradingCalendar, extended: bool) -> Result<Vec<IBApiBar>, Error> {
+    resample_with(bars, source, target, Some((calendar, extended)))
+}
+
+/// Like source_window, but widening intraday windows to whole sessions of `calendar`,
+/// and day, week and month windows to the sessions of their trade dates.
+pub fn source_window_in_sessions(target: &HashedBarSize, start: i64, end: i64, calendar: &TradingCalendar, extended: bool) -> Result<RangeInclusive<i64>, Error> {
+    let utc_window = source_window(target, start, end)?;
+    let (first, last) = match intraday_seconds(target) {
+        Some(_) => (calendar.session_at(start, extended)?, calendar.session_at(end, extended)?),
+        None => {
+            let sessions = calendar.sessions(*utc_window.start(), *utc_window.end(), extended)?;
+            let first_day = date_of(*utc_window.start())?;
+            let last_day = date_of(*utc_window.end())?;
+            let mut in_period = sessions.into_iter().filter(|session| session.date >= first_day && session.date <= last_day);
+            let first = in_period.next();
+            (first, in_period.next_back().or(first))
+        },
+    };
+    let window_start = first.map_or(*utc_window.start(), |session| session.open.min(*utc_window.start()));
+    let window_end = last.map_or(*utc_window.end(), |session| (session.close - 1).max(*utc_window.end()));
+    Ok(window_start..=window_end)
+}
+
+/// The session a bar belongs to: its trade date, open (None to open with its first bar) and last second.
+/// Without a calendar, or outside of its sessions, that is the bar's UTC day.
+fn session_of(timestamp: i64, sessions: Option<(&TradingCalendar, bool)>) -> Result<(Date, Option<i64>, i64), Error> {
+    if let Some((calendar, extended)) = sessions {
+        if let Some(session) = calendar.session_at(timestamp, extended)? {
+            return Ok((session.date, Some(session.open), session.close - 1));
+        }
+    }
+    Ok((date_of(timestamp)?, None, midnight(timestamp) + DAY - 1))
+}
+
+fn resample_with(bars: &[IBApiBar], source: &HashedBarSize, target: &HashedBarSize, sessions: Option<(&TradingCalendar, bool)>) -> Result<Vec<IBApiBar>, Error> {
+    if !can_resample(source, target) {
+        bail!("Cannot build {:?} bars out of {:?} bars", target, source);
+    }
+    if bars.windows(2).any(|pair| pair[0].date >= pair[1].date) {
+        bail!("Bars to resample must be sorted by date without duplicates");
+    }
+
+    let mut resampled = Vec::new();
+    let mut bucket: Option<i64> = None; // the date of the bar being built
+    let mut members: Vec<IBApiBar> = Vec::new();
+    let mut session: Option<(i64, i64)> = None; // (last second of the session, open)
+    for bar in bars {
+        let (trade_date, session_open, session_end) = session_of(bar.date, sessions)?;
+        let date = match intraday_seconds(target) {
+            Some(length) => {
+                let open = match (session_open, session) {
+                    (Some(open), _) => open,
+                    (None, Some((end, open))) if end == session_end => open,
+                    (None, _) => bar.date,
+                };
+                session = Some((session_end, open));
+                // buckets are dated by their start, which keeps those of different sessions apart
+                open + (bar.date - open) / length * length
+            },
+            None => {
+                let day = trade_date.midnight().assume_utc().unix_timestamp();
+                *calendar_period(target, day)?.start()
+            },
+        };
+        if let Some(current) = bucket {
+            if current == date {
+                members.push(bar.clone());
+                continue;
+            }
+            resampled.push(aggregate(current, &members)?);
+            members.clear();
+        }
+        bucket = Some(date);
+        members.push(bar.clone());
+    }
+    if let Some(date) = bucket {
+        resampled.push(aggregate(date, &members)?);
+    }
+    Ok(resampled)
+}
@@ -0,0 +1,18 @@
+use anyhow::Error;
+use time::OffsetDateTime;
+use ibapi_handler::{HistoricalSeries, IBApiBar, IbapiHandler, Instrument};
+use crate::HashedBarSize;
+
+/// Anything the DataBroker can fall back on when the requested bars are not cached.
+/// The broker only ever asks for an instrument, a bar size and a window; where the bars
+/// come from (TWS, a file dump, a generator) is up to the implementation.
+pub trait HistoricalDataSource {
+    /// Returns the `series` bars for `instrument` at `bar_size` between `start_date` and `end_date`.
+    fn fetch_bars(&mut self, instrument: &Instrument, series: &HistoricalSeries, bar_size: HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error>;
+}
+
+impl HistoricalDataSource for IbapiHandler {
+    fn fetch_bars(&mut self, instrument: &Instrument, series: &HistoricalSeries, bar_size: HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+        self.get_historical_series(&instrument.to_contract(), bar_size.to_bar_size(), series, start_date, end_date)
+    }
+}
@@ -0,0 +1,285 @@
+use anyhow::{bail, Error};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Poisson, StandardNormal};
+use time::macros::datetime;
+use time::{Date, Duration, OffsetDateTime, Weekday};
+use ibapi_handler::{HistoricalSeries, IBApiBar, Instrument};
+use crate::resample::{intraday_seconds, resample, source_window};
+use crate::source::HistoricalDataSource;
+use crate::HashedBarSize;
+
+/// Trading days per year, the unit the annualized model parameters are given in.
+const TRADING_DAYS: f64 = 252.0;
+
+/// One state of a regime-switching model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Regime {
+    pub drift: f64,
+    pub volatility: f64,
+}
+
+/// How the daily closes of a synthetic series evolve. Drifts, volatilities and jump intensities are annualized.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PriceModel {
+    /// Log-normal prices, as in Black-Scholes.
+    GeometricBrownian { drift: f64, volatility: f64 },
+    /// Prices pulled back towards `mean` at rate `reversion`; `volatility` is in price units.
+    OrnsteinUhlenbeck { mean: f64, reversion: f64, volatility: f64 },
+    /// Geometric Brownian motion whose drift and volatility change over time: every trading day,
+    /// the series moves on to another of the regimes with probability `switch_probability`.
+    RegimeSwitching { regimes: Vec<Regime>, switch_probability: f64 },
+    /// Merton's jump diffusion: geometric Brownian motion plus jumps, arriving `intensity` times a year
+    /// on average, whose log sizes are normal with `jump_mean` and `jump_volatility`.
+    JumpDiffusion { drift: f64, volatility: f64, intensity: f64, jump_mean: f64, jump_volatility: f64 },
+}
+
+impl PriceModel {
+    /// Advances `price` by one trading day.
+    fn step(&self, price: f64, regime: &mut usize, rng: &mut ChaCha8Rng) -> f64 {
+        let dt = 1.0 / TRADING_DAYS;
+        let z: f64 = rng.sample(StandardNormal);
+        match self {
+            PriceModel::GeometricBrownian { drift, volatility } => {
+                price * ((drift - volatility * volatility / 2.0) * dt + volatility * dt.sqrt() * z).exp()
+            },
+            PriceModel::OrnsteinUhlenbeck { mean, reversion, volatility } => {
+                // keep the price positive, an OU process happily goes below zero
+                (price + reversion * (mean - price) * dt + volatility * dt.sqrt() * z).max(0.01)
+            },
+            PriceModel::RegimeSwitching { regimes, switch_probability } => {
+                if regimes.len() > 1 && rng.gen_bool(switch_probability.clamp(0.0, 1.0)) {
+                    *regime = (*regime + rng.gen_range(1..regimes.len())) % regimes.len();
+                }
+                let Regime { drift, volatility } = regimes[*regime];
+                price * ((drift - volatility * volatility / 2.0) * dt + volatility * dt.sqrt() * z).exp()
+            },
+            PriceModel::JumpDiffusion { drift, volatility, intensity, jump_mean, jump_volatility } => {
+                let jumps = match Poisson::new(intensity * dt) {
+                    Ok(poisson) => poisson.sample(rng) as u64,
+                    Err(_) => 0,
+                };
+                let mut log_return = (drift - volatility * volatility / 2.0) * dt + volatility * dt.sqrt() * z;
+                for _ in 0..jumps {
+                    let jump: f64 = rng.sample(StandardNormal);
+                    log_return += jump_mean + jump_volatility * jump;
+                }
+                price * log_return.exp()
+            },
+        }
+    }
+
+    /// The relative volatility over one trading day at `price`, used to shape the intraday path.
+    fn daily_volatility(&self, price: f64, regime: usize) -> f64 {
+        let annual = match self {
+            PriceModel::GeometricBrownian { volatility, .. } => *volatility,
+            PriceModel::OrnsteinUhlenbeck { volatility, .. } => volatility / price,
+            PriceModel::RegimeSwitching { regimes, .. } => regimes[regime].volatility,
+            PriceModel::JumpDiffusion { volatility, .. } => *volatility,
+        };
+        annual / TRADING_DAYS.sqrt()
+    }
+}
+
+/// How much trades during a day. Intraday volume follows a smile: bars near the open and the close
+/// get up to `1 + smile` times the volume of the bars at midday.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VolumeProfile {
+    /// The average volume of a whole session.
+    pub daily_volume: f64,
+    pub smile: f64,
+    /// The volatility of the log-normal noise on each day's and each minute's volume.
+    pub noise: f64,
+}
+
+impl Default for VolumeProfile {
+    fn default() -> Self {
+        VolumeProfile {
+            daily_volume: 1_000_000.0,
+            smile: 2.0,
+            noise: 0.2,
+        }
+    }
+}
+
+/// A HistoricalDataSource that makes bars up, for tests and experiments that should not need TWS.
+///
+/// Every instrument gets its own path of daily closes, simulated with `model` from `origin` on
+/// (weekdays only) and seeded by `seed` and the instrument, so the same request always returns the same bars
+/// and windows fetched separately line up. Within a day, minute prices follow a Brownian bridge from the open
+/// to the close; bars of any size are built out of them, so e.g. hourly and daily bars agree with each other.
+/// The requested series (what to show, trading hours) does not change the bars.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticSource {
+    pub seed: u64,
+    pub model: PriceModel,
+    pub initial_price: f64,
+    /// The first day of every path; bars before it cannot be requested.
+    pub origin: OffsetDateTime,
+    pub volume: VolumeProfile,
+    /// When the session opens, in seconds after midnight UTC.
+    pub session_open: i64,
+    /// The session length in seconds.
+    pub session_length: i64,
+}
+
+impl SyntheticSource {
+    /// A source starting at 100 on 2000-01-03, with a 14:30-21:00 UTC session (the NYSE's, outside of DST).
+    pub fn new(seed: u64, model: PriceModel) -> Self {
+        SyntheticSource {
+            seed,
+            model,
+            initial_price: 100.0,
+            origin: datetime!(2000-01-03 00:00:00 UTC),
+            volume: VolumeProfile::default(),
+            session_open: 14 * 3600 + 30 * 60,
+            session_length: 6 * 3600 + 30 * 60,
+        }
+    }
+
+    pub fn with_initial_price(mut self, initial_price: f64) -> Self {
+        self.initial_price = initial_price;
+        self
+    }
+
+    pub fn with_origin(mut self, origin: OffsetDateTime) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn with_volume(mut self, volume: VolumeProfile) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    /// Sets the session's open (seconds after midnight UTC) and length in seconds.
+    pub fn with_session(mut self, open: i64, length: i64) -> Self {
+        self.session_open = open;
+        self.session_length = length;
+        self
+    }
+
+    /// The bars `instrument` would have at `bar_size` between `start_date` and `end_date`.
+    pub fn generate(&self, instrument: &Instrument, bar_size: HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+        if start_date < self.origin {
+            bail!("Synthetic bars start at {}, {} was requested", self.origin, start_date);
+        }
+        if self.session_length <= 0 || self.session_open < 0 || self.session_open + self.session_length > 86400 {
+            bail!("The synthetic session has to lie within a UTC day");
+        }
+        let (start, end) = (start_date.unix_timestamp(), end_date.unix_timestamp());
+        let window = source_window(&bar_size, start, end)?;
+        let first_day = OffsetDateTime::from_unix_timestamp(*window.start())?.date().max(self.origin.date());
+        let last_day = OffsetDateTime::from_unix_timestamp(*window.end())?.date();
+
+        // sub-minute bars get a path of their own length, everything else is built from minutes
+        let step = match intraday_seconds(&bar_size) {
+            Some(seconds) if seconds < 60 => seconds,
+            _ => 60,
+        };
+        let base_size = if step == 60 { HashedBarSize::Min } else { bar_size };
+
+        let instrument_seed = mix(self.seed, fnv1a(instrument.directory_name().as_bytes()));
+        let mut bars = Vec::new();
+        for day in self.daily_path(instrument_seed, first_day, last_day) {
+            bars.extend(self.intraday_bars(instrument_seed, &day, step)?);
+        }
+        let bars = if base_size == bar_size { bars } else { resample(&bars, &base_size, &bar_size)? };
+        Ok(bars.into_iter().filter(|bar| bar.date >= start && bar.date <= end).collect())
+    }
+
+    /// Simulates the daily closes from the origin up to `last_day`, keeping the days from `first_day` on.
+    fn daily_path(&self, instrument_seed: u64, first_day: Date, last_day: Date) -> Vec<SyntheticDay> {
+        let mut rng = ChaCha8Rng::seed_from_u64(instrument_seed);
+        let mut price = self.initial_price;
+        let mut regime = 0;
+        let mut days = Vec::new();
+        let mut day = self.origin.date();
+        while day <= last_day {
+            if day.weekday() != Weekday::Saturday && day.weekday() != Weekday::Sunday {
+                let volatility = self.model.daily_volatility(price, regime);
+                let close = self.model.step(price, &mut regime, &mut rng);
+                if day >= first_day {
+                    days.push(SyntheticDay { date: day, open: price, close, volatility });
+                }
+                price = close;
+            }
+            day += Duration::days(1);
+        }
+        days
+    }
+
+    /// The day's bars of `step` seconds, a Brownian bridge from its open to its close.
+    fn intraday_bars(&self, instrument_seed: u64, day: &SyntheticDay, step: i64) -> Result<Vec<IBApiBar>, Error> {
+        let mut rng = ChaCha8Rng::seed_from_u64(mix(instrument_seed, day.date.to_julian_day() as u64));
+        let steps = (self.session_length / step).max(1) as usize;
+        let step_volatility = day.volatility / (steps as f64).sqrt();
+
+        // random walk, then pinned to end where it started
+        let mut walk = Vec::with_capacity(steps + 1);
+        walk.push(0.0);
+        for i in 0..steps {
+            let z: f64 = rng.sample(StandardNormal);
+            walk.push(walk[i] + step_volatility * z);
+        }
+        let (log_open, log_close) = (day.open.ln(), day.close.ln());
+        let prices: Vec<f64> = walk.iter().enumerate().map(|(i, w)| {
+            let t = i as f64 / steps as f64;
+            (log_open + t * (log_close - log_open) + w - t * walk[steps]).exp()
+        }).collect();
+
+        let daily_noise: f64 = rng.sample(StandardNormal);
+        let noise = self.volume.noise;
+        let day_volume = self.volume.daily_volume * (noise * daily_noise - noise * noise / 2.0).exp();
+        let weights: Vec<f64> = (0..steps).map(|i| {
+            let x = (i as f64 + 0.5) / steps as f64;
+            1.0 + self.volume.smile * (2.0 * x - 1.0).powi(2)
+        }).collect();
+        let total_weight: f64 = weights.iter().sum();
+
+        let session_start = day.date.midnight().assume_utc().unix_timestamp() + self.session_open;
+        let mut bars = Vec::with_capacity(steps);
+        for i in 0..steps {
+            let (open, close) = (prices[i], prices[i + 1]);
+            let (up, down, volume_noise): (f64, f64, f64) = (rng.sample(StandardNormal), rng.sample(StandardNormal), rng.sample(StandardNormal));
+            let high = open.max(close) * (0.5 * step_volatility * up.abs()).exp();
+            let low = open.min(close) * (-0.5 * step_volatility * down.abs()).exp();
+            let volume = (day_volume * weights[i] / total_weight * (noise * volume_noise - noise * noise / 2.0).exp()).round();
+            let bar = IBApiBar::builder()
+                .date(session_start + i as i64 * step)
+                .ohlc(open, high, low, close)
+                .volume(volume)
+                .count((volume / 100.0).ceil() as i32)
+                .build()?;
+            bars.push(bar);
+        }
+        Ok(bars)
+    }
+}
+
+impl HistoricalDataSource for SyntheticSource {
+    fn fetch_bars(&mut self, instrument: &Instrument, _series: &HistoricalSeries, bar_size: HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+        self.generate(instrument, bar_size, start_date, end_date)
+    }
+}
+
+/// A trading day of the daily path: where it opens and closes and how volatile it is.
+struct SyntheticDay {
+    date: Date,
+    open: f64,
+    close: f64,
+    volatility: f64,
+}
+
+/// FNV-1a, a hash that stays the same across Rust releases (unlike DefaultHasher), so seeds do too.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Combines two seeds (splitmix64's finalizer).
+fn mix(seed: u64, value: u64) -> u64 {
+    let mut z = seed ^ value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -3,14 +3,60 @@ mod data_broker_tests {
     use anyhow::Error;
     use ordered_float::OrderedFloat;
     use time::macros::datetime;
-    use fq_data_broker::{DataBroker, HashedBarSize};
-    use ibapi_handler::IBApiBar;
+    use time::OffsetDateTime;
+    use fq_data_broker::{HashedWhatToShow, HistoricalSeries, Instrument, InstrumentType, SeriesKey, CsvColumnMapping, CsvCoverage, CsvImportConfig, CsvTimestampFormat, DataBroker, HashedBarSize, HistoricalDataSource, StorageFormat};
+    use fq_data_broker::migrate::migrate_storage;
+    use fq_data_broker::{PriceModel, Regime, SyntheticSource};
+    use ibapi_handler::{IBApiBar, IbapiConfig, IbapiHandler};
+
+    type Requests = std::rc::Rc<std::cell::RefCell<Vec<(i64, i64)>>>;
+
+    /// Serves one flat bar per UTC midnight in the requested window and records every window it was asked for.
+    struct StubSource {
+        requests: Requests,
+    }
+
+    impl StubSource {
+        fn new() -> (Self, Requests) {
+            let requests = Requests::default();
+            (StubSource { requests: requests.clone() }, requests)
+        }
+    }
+
+    impl HistoricalDataSource for StubSource {
+        fn fetch_bars(&mut self, _instrument: &Instrument, _series: &HistoricalSeries, _bar_size: HashedBarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+            self.requests.borrow_mut().push((start_date.unix_timestamp(), end_date.unix_timestamp()));
+            let mut bars = Vec::new();
+            let mut date = (start_date.unix_timestamp() + 86399) / 86400 * 86400;
+            while date <= end_date.unix_timestamp() {
+                bars.push(IBApiBar::builder().date(date).ohlc(1.0, 1.0, 1.0, 1.0).volume(100.0).count(1).wap(1.0).build().unwrap());
+                date += 86400;
+            }
+            Ok(bars)
+        }
+    }
+
+    /// Replays tests/fixtures/<name>.json, or records it from TWS when FQ_IB_RECORD is set.
+    /// The committed fixtures are hand-written stand-ins, not recorded TWS data.
+    fn fixture_source(name: &str) -> Box<dyn HistoricalDataSource> {
+        let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(format!("{}.json", name));
+        Box::new(IbapiHandler::from_fixture(IbapiConfig::from_env().unwrap(), fixture).unwrap())
+    }
+
+    /// Returns a fresh, empty storage directory under the system temp dir.
+    fn temp_storage(name: &str) -> String {
+        let path = std::env::temp_dir().join("fq_data_broker_tests").join(name);
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+        path.to_str().unwrap().to_string()
+    }
 
     #[test]
     fn instantiate_test() {
         let curr_dir_res = std::env::set_current_dir("..");
         assert!(curr_dir_res.is_ok());
-        let broker = DataBroker::new(None);
+        let broker = DataBroker::new(None, IbapiConfig::from_env().unwrap());
         assert!(broker.is_ok());
         let broker = broker.unwrap();
 
@@ -27,7 +73,7 @@ mod data_broker_tests {
         let curr_dir_res = std::env::set_current_dir("..");
         assert!(curr_dir_res.is_ok());
 
-        let broker = DataBroker::new(Some("@test_data".to_string()));
+        let broker = DataBroker::new(Some("@test_data".to_string()), IbapiConfig::from_env().unwrap());
         assert!(broker.is_ok());
         let broker = broker.unwrap();
 
@@ -59,7 +105,7 @@ mod data_broker_tests {
         let apple_path_res = std::fs::create_dir_all(apple_path);
         assert!(apple_path_res.is_ok());
 
-        let broker = DataBroker::new(Some("@test_data".to_string()));
+        let broker = DataBroker::new(Some("@test_data".to_string()), IbapiConfig::from_env().unwrap());
         assert!(broker.is_ok());
         let broker = broker.unwrap();
 
@@ -94,7 +140,7 @@ mod data_broker_tests {
         assert!(apple_path_res.is_ok());
         
         // call retrieve data
-        let broker = DataBroker::new(Some("@test_data".to_string()));
+        let broker = DataBroker::with_source(Some("@test_data".to_string()), fixture_source("retrieve_data_test_one_day"));
         assert!(broker.is_ok());
         
         let mut broker = broker.unwrap();
@@ -126,7 +172,7 @@ mod data_broker_tests {
         assert!(path.exists());
 
         // instantiate data broker
-        let broker = DataBroker::new(Some("@test_data".to_string()));
+        let broker = DataBroker::with_source(Some("@test_data".to_string()), fixture_source("retrieve_data_test_one_day_no_delete"));
         assert!(broker.is_ok());
 
         let mut broker = broker.unwrap();
@@ -175,7 +221,7 @@ mod data_broker_tests {
         assert!(dir_create_res.is_ok());
 
         // instantiate data broker
-        let broker = DataBroker::new(Some("@test_data".to_string()));
+        let broker = DataBroker::with_source(Some("@test_data".to_string()), fixture_source("retrieve_data_test_three_days"));
         assert!(broker.is_ok());
 
         let mut broker = broker.unwrap();
@@ -206,4 +252,708 @@ mod data_broker_tests {
     fn test_range_storage() {
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn retrieve_data_from_custom_source() {
+        let storage = temp_storage("custom_source");
+        let (source, requests) = StubSource::new();
+        let broker = DataBroker::with_source(Some(storage.clone()), Box::new(source));
+        assert!(broker.is_ok());
+        let mut broker = broker.unwrap();
+
+        let data = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-01-06 00:00:00 UTC)
+        );
+        assert!(data.is_ok(), "Error: {:?}", data.err());
+        assert_eq!(data.unwrap().len(), 3);
+        assert_eq!(requests.borrow().len(), 1);
+
+        // the fetched bars should have been written to the storage directory
+        let path = std::path::Path::new(&storage).join("AAPL").join(HashedBarSize::Day.to_location());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn import_csv_test() {
+        let storage = temp_storage("import_csv");
+        std::fs::create_dir_all(&storage).unwrap();
+        let csv_path = std::path::Path::new(&storage).join("vendor.csv");
+        std::fs::write(&csv_path, "Time;Open;High;Low;Close;Vol\n\
+            2021-01-05 09:30;10.5;11;10;10.8;2000\n\
+            2021-01-04 09:30;10;10.5;9.5;10.2;1000\n").unwrap();
+
+        let mut config = CsvImportConfig {
+            columns: CsvColumnMapping {
+                date: "Time".to_string(),
+                open: "Open".to_string(),
+                high: "High".to_string(),
+                low: "Low".to_string(),
+                close: "Close".to_string(),
+                volume: Some("Vol".to_string()),
+                count: None,
+                wap: None,
+            },
+            timestamp_format: CsvTimestampFormat::Pattern("[year]-[month]-[day] [hour]:[minute]".to_string()),
+            timezone: "America/New_York".to_string(),
+            delimiter: b';',
+            coverage: CsvCoverage::Bars,
+        };
+        let (source, requests) = StubSource::new();
+        let broker = DataBroker::with_source(Some(storage.clone()), Box::new(source));
+        let mut broker = broker.unwrap();
+        let imported = broker.import_csv("AAPL".to_string(), HashedBarSize::Day, &csv_path, &config);
+        assert!(imported.is_ok(), "Error: {:?}", imported.err());
+        assert_eq!(imported.unwrap(), 2);
+
+        // 09:30 New York is 14:30 UTC in January
+        let data = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-04 14:30:00 UTC),
+            datetime!(2021-01-05 14:30:00 UTC)
+        );
+        assert!(data.is_ok(), "Error: {:?}", data.err());
+        let data = data.unwrap();
+        assert!(requests.borrow().is_empty());
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].date, datetime!(2021-01-04 14:30:00 UTC).unix_timestamp());
+        assert_eq!(data[0].close, OrderedFloat(10.2));
+        assert_eq!(data[1].volume(), OrderedFloat(2000.0));
+
+        // only the days of the imported bars are cached, the hole on Tuesday the 12th is fetched
+        std::fs::write(&csv_path, "Time;Open;High;Low;Close;Vol\n\
+            2021-01-11 09:30;10;10.5;9.5;10.2;1000\n\
+            2021-01-13 09:30;10;10.5;9.5;10.2;1000\n").unwrap();
+        broker.import_csv("AAPL".to_string(), HashedBarSize::Day, &csv_path, &config).unwrap();
+        let (start, end) = (datetime!(2021-01-11 14:30:00 UTC), datetime!(2021-01-13 14:30:00 UTC));
+        assert_eq!(broker.retrieve_data("AAPL".to_string(), HashedBarSize::Day, start, end).unwrap().len(), 3);
+        assert_eq!(*requests.borrow(), vec![(datetime!(2021-01-12 00:00:00 UTC).unix_timestamp(), datetime!(2021-01-12 23:59:59 UTC).unix_timestamp())]);
+
+        // unless the file is taken to be complete
+        config.coverage = CsvCoverage::Span;
+        broker.import_csv("MSFT".to_string(), HashedBarSize::Day, &csv_path, &config).unwrap();
+        assert_eq!(broker.retrieve_data("MSFT".to_string(), HashedBarSize::Day, start, end).unwrap().len(), 2);
+        assert_eq!(requests.borrow().len(), 1);
+
+        // milliseconds before the epoch round down to the second they fall in
+        std::fs::write(&csv_path, "date,open,high,low,close,volume\n-1500,1,1,1,1,0\n").unwrap();
+        let config = CsvImportConfig { timestamp_format: CsvTimestampFormat::UnixMillis, ..CsvImportConfig::default() };
+        assert_eq!(fq_data_broker::importer::read_csv_bars(&csv_path, &config).unwrap()[0].date, -2);
+    }
+
+    #[test]
+    fn retrieve_data_fetches_only_missing_intervals() {
+        let storage = temp_storage("missing_intervals");
+        let (source, requests) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage), Box::new(source)).unwrap();
+        // the stub has bars on weekends too, so look at the gaps without the exchange calendar
+        broker.set_use_calendars(false);
+
+        let first = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-01-06 00:00:00 UTC)
+        ).unwrap();
+        assert_eq!(first.len(), 3);
+
+        // overlaps the first window on both sides of a hole
+        let second = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-02 00:00:00 UTC),
+            datetime!(2021-01-08 00:00:00 UTC)
+        ).unwrap();
+        let jan_4 = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let jan_6 = datetime!(2021-01-06 00:00:00 UTC).unix_timestamp();
+        assert_eq!(*requests.borrow(), vec![
+            (jan_4, jan_6),
+            (datetime!(2021-01-02 00:00:00 UTC).unix_timestamp(), jan_4 - 1),
+            (jan_6 + 1, datetime!(2021-01-08 00:00:00 UTC).unix_timestamp()),
+        ]);
+        let dates: Vec<i64> = second.iter().map(|bar| bar.date).collect();
+        let expected: Vec<i64> = (0..7).map(|day| datetime!(2021-01-02 00:00:00 UTC).unix_timestamp() + day * 86400).collect();
+        assert_eq!(dates, expected);
+
+        // fully cached now, and only the bars inside the window come back
+        let third = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-05 00:00:00 UTC),
+            datetime!(2021-01-05 12:00:00 UTC)
+        ).unwrap();
+        assert_eq!(requests.borrow().len(), 3);
+        assert_eq!(third.len(), 1);
+        assert_eq!(third[0].date, datetime!(2021-01-05 00:00:00 UTC).unix_timestamp());
+    }
+
+    #[test]
+    fn insert_and_has_range_test() {
+        let storage = temp_storage("insert_has_range");
+        let (source, requests) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage.clone()), Box::new(source)).unwrap();
+        let jan_4 = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let jan_8 = datetime!(2021-01-08 00:00:00 UTC).unix_timestamp();
+
+        // unknown tickers are simply not cached, and asking must not create their directory
+        assert!(!broker.has_range("MSFT".to_string(), HashedBarSize::Day, jan_4, jan_8).unwrap());
+        assert!(!std::path::Path::new(&storage).join("MSFT").exists());
+
+        let bars: Vec<IBApiBar> = (0..5).map(|day| IBApiBar::builder().date(jan_4 + day * 86400).ohlc(1.0, 2.0, 0.5, 1.5).volume(10.0).count(1).wap(1.2).build().unwrap()).collect();
+        let res = broker.insert("MSFT".to_string(), HashedBarSize::Day, jan_4, jan_8, bars);
+        assert!(res.is_ok(), "Error: {:?}", res.err());
+        assert!(broker.has_range("MSFT".to_string(), HashedBarSize::Day, jan_4, jan_8).unwrap());
+        assert!(broker.has_range("MSFT".to_string(), HashedBarSize::Day, jan_4 + 3600, jan_4 + 7200).unwrap());
+        assert!(!broker.has_range("MSFT".to_string(), HashedBarSize::Day, jan_4 - 1, jan_8).unwrap());
+        assert!(!broker.has_range("MSFT".to_string(), HashedBarSize::Hour, jan_4, jan_8).unwrap());
+
+        // bars outside of the declared window are rejected
+        let stray = vec![IBApiBar::builder().date(jan_8 + 86400).ohlc(1.0, 1.0, 1.0, 1.0).volume(1.0).count(1).wap(1.0).build().unwrap()];
+        assert!(broker.insert("MSFT".to_string(), HashedBarSize::Day, jan_4, jan_8, stray).is_err());
+
+        // a fresh broker sees the inserted bars from disk and serves them without the source
+        let (source, _) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage), Box::new(source)).unwrap();
+        assert!(broker.has_range("MSFT".to_string(), HashedBarSize::Day, jan_4, jan_8).unwrap());
+        let data = broker.retrieve_data(
+            "MSFT".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-01-08 00:00:00 UTC)
+        ).unwrap();
+        assert_eq!(data.len(), 5);
+        assert!(requests.borrow().is_empty());
+    }
+
+    #[test]
+    fn binary_storage_and_migration_test() {
+        let storage = temp_storage("binary_migration");
+        let jan_4 = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let jan_8 = datetime!(2021-01-08 00:00:00 UTC).unix_timestamp();
+        let bars: Vec<IBApiBar> = (0..5).map(|day| IBApiBar::builder().date(jan_4 + day * 86400).ohlc(1.0, 2.0, 0.5, 1.5).volume(10.0).count(1).wap(1.2).build().unwrap()).collect();
+
+        // written as JSON by a default broker
+        let (source, _) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage.clone()), Box::new(source)).unwrap();
+        assert_eq!(broker.storage_format(), StorageFormat::Json);
+        broker.insert("AAPL".to_string(), HashedBarSize::Day, jan_4, jan_8, bars.clone()).unwrap();
+        broker.insert("AAPL".to_string(), HashedBarSize::Hour, jan_4, jan_8, bars.clone()).unwrap();
+        let ticker_dir = std::path::Path::new(&storage).join("AAPL");
+        assert!(ticker_dir.join("day.json").exists());
+
+        let migrated = migrate_storage(&storage, StorageFormat::Binary, true);
+        assert!(migrated.is_ok(), "Error: {:?}", migrated.err());
+        let report = migrated.unwrap();
+        assert_eq!(report.converted.len(), 2);
+        assert!(report.skipped.is_empty());
+        assert!(ticker_dir.join("day.bin").exists());
+        assert!(ticker_dir.join("hour.bin").exists());
+        assert!(!ticker_dir.join("day.json").exists());
+        assert!(!ticker_dir.join("day.json.bak").exists());
+
+        // a binary broker serves the migrated data without asking its source
+        let (source, requests) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage.clone()), Box::new(source)).unwrap();
+        broker.set_storage_format(StorageFormat::Binary).unwrap();
+        let data = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-01-08 00:00:00 UTC)
+        ).unwrap();
+        assert_eq!(data, bars);
+        assert!(requests.borrow().is_empty());
+
+        // new data for a binary broker lands in binary files
+        broker.insert("MSFT".to_string(), HashedBarSize::Day, jan_4, jan_8, bars).unwrap();
+        assert!(std::path::Path::new(&storage).join("MSFT").join("day.bin").exists());
+
+        // converted back without removing the binary files, which are then skipped
+        assert_eq!(migrate_storage(&storage, StorageFormat::Json, false).unwrap().converted.len(), 3);
+        let report = migrate_storage(&storage, StorageFormat::Binary, false).unwrap();
+        assert!(report.converted.is_empty());
+        assert_eq!(report.skipped.len(), 3);
+    }
+
+    #[test]
+    fn overlapping_fetches_merge_per_bar() {
+        let storage = temp_storage("per_bar");
+        let jan_4 = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let jan_6 = datetime!(2021-01-06 00:00:00 UTC).unix_timestamp();
+        let jan_8 = datetime!(2021-01-08 00:00:00 UTC).unix_timestamp();
+        let (source, requests) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage), Box::new(source)).unwrap();
+
+        let first: Vec<IBApiBar> = (0..3).map(|day| IBApiBar::builder().date(jan_4 + day * 86400).ohlc(1.0, 1.0, 1.0, 1.0).volume(10.0).count(1).wap(1.0).build().unwrap()).collect();
+        broker.insert("AAPL".to_string(), HashedBarSize::Day, jan_4, jan_6, first).unwrap();
+        // overlaps the first window by one bar, which gets replaced
+        let second: Vec<IBApiBar> = (2..5).map(|day| IBApiBar::builder().date(jan_4 + day * 86400).ohlc(2.0, 2.0, 2.0, 2.0).volume(20.0).count(1).wap(2.0).build().unwrap()).collect();
+        broker.insert("AAPL".to_string(), HashedBarSize::Day, jan_6, jan_8, second).unwrap();
+        assert_eq!(broker.get_num_entries("AAPL".to_string(), HashedBarSize::Day), 1);
+        assert_eq!(broker.get_num_bars("AAPL".to_string(), HashedBarSize::Day), 5);
+        assert_eq!(broker.cached_intervals("AAPL".to_string(), HashedBarSize::Day).unwrap(), vec![jan_4..=jan_8]);
+        assert!(broker.cached_intervals("MSFT".to_string(), HashedBarSize::Day).unwrap().is_empty());
+
+        let data = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-05 12:00:00 UTC),
+            datetime!(2021-01-07 00:00:00 UTC)
+        ).unwrap();
+        assert!(requests.borrow().is_empty());
+        let dates: Vec<i64> = data.iter().map(|bar| bar.date).collect();
+        assert_eq!(dates, vec![jan_6, jan_6 + 86400]);
+        assert_eq!(data[0].close, OrderedFloat(2.0));
+    }
+
+    #[test]
+    fn legacy_range_files_are_upgraded() {
+        use range_data_storage::range_data_storage::RangeDataStorage;
+
+        let storage = temp_storage("legacy_upgrade");
+        let jan_4 = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let jan_8 = datetime!(2021-01-08 00:00:00 UTC).unix_timestamp();
+        let bars: Vec<IBApiBar> = (0..5).map(|day| IBApiBar::builder().date(jan_4 + day * 86400).ohlc(1.0, 1.0, 1.0, 1.0).volume(10.0).count(1).wap(1.0).build().unwrap()).collect();
+        let location = std::path::Path::new(&storage).join("AAPL").join(HashedBarSize::Day.to_location());
+        let location = location.to_str().unwrap().to_string();
+        let mut legacy: RangeDataStorage<i64, Vec<IBApiBar>> = RangeDataStorage::new(Some(location.clone())).unwrap();
+        legacy.insert(jan_4, jan_8, bars.clone());
+        legacy.flush().unwrap();
+        drop(legacy);
+
+        let (source, requests) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage), Box::new(source)).unwrap();
+        broker.set_use_calendars(false);
+        let data = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-05 00:00:00 UTC),
+            datetime!(2021-01-10 00:00:00 UTC)
+        ).unwrap();
+        assert_eq!(requests.borrow().len(), 1);
+        assert_eq!(data.len(), 6);
+        assert_eq!(data[..4], bars[1..]);
+
+        // the fetch rewrote the file in the per-bar layout
+        let reloaded = fq_data_broker::BarStorage::new(Some(location)).unwrap();
+        assert_eq!(reloaded.len(), 7);
+    }
+
+    #[test]
+    fn resample_test() {
+        use fq_data_broker::resample::{can_resample, resample};
+
+        assert!(can_resample(&HashedBarSize::Min, &HashedBarSize::Min15));
+        assert!(can_resample(&HashedBarSize::Min20, &HashedBarSize::Hour));
+        assert!(!can_resample(&HashedBarSize::Min15, &HashedBarSize::Min20));
+        assert!(can_resample(&HashedBarSize::Day, &HashedBarSize::Week));
+        assert!(!can_resample(&HashedBarSize::Week, &HashedBarSize::Month));
+
+        // a session opening at 14:30 UTC, with a two day weekend gap before the next one
+        let open = datetime!(2021-01-08 14:30:00 UTC).unix_timestamp();
+        let next_open = datetime!(2021-01-11 14:30:00 UTC).unix_timestamp();
+        let mut bars = Vec::new();
+        for (minute, price) in [(0, 10.0), (1, 12.0), (59, 11.0), (60, 9.0)] {
+            bars.push(IBApiBar::builder().date(open + minute * 60).ohlc(price, price + 1.0, price - 1.0, price).volume(100.0 * (minute + 1) as f64).count(1).wap(price).build().unwrap());
+        }
+        bars.push(IBApiBar::builder().date(next_open).ohlc(20.0, 21.0, 19.0, 20.0).volume(0.0).count(0).wap(20.0).build().unwrap());
+
+        let hours = resample(&bars, &HashedBarSize::Min, &HashedBarSize::Hour).unwrap();
+        assert_eq!(hours.len(), 3);
+        assert_eq!(hours[0].date, open);
+        assert_eq!(hours[0].open(), OrderedFloat(10.0));
+        assert_eq!(hours[0].high(), OrderedFloat(13.0));
+        assert_eq!(hours[0].low(), OrderedFloat(9.0));
+        assert_eq!(hours[0].close(), OrderedFloat(11.0));
+        assert_eq!(hours[0].volume(), OrderedFloat(100.0 + 200.0 + 6000.0));
+        assert_eq!(hours[0].count(), 3);
+        let wap = (10.0 * 100.0 + 12.0 * 200.0 + 11.0 * 6000.0) / 6300.0;
+        assert!((hours[0].wap().into_inner() - wap).abs() < 1e-9);
+        assert_eq!(hours[1].date, open + 3600);
+        assert_eq!(hours[2].date, next_open);
+
+        let weeks = resample(&bars, &HashedBarSize::Min, &HashedBarSize::Week).unwrap();
+        assert_eq!(weeks.len(), 2);
+        assert_eq!(weeks[0].date, datetime!(2021-01-04 00:00:00 UTC).unix_timestamp());
+        assert_eq!(weeks[1].date, datetime!(2021-01-11 00:00:00 UTC).unix_timestamp());
+
+        assert!(resample(&bars, &HashedBarSize::Hour, &HashedBarSize::Min).is_err());
+    }
+
+    #[test]
+    fn retrieve_data_resamples_cached_finer_bars() {
+        let storage = temp_storage("resample_cached");
+        let (source, requests) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage), Box::new(source)).unwrap();
+
+        let day = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let open = datetime!(2021-01-04 14:30:00 UTC).unix_timestamp();
+        let minutes: Vec<IBApiBar> = (0..390).map(|minute| {
+            let price = 100.0 + minute as f64;
+            IBApiBar::builder().date(open + minute * 60).ohlc(price, price, price, price).volume(10.0).count(1).wap(price).build().unwrap()
+        }).collect();
+        broker.insert("AAPL".to_string(), HashedBarSize::Min, day, day + 86399, minutes).unwrap();
+
+        let hours = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Hour,
+            datetime!(2021-01-04 15:00:00 UTC),
+            datetime!(2021-01-04 21:00:00 UTC)
+        ).unwrap();
+        assert!(requests.borrow().is_empty());
+        // 15:30 through 20:30, the 14:30 bar starts before the window
+        assert_eq!(hours.len(), 6);
+        assert_eq!(hours[0].date, open + 3600);
+        assert_eq!(hours[0].open(), OrderedFloat(160.0));
+        assert_eq!(hours[0].close(), OrderedFloat(219.0));
+        assert_eq!(hours[5].volume(), OrderedFloat(300.0));
+
+        // nothing finer is cached for the next day, so that goes to the source
+        broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Hour,
+            datetime!(2021-01-05 15:00:00 UTC),
+            datetime!(2021-01-05 16:00:00 UTC)
+        ).unwrap();
+        assert_eq!(requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn calendar_holidays_test() {
+        use fq_data_broker::{HolidayTable, TradingCalendar};
+        use time::macros::date;
+
+        let nyse = TradingCalendar::nyse();
+        for holiday in [
+            date!(2021-01-01), date!(2021-01-18), date!(2021-02-15), date!(2021-04-02), date!(2021-05-31),
+            date!(2021-07-05), date!(2021-09-06), date!(2021-11-25), date!(2021-12-24),
+            date!(2022-06-20), date!(2012-10-29), date!(2025-01-09),
+        ] {
+            assert!(nyse.is_holiday(holiday), "{} should be a holiday", holiday);
+            assert!(!nyse.is_trading_day(holiday));
+        }
+        // New Year's Day 2022 is a Saturday and not made up for on the Friday before, Juneteenth only counts from 2022
+        assert!(nyse.is_trading_day(date!(2021-12-31)));
+        assert!(nyse.is_trading_day(date!(2021-06-18)));
+        assert!(!nyse.is_trading_day(date!(2021-01-02)));
+
+        for half_day in [date!(2021-11-26), date!(2023-07-03), date!(2024-12-24)] {
+            assert!(nyse.is_early_close(half_day), "{} should close early", half_day);
+        }
+        // Christmas Eve 2021 was the observed Christmas holiday, not a half day
+        assert!(!nyse.is_early_close(date!(2021-12-24)));
+        assert!(!nyse.is_early_close(date!(2021-07-02)));
+
+        // Globex trades through most holidays, just not as long
+        let cme = TradingCalendar::cme();
+        assert!(cme.is_trading_day(date!(2021-01-18)));
+        assert!(cme.is_early_close(date!(2021-01-18)));
+        assert!(!cme.is_trading_day(date!(2021-04-02)));
+        assert!(!cme.is_trading_day(date!(2021-12-24)));
+
+        let table = HolidayTable::from_toml_str("holidays = [\"2021-03-01\"]\nearly_closes = [\"2021-03-02\"]").unwrap();
+        let extended = TradingCalendar::nyse().with_table(&table).unwrap();
+        assert!(!extended.is_trading_day(date!(2021-03-01)));
+        assert!(extended.is_early_close(date!(2021-03-02)));
+        assert!(HolidayTable::from_toml_str("holidays = [\"March 1st\"]").map(|table| TradingCalendar::nyse().with_table(&table)).unwrap().is_err());
+    }
+
+    #[test]
+    fn calendar_sessions_test() {
+        use fq_data_broker::TradingCalendar;
+        use time::macros::date;
+
+        let nyse = TradingCalendar::nyse();
+        let winter = nyse.session(date!(2021-01-04), false).unwrap().unwrap();
+        assert_eq!(winter.open, datetime!(2021-01-04 14:30:00 UTC).unix_timestamp());
+        assert_eq!(winter.close, datetime!(2021-01-04 21:00:00 UTC).unix_timestamp());
+        // daylight saving time moves the session an hour earlier in UTC
+        let summer = nyse.session(date!(2021-07-06), false).unwrap().unwrap();
+        assert_eq!(summer.open, datetime!(2021-07-06 13:30:00 UTC).unix_timestamp());
+        let half_day = nyse.session(date!(2021-11-26), false).unwrap().unwrap();
+        assert_eq!(half_day.close, datetime!(2021-11-26 18:00:00 UTC).unix_timestamp());
+        let extended = nyse.session(date!(2021-01-04), true).unwrap().unwrap();
+        assert_eq!(extended.open, datetime!(2021-01-04 09:00:00 UTC).unix_timestamp());
+        assert_eq!(extended.close, datetime!(2021-01-05 01:00:00 UTC).unix_timestamp());
+        assert!(nyse.session(date!(2021-01-02), false).unwrap().is_none());
+
+        // the Globex session for Monday opens on Sunday evening
+        let cme = TradingCalendar::cme();
+        let sunday_evening = datetime!(2021-01-03 23:30:00 UTC).unix_timestamp();
+        let session = cme.session_at(sunday_evening, true).unwrap().unwrap();
+        assert_eq!(session.date, date!(2021-01-04));
+        assert_eq!(session.open, datetime!(2021-01-03 23:00:00 UTC).unix_timestamp());
+        assert_eq!(session.close, datetime!(2021-01-04 22:00:00 UTC).unix_timestamp());
+        assert!(cme.session_at(sunday_evening, false).unwrap().is_none());
+
+        let saturday = datetime!(2021-01-02 00:00:00 UTC).unix_timestamp();
+        let sunday = datetime!(2021-01-03 00:00:00 UTC).unix_timestamp();
+        let monday = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        assert!(nyse.sessions(saturday, sunday + 86399, true).unwrap().is_empty());
+        assert!(!nyse.expects_bars(&HashedBarSize::Day, saturday, sunday, false).unwrap());
+        // New Year's Day is a holiday as well
+        assert!(!nyse.expects_bars(&HashedBarSize::Day, saturday - 86400, sunday, false).unwrap());
+        assert!(nyse.expects_bars(&HashedBarSize::Day, saturday, monday, false).unwrap());
+        assert!(!nyse.expects_bars(&HashedBarSize::Min15, monday, monday + 14 * 3600, false).unwrap());
+        assert!(nyse.expects_bars(&HashedBarSize::Min15, monday, monday + 14 * 3600, true).unwrap());
+        assert!(nyse.expects_bars(&HashedBarSize::Week, saturday, sunday, false).unwrap());
+    }
+
+    #[test]
+    fn retrieve_data_skips_closed_days() {
+        let storage = temp_storage("closed_days");
+        let (source, requests) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage), Box::new(source)).unwrap();
+
+        let weekend = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-02 00:00:00 UTC),
+            datetime!(2021-01-03 00:00:00 UTC)
+        ).unwrap();
+        assert!(weekend.is_empty());
+        assert!(requests.borrow().is_empty());
+        assert!(broker.has_range("AAPL", HashedBarSize::Day, datetime!(2021-01-02 00:00:00 UTC).unix_timestamp(), datetime!(2021-01-03 00:00:00 UTC).unix_timestamp()).unwrap());
+
+        // New Year's Day is skipped too, only the days after the weekend are asked for
+        let jan_3 = datetime!(2021-01-03 00:00:00 UTC).unix_timestamp();
+        let jan_5 = datetime!(2021-01-05 00:00:00 UTC).unix_timestamp();
+        broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-01 00:00:00 UTC),
+            datetime!(2021-01-05 00:00:00 UTC)
+        ).unwrap();
+        assert_eq!(*requests.borrow(), vec![(jan_3 + 1, jan_5)]);
+
+        // forex trades around the clock, so nothing is skipped for it
+        broker.retrieve_data(
+            Instrument::forex("EUR", "USD"),
+            HashedBarSize::Day,
+            datetime!(2021-01-02 00:00:00 UTC),
+            datetime!(2021-01-03 00:00:00 UTC)
+        ).unwrap();
+        assert_eq!(requests.borrow().len(), 2);
+    }
+
+    #[test]
+    fn resample_in_sessions_test() {
+        use fq_data_broker::resample::{resample, resample_in_sessions, source_window_in_sessions};
+        use fq_data_broker::TradingCalendar;
+
+        // Globex minutes from the Sunday evening open into Monday
+        let cme = TradingCalendar::cme();
+        let sunday_open = datetime!(2021-01-03 23:00:00 UTC).unix_timestamp();
+        let monday = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let bars: Vec<IBApiBar> = [sunday_open, sunday_open + 60, monday + 3600].iter()
+            .map(|date| IBApiBar::builder().date(*date).ohlc(10.0, 10.0, 10.0, 10.0).volume(1.0).count(1).wap(10.0).build().unwrap())
+            .collect();
+        assert_eq!(resample(&bars, &HashedBarSize::Min, &HashedBarSize::Day).unwrap().len(), 2);
+        let days = resample_in_sessions(&bars, &HashedBarSize::Min, &HashedBarSize::Day, &cme, true).unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].date, monday);
+        assert_eq!(days[0].count(), 3);
+        let window = source_window_in_sessions(&HashedBarSize::Day, monday, monday, &cme, true).unwrap();
+        assert_eq!(*window.start(), sunday_open);
+
+        // the first quarter hour is missing, buckets still start at the 9:30 open
+        let nyse = TradingCalendar::nyse();
+        let open = datetime!(2021-01-04 14:30:00 UTC).unix_timestamp();
+        let bars: Vec<IBApiBar> = (15..120).map(|minute| IBApiBar::builder().date(open + minute * 60).ohlc(1.0, 1.0, 1.0, 1.0).volume(1.0).count(1).wap(1.0).build().unwrap()).collect();
+        let hours = resample(&bars, &HashedBarSize::Min, &HashedBarSize::Hour).unwrap();
+        assert_eq!(hours[0].date, open + 15 * 60);
+        let hours = resample_in_sessions(&bars, &HashedBarSize::Min, &HashedBarSize::Hour, &nyse, false).unwrap();
+        assert_eq!(hours.iter().map(|bar| bar.date).collect::<Vec<i64>>(), vec![open, open + 3600]);
+        assert_eq!(hours[0].count(), 45);
+    }
+
+    #[test]
+    fn offline_broker_serves_cached_data() {
+        use fq_data_broker::OfflineError;
+
+        let storage = temp_storage("offline");
+        let jan_4 = datetime!(2021-01-04 00:00:00 UTC).unix_timestamp();
+        let jan_8 = datetime!(2021-01-08 00:00:00 UTC).unix_timestamp();
+        let bars: Vec<IBApiBar> = (0..5).map(|day| IBApiBar::builder().date(jan_4 + day * 86400).ohlc(1.0, 1.0, 1.0, 1.0).volume(10.0).count(1).wap(1.0).build().unwrap()).collect();
+        let (source, _) = StubSource::new();
+        let mut broker = DataBroker::with_source(Some(storage.clone()), Box::new(source)).unwrap();
+        broker.insert("AAPL".to_string(), HashedBarSize::Day, jan_4, jan_8, bars.clone()).unwrap();
+        drop(broker);
+
+        // nothing listens there, but the broker does not connect until it has to
+        let config = IbapiConfig { port: Some(1), connect_timeout_secs: 1, ..IbapiConfig::default() };
+        let broker = DataBroker::new(Some(storage), config);
+        assert!(broker.is_ok(), "Error: {:?}", broker.err());
+        let mut broker = broker.unwrap();
+        let data = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-01-08 00:00:00 UTC)
+        ).unwrap();
+        assert_eq!(data, bars);
+
+        let missing = broker.retrieve_data(
+            "AAPL".to_string(),
+            HashedBarSize::Day,
+            datetime!(2021-01-04 00:00:00 UTC),
+            datetime!(2021-01-12 00:00:00 UTC)
+        );
+        let error = missing.unwrap_err();
+        assert!(error.downcast_ref::<OfflineError>().is_some(), "Error: {:?}", error);
+        // the failed fetch did not mark anything as cached
+        assert!(!broker.has_range("AAPL".to_string(), HashedBarSize::Day, jan_8, jan_8 + 86400).unwrap());
+    }
+
+    #[test]
+    fn non_stock_instruments_are_cached_separately() {
+        /// Serves one bar per day whose close encodes the security type it was asked for.
+        struct InstrumentSource {
+            instruments: std::rc::Rc<std::cell::RefCell<Vec<Instrument>>>,
+        }
+
+        impl HistoricalDataSource for InstrumentSource {
+            fn fetch_bars(&mut self, instrument: &Instrument, _series: &HistoricalSeries, _bar_size: HashedBarSize, start_date: OffsetDateTime, _end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+                self.instruments.borrow_mut().push(instrument.clone());
+                let price = if instrument.security_type == InstrumentType::Future { 2.0 } else { 1.0 };
+                Ok(vec![IBApiBar::builder().date(start_date.unix_timestamp()).ohlc(price, price, price, price).volume(1.0).count(1).wap(price).build().unwrap()])
+            }
+        }
+
+        let storage = temp_storage("instruments");
+        let instruments = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut broker = DataBroker::with_source(Some(storage.clone()), Box::new(InstrumentSource { instruments: instruments.clone() })).unwrap();
+        let future = Instrument::future("ES", "CME", "202103");
+
+        let start = datetime!(2021-01-04 00:00:00 UTC);
+        let end = datetime!(2021-01-05 00:00:00 UTC);
+        let futures = broker.retrieve_data(&future, HashedBarSize::Day, start, end).unwrap();
+        let stocks = broker.retrieve_data("ES".to_string(), HashedBarSize::Day, start, end).unwrap();
+        assert_eq!(futures[0].close, OrderedFloat(2.0));
+        assert_eq!(stocks[0].close, OrderedFloat(1.0));
+        assert_eq!(*instruments.borrow(), vec![future.clone(), Instrument::stock("ES")]);
+
+        let root = std::path::Path::new(&storage);
+        assert!(root.join("FUT_ES_CME_USD_202103").join("day.json").exists());
+        assert!(root.join("ES").join("day.json").exists());
+
+        // a new broker finds the future again under its directory
+        let mut broker = DataBroker::with_source(Some(storage), Box::new(InstrumentSource { instruments: instruments.clone() })).unwrap();
+        assert!(broker.has_range(&future, HashedBarSize::Day, start.unix_timestamp(), end.unix_timestamp()).unwrap());
+    }
+
+    #[test]
+    fn series_are_cached_separately() {
+        /// Serves one bar per request, priced by whether bid or trade bars were asked for.
+        struct SeriesSource;
+
+        impl HistoricalDataSource for SeriesSource {
+            fn fetch_bars(&mut self, _instrument: &Instrument, series: &HistoricalSeries, _bar_size: HashedBarSize, start_date: OffsetDateTime, _end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+                let price = match series.what_to_show {
+                    HashedWhatToShow::Bid => 1.0,
+                    _ => 2.0,
+                };
+                let price = if series.use_rth { price } else { price * 10.0 };
+                Ok(vec![IBApiBar::builder().date(start_date.unix_timestamp()).ohlc(price, price, price, price).volume(1.0).count(1).wap(price).build().unwrap()])
+            }
+        }
+
+        let storage = temp_storage("series");
+        let mut broker = DataBroker::with_source(Some(storage.clone()), Box::new(SeriesSource)).unwrap();
+        let start = datetime!(2021-01-04 00:00:00 UTC);
+        let end = datetime!(2021-01-05 00:00:00 UTC);
+        let bid = SeriesKey::new("AAPL", HistoricalSeries::new(HashedWhatToShow::Bid, true));
+        let extended = SeriesKey::new("AAPL", HistoricalSeries::new(HashedWhatToShow::Trades, false));
+
+        let trades = broker.retrieve_data("AAPL", HashedBarSize::Day, start, end).unwrap();
+        let bids = broker.retrieve_data(&bid, HashedBarSize::Day, start, end).unwrap();
+        let extended_trades = broker.retrieve_data(&extended, HashedBarSize::Day, start, end).unwrap();
+        assert_eq!(trades[0].close, OrderedFloat(2.0));
+        assert_eq!(bids[0].close, OrderedFloat(1.0));
+        assert_eq!(extended_trades[0].close, OrderedFloat(20.0));
+
+        let root = std::path::Path::new(&storage);
+        assert!(root.join("AAPL").join("day.json").exists());
+        assert!(root.join("AAPL@bid_rth").join("day.json").exists());
+        assert!(root.join("AAPL@trades_eth").join("day.json").exists());
+        assert_eq!(SeriesKey::from("AAPL").directory_name(), "AAPL");
+        // a symbol spelling out a series keeps its own directory
+        assert_eq!(SeriesKey::from("AAPL.bid_rth").directory_name(), "AAPL.bid%5Frth");
+        assert_eq!(SeriesKey::from("AAPL@bid_rth").directory_name(), "AAPL%40bid%5Frth");
+        assert_eq!(bid.directory_name(), "AAPL@bid_rth");
+    }
+
+    #[test]
+    fn synthetic_source_test() {
+        let source = SyntheticSource::new(7, PriceModel::GeometricBrownian { drift: 0.05, volatility: 0.2 });
+        let aapl = Instrument::stock("AAPL");
+        let (start, end) = (datetime!(2021-01-04 00:00:00 UTC), datetime!(2021-01-29 23:59:59 UTC));
+
+        let minutes = source.generate(&aapl, HashedBarSize::Min, start, end).unwrap();
+        // 20 weekdays of 390 minutes
+        assert_eq!(minutes.len(), 20 * 390);
+        assert!(minutes.iter().all(|bar| bar.validate().is_ok()));
+        assert_eq!(minutes, source.clone().generate(&aapl, HashedBarSize::Min, start, end).unwrap());
+        assert_ne!(minutes, source.generate(&Instrument::stock("MSFT"), HashedBarSize::Min, start, end).unwrap());
+
+        // separately generated windows line up
+        let middle = datetime!(2021-01-15 18:00:00 UTC);
+        let mut halves = source.generate(&aapl, HashedBarSize::Min, start, middle).unwrap();
+        halves.extend(source.generate(&aapl, HashedBarSize::Min, middle + time::Duration::SECOND, end).unwrap());
+        assert_eq!(halves, minutes);
+
+        // coarser bars are built from the same minutes
+        let days = source.generate(&aapl, HashedBarSize::Day, start, end).unwrap();
+        let hours = source.generate(&aapl, HashedBarSize::Hour, start, end).unwrap();
+        assert_eq!(days.len(), 20);
+        assert_eq!(days[0].close(), minutes[389].close());
+        assert_eq!(days[0].high(), minutes[..390].iter().map(|bar| bar.high()).max().unwrap());
+        assert_eq!(hours[0].date, datetime!(2021-01-04 14:30:00 UTC).unix_timestamp());
+        assert_eq!(hours[6].close(), days[0].close());
+        assert_eq!(source.generate(&aapl, HashedBarSize::Week, start, end).unwrap().len(), 4);
+
+        // volume smiles: the first and last minutes of the session trade more than midday
+        let volume_at = |minute: usize| minutes.iter().skip(minute).step_by(390).map(|bar| bar.volume().into_inner()).sum::<f64>();
+        assert!(volume_at(0) > 2.0 * volume_at(195));
+        assert!(volume_at(389) > 2.0 * volume_at(195));
+
+        assert!(source.generate(&aapl, HashedBarSize::Day, datetime!(1999-12-01 00:00:00 UTC), end).is_err());
+    }
+
+    #[test]
+    fn synthetic_models_test() {
+        let aapl = Instrument::stock("AAPL");
+        let (start, end) = (datetime!(2020-01-01 00:00:00 UTC), datetime!(2020-12-31 23:59:59 UTC));
+
+        // mean reversion keeps the price close to its mean
+        let ou = SyntheticSource::new(1, PriceModel::OrnsteinUhlenbeck { mean: 50.0, reversion: 20.0, volatility: 5.0 });
+        let days = ou.generate(&aapl, HashedBarSize::Day, start, end).unwrap();
+        assert_eq!(days.len(), 262);
+        assert!(days.iter().all(|bar| (bar.close().into_inner() - 50.0).abs() < 10.0));
+
+        // a calm and a wild regime
+        let regimes = vec![Regime { drift: 0.1, volatility: 0.1 }, Regime { drift: -0.2, volatility: 0.6 }];
+        let switching = SyntheticSource::new(1, PriceModel::RegimeSwitching { regimes, switch_probability: 0.05 });
+        assert!(switching.generate(&aapl, HashedBarSize::Day, start, end).unwrap().iter().all(|bar| bar.validate().is_ok()));
+
+        // frequent large jumps show up as large daily moves
+        let jumps = SyntheticSource::new(1, PriceModel::JumpDiffusion { drift: 0.0, volatility: 0.1, intensity: 50.0, jump_mean: 0.0, jump_volatility: 0.1 });
+        let days = jumps.generate(&aapl, HashedBarSize::Day, start, end).unwrap();
+        assert!(days.windows(2).any(|pair| (pair[1].close().into_inner() / pair[0].close().into_inner()).ln().abs() > 0.1));
+    }
+
+    #[test]
+    fn retrieve_data_from_synthetic_source() {
+        let storage = temp_storage("synthetic");
+        let source = SyntheticSource::new(3, PriceModel::GeometricBrownian { drift: 0.0, volatility: 0.3 });
+        let mut broker = DataBroker::with_source(Some(storage.clone()), Box::new(source.clone())).unwrap();
+        let (start, end) = (datetime!(2021-03-01 00:00:00 UTC), datetime!(2021-03-05 23:59:59 UTC));
+        let bars = broker.retrieve_data("AAPL", HashedBarSize::Min15, start, end).unwrap();
+        assert_eq!(bars, source.generate(&Instrument::stock("AAPL"), HashedBarSize::Min15, start, end).unwrap());
+        assert_eq!(bars.len(), 5 * 26);
+        assert!(broker.has_range("AAPL", HashedBarSize::Min15, start.unix_timestamp(), end.unix_timestamp()).unwrap());
+    }
+}
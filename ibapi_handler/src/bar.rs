@@ -0,0 +1,134 @@
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use crate::IBApiBar;
+
+/// An IBApiBar with plain f64 fields, for number crunching and interop outside of the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PlainBar {
+    pub date: i64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub count: i32,
+    pub wap: f64,
+}
+
+impl From<&IBApiBar> for PlainBar {
+    fn from(bar: &IBApiBar) -> Self {
+        PlainBar {
+            date: bar.date,
+            open: bar.open().into_inner(),
+            high: bar.high().into_inner(),
+            low: bar.low().into_inner(),
+            close: bar.close().into_inner(),
+            volume: bar.volume().into_inner(),
+            count: bar.count(),
+            wap: bar.wap().into_inner(),
+        }
+    }
+}
+
+impl From<IBApiBar> for PlainBar {
+    fn from(bar: IBApiBar) -> Self {
+        PlainBar::from(&bar)
+    }
+}
+
+/// Goes through the same checks as IBApiBarBuilder::build.
+impl TryFrom<PlainBar> for IBApiBar {
+    type Error = Error;
+
+    fn try_from(bar: PlainBar) -> Result<Self, Self::Error> {
+        IBApiBar::from_plain(bar).validated()
+    }
+}
+
+/// Builds an IBApiBar field by field, checking it on `build`.
+/// Date, open, high, low and close are required; volume and count default to 0
+/// and wap to the typical price (high + low + close) / 3.
+#[derive(Debug, Clone, Default)]
+pub struct IBApiBarBuilder {
+    date: Option<i64>,
+    open: Option<f64>,
+    high: Option<f64>,
+    low: Option<f64>,
+    close: Option<f64>,
+    volume: f64,
+    count: i32,
+    wap: Option<f64>,
+}
+
+impl IBApiBarBuilder {
+    /// The bar's start as a unix timestamp.
+    pub fn date(mut self, date: i64) -> Self {
+        self.date = Some(date);
+        self
+    }
+
+    /// The bar's start.
+    pub fn datetime(self, datetime: OffsetDateTime) -> Self {
+        self.date(datetime.unix_timestamp())
+    }
+
+    pub fn open(mut self, open: f64) -> Self {
+        self.open = Some(open);
+        self
+    }
+
+    pub fn high(mut self, high: f64) -> Self {
+        self.high = Some(high);
+        self
+    }
+
+    pub fn low(mut self, low: f64) -> Self {
+        self.low = Some(low);
+        self
+    }
+
+    pub fn close(mut self, close: f64) -> Self {
+        self.close = Some(close);
+        self
+    }
+
+    /// Sets open, high, low and close at once.
+    pub fn ohlc(self, open: f64, high: f64, low: f64, close: f64) -> Self {
+        self.open(open).high(high).low(low).close(close)
+    }
+
+    pub fn volume(mut self, volume: f64) -> Self {
+        self.volume = volume;
+        self
+    }
+
+    pub fn count(mut self, count: i32) -> Self {
+        self.count = count;
+        self
+    }
+
+    pub fn wap(mut self, wap: f64) -> Self {
+        self.wap = Some(wap);
+        self
+    }
+
+    pub fn build(self) -> Result<IBApiBar, Error> {
+        IBApiBar::from_plain(self.plain()?).validated()
+    }
+
+    /// Builds the bar without checking its values, for series that do not pass `IBApiBar::validate`
+    /// (e.g. MIDPOINT, whose volume IB reports as -1). The required fields are still required.
+    pub fn build_unchecked(self) -> Result<IBApiBar, Error> {
+        Ok(IBApiBar::from_plain(self.plain()?))
+    }
+
+    fn plain(self) -> Result<PlainBar, Error> {
+        let (date, open, high, low, close) = match (self.date, self.open, self.high, self.low, self.close) {
+            (Some(date), Some(open), Some(high), Some(low), Some(close)) => (date, open, high, low, close),
+            _ => bail!("A bar needs a date, open, high, low and close"),
+        };
+        let wap = self.wap.unwrap_or((high + low + close) / 3.0);
+        Ok(PlainBar { date, open, high, low, close, volume: self.volume, count: self.count, wap })
+    }
+}
@@ -0,0 +1,62 @@
+use ibapi::market_data::historical::{BarSize, Duration};
+use time::OffsetDateTime;
+
+const DAY: i64 = 86400;
+
+/// One IB historical data request: `duration` worth of bars ending at `end`.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoricalRequest {
+    pub end: OffsetDateTime,
+    pub duration: Duration,
+    /// The span `duration` stands for, in seconds.
+    pub seconds: i64,
+}
+
+/// The longest span IB serves in one request for a bar size, in seconds, following IB's
+/// "valid duration and bar size settings" table (e.g. 1800 S for 1 sec bars, 1 W for 5 min bars).
+/// Month-wide limits are taken as 28 days and year-wide ones as 365 days so they are valid for every month/year.
+pub fn max_request_seconds(bar_size: BarSize) -> i64 {
+    match bar_size {
+        BarSize::Sec => 1800,
+        BarSize::Sec5 => 3600,
+        BarSize::Sec15 => 14400,
+        BarSize::Sec30 => 28800,
+        BarSize::Min => DAY,
+        BarSize::Min2 => 2 * DAY,
+        BarSize::Min3 | BarSize::Min5 | BarSize::Min15 | BarSize::Min20 => 7 * DAY,
+        BarSize::Min30 | BarSize::Hour | BarSize::Hour2 | BarSize::Hour3 | BarSize::Hour4 | BarSize::Hour8 => 28 * DAY,
+        BarSize::Day | BarSize::Week | BarSize::Month => 365 * DAY,
+    }
+}
+
+/// The IB duration covering `seconds`: seconds up to a day, whole days (rounded up) beyond that.
+fn to_ib_duration(seconds: i64) -> (Duration, i64) {
+    if seconds <= DAY {
+        (Duration::seconds(seconds as i32), seconds)
+    } else {
+        let days = (seconds + DAY - 1) / DAY;
+        (Duration::days(days as i32), days * DAY)
+    }
+}
+
+/// Splits `start_date..=end_date` into IB-legal requests for `bar_size`, walking back from `end_date`.
+/// Requests never exceed max_request_seconds; the last (earliest) one may reach a little before `start_date`
+/// when IB can only express its span in whole days. A window of a single instant still yields one request.
+pub fn plan_requests(bar_size: BarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Vec<HistoricalRequest> {
+    let start = start_date.unix_timestamp();
+    let max = max_request_seconds(bar_size);
+    let mut requests = Vec::new();
+    let mut end = end_date.unix_timestamp();
+    loop {
+        let (duration, seconds) = to_ib_duration((end - start).clamp(1, max));
+        requests.push(HistoricalRequest {
+            end: end_date - time::Duration::seconds(end_date.unix_timestamp() - end),
+            duration,
+            seconds,
+        });
+        end -= seconds;
+        if end <= start {
+            return requests;
+        }
+    }
+}
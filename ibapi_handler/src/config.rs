@@ -0,0 +1,126 @@
+use std::path::Path;
+use anyhow::{bail, Context, Error};
+use serde::{Deserialize, Serialize};
+
+/// Whether a TWS/Gateway session trades a paper or a live account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TradingMode {
+    #[default]
+    Paper,
+    Live,
+}
+
+impl TradingMode {
+    /// The port TWS listens on by default in this mode (IB Gateway uses 4002/4001 instead).
+    pub fn default_port(&self) -> u16 {
+        match self {
+            TradingMode::Paper => 7497,
+            TradingMode::Live => 7496,
+        }
+    }
+}
+
+/// Where and as whom to connect to TWS or IB Gateway.
+/// Every field is optional in a TOML file; missing ones take the defaults (paper TWS on 127.0.0.1, client id 100).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IbapiConfig {
+    pub host: String,
+    /// Defaults to the TWS port of `mode`.
+    pub port: Option<u16>,
+    /// Has to be unique among the clients connected to the same TWS/Gateway.
+    pub client_id: i32,
+    /// How long to wait for the TCP connection before giving up, in seconds.
+    pub connect_timeout_secs: u64,
+    pub mode: TradingMode,
+}
+
+impl Default for IbapiConfig {
+    fn default() -> Self {
+        IbapiConfig {
+            host: "127.0.0.1".to_string(),
+            port: None,
+            client_id: 100,
+            connect_timeout_secs: 10,
+            mode: TradingMode::Paper,
+        }
+    }
+}
+
+fn parse_var<T: std::str::FromStr>(lookup: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, Error>
+    where T::Err: std::error::Error + Send + Sync + 'static {
+    match lookup(name) {
+        Some(value) => Ok(Some(value.trim().parse::<T>().with_context(|| format!("Invalid value '{}' for {}", value, name))?)),
+        None => Ok(None),
+    }
+}
+
+// non-unicode values are read lossily, and fail to parse unless they are hosts
+fn env_lookup(name: &str) -> Option<String> {
+    std::env::var_os(name).map(|value| value.to_string_lossy().into_owned())
+}
+
+impl IbapiConfig {
+    /// The defaults, overridden by whichever of `FQ_IB_HOST`, `FQ_IB_PORT`, `FQ_IB_CLIENT_ID`,
+    /// `FQ_IB_CONNECT_TIMEOUT_SECS` and `FQ_IB_MODE` (`paper` or `live`) are set.
+    pub fn from_env() -> Result<Self, Error> {
+        IbapiConfig::default().with_env()
+    }
+
+    /// Overrides the fields of this config with the `FQ_IB_*` environment variables that are set.
+    pub fn with_env(self) -> Result<Self, Error> {
+        self.with_vars(env_lookup)
+    }
+
+    /// Like from_env, with the variables looked up by `lookup` instead of in the environment.
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        IbapiConfig::default().with_vars(lookup)
+    }
+
+    /// Like with_env, with the variables looked up by `lookup` instead of in the environment.
+    pub fn with_vars(mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        if let Some(host) = parse_var(&lookup, "FQ_IB_HOST")? {
+            self.host = host;
+        }
+        if let Some(port) = parse_var(&lookup, "FQ_IB_PORT")? {
+            self.port = Some(port);
+        }
+        if let Some(client_id) = parse_var(&lookup, "FQ_IB_CLIENT_ID")? {
+            self.client_id = client_id;
+        }
+        if let Some(timeout) = parse_var(&lookup, "FQ_IB_CONNECT_TIMEOUT_SECS")? {
+            self.connect_timeout_secs = timeout;
+        }
+        if let Some(mode) = parse_var::<String>(&lookup, "FQ_IB_MODE")? {
+            self.mode = match mode.to_lowercase().as_str() {
+                "paper" => TradingMode::Paper,
+                "live" => TradingMode::Live,
+                _ => bail!("Invalid value '{}' for FQ_IB_MODE, expected paper or live", mode),
+            };
+        }
+        Ok(self)
+    }
+
+    /// Parses a config from TOML, e.g. `host = "10.0.0.5"`, `client_id = 7`, `mode = "live"`.
+    pub fn from_toml_str(toml: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Reads a config from a TOML file.
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Could not read {}", path.as_ref().display()))?;
+        Self::from_toml_str(&contents)
+    }
+
+    /// The port to connect to.
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.mode.default_port())
+    }
+
+    /// `host:port`, as ibapi expects it.
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port())
+    }
+}
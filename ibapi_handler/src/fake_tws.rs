@@ -0,0 +1,299 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use anyhow::{bail, Error};
+use time::macros::format_description;
+use time::OffsetDateTime;
+use crate::{IBApiBar, IbapiConfig};
+
+/// The server version the fake reports, the newest ibapi 0.4 asks for.
+const SERVER_VERSION: i32 = 165;
+
+// message ids of the TWS API
+const START_API: &str = "71";
+const REQUEST_HISTORICAL_DATA: &str = "20";
+const ERROR: &str = "4";
+const NEXT_VALID_ID: &str = "9";
+const MANAGED_ACCOUNTS: &str = "15";
+const HISTORICAL_DATA: &str = "17";
+
+/// How the fake answers the API handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FakeHandshake {
+    /// Reports its server version and connection time, then sends the next order id and managed accounts.
+    Normal,
+    /// Closes the connection instead of answering, like a TWS that does not trust the client's host.
+    Close,
+    /// Answers with these fields instead, e.g. to send a malformed server version.
+    Raw(Vec<String>),
+}
+
+/// How the fake answers a historical-data request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FakeResponse {
+    Bars(Vec<IBApiBar>),
+    /// An error message for the request, e.g. code 162 for "Historical Market Data Service error".
+    Error { code: i32, message: String },
+    /// These fields as the whole message, e.g. to send a malformed one.
+    Raw(Vec<String>),
+    /// No answer at all; ibapi gives up on the request after 10 seconds.
+    Silence,
+}
+
+/// A historical-data request the fake received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeHistoricalRequest {
+    pub request_id: String,
+    pub symbol: String,
+    pub security_type: String,
+    pub exchange: String,
+    pub currency: String,
+    /// As sent, e.g. `20210102 00:00:00 UTC`.
+    pub end: String,
+    pub bar_size: String,
+    pub duration: String,
+    pub use_rth: bool,
+    pub what_to_show: String,
+}
+
+impl FakeHistoricalRequest {
+    /// The requested window as unix timestamps, `end - duration` to `end`.
+    pub fn window(&self) -> Result<(i64, i64), Error> {
+        let format = format_description!("[year][month][day] [hour]:[minute]:[second]");
+        let end = match self.end.strip_suffix(" UTC") {
+            Some(end) => time::PrimitiveDateTime::parse(end, format)?.assume_utc(),
+            None => OffsetDateTime::now_utc(),
+        };
+        let (count, unit) = match self.duration.split_once(' ') {
+            Some((count, unit)) => (count.parse::<i64>()?, unit),
+            None => bail!("Invalid duration '{}'", self.duration),
+        };
+        let seconds = match unit {
+            "S" => count,
+            "D" => count * 86400,
+            "W" => count * 7 * 86400,
+            "M" => count * 30 * 86400,
+            "Y" => count * 365 * 86400,
+            _ => bail!("Invalid duration '{}'", self.duration),
+        };
+        Ok((end.unix_timestamp() - seconds, end.unix_timestamp()))
+    }
+
+    fn parse(fields: &[String]) -> Result<Self, Error> {
+        if fields.len() < 20 {
+            bail!("Historical-data request with {} fields", fields.len());
+        }
+        Ok(FakeHistoricalRequest {
+            request_id: fields[1].clone(),
+            symbol: fields[3].clone(),
+            security_type: fields[4].clone(),
+            exchange: fields[9].clone(),
+            currency: fields[11].clone(),
+            end: fields[15].clone(),
+            bar_size: fields[16].clone(),
+            duration: fields[17].clone(),
+            use_rth: fields[18] == "1",
+            what_to_show: fields[19].clone(),
+        })
+    }
+}
+
+type Responder = Box<dyn Fn(&FakeHistoricalRequest) -> FakeResponse + Send + Sync>;
+
+struct FakeState {
+    handshake: FakeHandshake,
+    respond: Responder,
+    stopped: AtomicBool,
+    connections: AtomicUsize,
+    requests: Mutex<Vec<FakeHistoricalRequest>>,
+}
+
+/// A stand-in for TWS/IB Gateway on a local port, speaking just enough of the API
+/// (handshake, start API, historical data) for `ibapi::client::Client` to connect and request bars.
+/// Meant for tests of the connection handling that should not need an IB account.
+///
+/// It stops accepting connections when dropped. Connections already made stay open until the client goes
+/// away, since ibapi's reader thread spins on a closed socket.
+pub struct FakeTws {
+    address: SocketAddr,
+    state: Arc<FakeState>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl FakeTws {
+    /// A fake that accepts connections and answers every historical-data request without bars.
+    pub fn start() -> Result<FakeTws, Error> {
+        FakeTws::with_responder(FakeHandshake::Normal, |_| FakeResponse::Bars(Vec::new()))
+    }
+
+    /// A fake that answers historical-data requests with the `bars` inside the requested window.
+    pub fn serving(bars: Vec<IBApiBar>) -> Result<FakeTws, Error> {
+        FakeTws::with_responder(FakeHandshake::Normal, move |request| match request.window() {
+            Ok((start, end)) => FakeResponse::Bars(bars.iter().filter(|bar| bar.date >= start && bar.date < end).cloned().collect()),
+            Err(e) => FakeResponse::Error { code: 321, message: e.to_string() },
+        })
+    }
+
+    /// A fake answering the handshake as told and historical-data requests with whatever `respond` returns.
+    pub fn with_responder<F>(handshake: FakeHandshake, respond: F) -> Result<FakeTws, Error>
+        where F: Fn(&FakeHistoricalRequest) -> FakeResponse + Send + Sync + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(FakeState {
+            handshake,
+            respond: Box::new(respond),
+            stopped: AtomicBool::new(false),
+            connections: AtomicUsize::new(0),
+            requests: Mutex::new(Vec::new()),
+        });
+        let accept_state = state.clone();
+        let accept_thread = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accept_state.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    accept_state.connections.fetch_add(1, Ordering::SeqCst);
+                    let state = accept_state.clone();
+                    std::thread::spawn(move || {
+                        // the client going away ends the session, nothing to report
+                        let _ = serve(stream, &state);
+                    });
+                }
+            }
+        });
+        Ok(FakeTws { address, state, accept_thread: Some(accept_thread) })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    /// A config pointing at the fake, with a short connect timeout.
+    pub fn config(&self) -> IbapiConfig {
+        IbapiConfig {
+            host: self.address.ip().to_string(),
+            port: Some(self.port()),
+            connect_timeout_secs: 1,
+            ..IbapiConfig::default()
+        }
+    }
+
+    /// How many connections were accepted so far.
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// The historical-data requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<FakeHistoricalRequest> {
+        self.state.requests.lock().map(|requests| requests.clone()).unwrap_or_default()
+    }
+}
+
+impl Drop for FakeTws {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        // wake the accept loop up so it sees it has been stopped
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, state: &FakeState) -> Result<(), Error> {
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix)?;
+    if &prefix != b"API\0" {
+        bail!("Not an API client");
+    }
+    read_frame(&mut stream)?; // supported versions, e.g. v100..165
+
+    match &state.handshake {
+        FakeHandshake::Close => return Ok(()),
+        FakeHandshake::Raw(fields) => write_frame(&mut stream, fields)?,
+        FakeHandshake::Normal => {
+            let format = format_description!("[year][month][day] [hour]:[minute]:[second]");
+            let now = OffsetDateTime::now_utc().format(format)?;
+            write_frame(&mut stream, &[SERVER_VERSION.to_string(), format!("{} UTC", now)])?;
+        },
+    }
+
+    loop {
+        let fields = read_frame(&mut stream)?;
+        match fields.first().map(|id| id.as_str()) {
+            Some(START_API) => {
+                write_frame(&mut stream, &fields_of(&[NEXT_VALID_ID, "1", "1"]))?;
+                write_frame(&mut stream, &fields_of(&[MANAGED_ACCOUNTS, "1", "DU0000000"]))?;
+            },
+            Some(REQUEST_HISTORICAL_DATA) => {
+                let request = FakeHistoricalRequest::parse(&fields)?;
+                if let Ok(mut requests) = state.requests.lock() {
+                    requests.push(request.clone());
+                }
+                match (state.respond)(&request) {
+                    FakeResponse::Bars(bars) => write_frame(&mut stream, &historical_data(&request, &bars)?)?,
+                    FakeResponse::Error { code, message } => {
+                        write_frame(&mut stream, &[ERROR.to_string(), "2".to_string(), request.request_id.clone(), code.to_string(), message])?
+                    },
+                    FakeResponse::Raw(fields) => write_frame(&mut stream, &fields)?,
+                    FakeResponse::Silence => {},
+                }
+            },
+            // anything else goes unanswered
+            _ => {},
+        }
+    }
+}
+
+/// A historical-data message; day and longer bars are dated `yyyyMMdd` like TWS does, shorter ones by timestamp.
+fn historical_data(request: &FakeHistoricalRequest, bars: &[IBApiBar]) -> Result<Vec<String>, Error> {
+    let (start, end) = request.window()?;
+    let window_format = format_description!("[year][month][day]  [hour]:[minute]:[second]");
+    let day_format = format_description!("[year][month][day]");
+    let daily = matches!(request.bar_size.as_str(), "1 day" | "1 week" | "1 month");
+
+    let mut fields = vec![
+        HISTORICAL_DATA.to_string(),
+        request.request_id.clone(),
+        OffsetDateTime::from_unix_timestamp(start)?.format(window_format)?,
+        OffsetDateTime::from_unix_timestamp(end)?.format(window_format)?,
+        bars.len().to_string(),
+    ];
+    for bar in bars {
+        fields.push(if daily { bar.datetime()?.format(day_format)? } else { bar.date.to_string() });
+        for value in [bar.open(), bar.high(), bar.low(), bar.close(), bar.volume(), bar.wap()] {
+            fields.push(value.to_string());
+        }
+        fields.push(bar.count().to_string());
+    }
+    Ok(fields)
+}
+
+fn fields_of(fields: &[&str]) -> Vec<String> {
+    fields.iter().map(|field| field.to_string()).collect()
+}
+
+/// Reads one length-prefixed message and splits it into its NUL-terminated fields.
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<String>, Error> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+    let mut data = vec![0u8; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut data)?;
+    let text = String::from_utf8(data)?;
+    Ok(text.strip_suffix('\0').unwrap_or(&text).split('\0').map(|field| field.to_string()).collect())
+}
+
+fn write_frame(stream: &mut TcpStream, fields: &[String]) -> Result<(), Error> {
+    let mut data = fields.join("\0");
+    data.push('\0');
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data.as_bytes())?;
+    Ok(())
+}
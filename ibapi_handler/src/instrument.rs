@@ -0,0 +1,228 @@
+use ibapi::contracts::{Contract, SecurityType};
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+
+/// The kinds of instruments we fetch and cache bars for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum InstrumentType {
+    Stock,
+    Future,
+    Option,
+    FuturesOption,
+    Forex,
+    Index,
+    Crypto,
+}
+
+impl InstrumentType {
+    /// IB's short code for the security type, e.g. `FUT`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            InstrumentType::Stock => "STK",
+            InstrumentType::Future => "FUT",
+            InstrumentType::Option => "OPT",
+            InstrumentType::FuturesOption => "FOP",
+            InstrumentType::Forex => "CASH",
+            InstrumentType::Index => "IND",
+            InstrumentType::Crypto => "CRYPTO",
+        }
+    }
+
+    fn to_security_type(self) -> SecurityType {
+        match self {
+            InstrumentType::Stock => SecurityType::Stock,
+            InstrumentType::Future => SecurityType::Future,
+            InstrumentType::Option => SecurityType::Option,
+            InstrumentType::FuturesOption => SecurityType::FuturesOption,
+            InstrumentType::Forex => SecurityType::ForexPair,
+            InstrumentType::Index => SecurityType::Index,
+            InstrumentType::Crypto => SecurityType::Crypto,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OptionRight {
+    Call,
+    Put,
+}
+
+impl OptionRight {
+    pub fn code(&self) -> &'static str {
+        match self {
+            OptionRight::Call => "C",
+            OptionRight::Put => "P",
+        }
+    }
+}
+
+/// Describes an instrument independently of ibapi, so it can be stored, hashed and used as a cache key.
+/// `expiry` is a contract month (`YYYYMM`) or last trading day (`YYYYMMDD`) for derivatives.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Instrument {
+    pub security_type: InstrumentType,
+    pub symbol: String,
+    pub exchange: String,
+    pub currency: String,
+    pub expiry: Option<String>,
+    pub strike: Option<OrderedFloat<f64>>,
+    pub right: Option<OptionRight>,
+    pub multiplier: Option<String>,
+}
+
+impl Instrument {
+    fn new(security_type: InstrumentType, symbol: &str, exchange: &str, currency: &str) -> Instrument {
+        Instrument {
+            security_type,
+            symbol: symbol.to_string(),
+            exchange: exchange.to_string(),
+            currency: currency.to_string(),
+            expiry: None,
+            strike: None,
+            right: None,
+            multiplier: None,
+        }
+    }
+
+    /// A SMART routed stock (or ETF) traded in USD.
+    pub fn stock(symbol: &str) -> Instrument {
+        Instrument::new(InstrumentType::Stock, symbol, "SMART", "USD")
+    }
+
+    /// A futures contract, e.g. `Instrument::future("ES", "CME", "202403")`.
+    pub fn future(symbol: &str, exchange: &str, expiry: &str) -> Instrument {
+        Instrument {
+            expiry: Some(expiry.to_string()),
+            ..Instrument::new(InstrumentType::Future, symbol, exchange, "USD")
+        }
+    }
+
+    /// A SMART routed equity option with the usual multiplier of 100.
+    pub fn option(symbol: &str, expiry: &str, strike: f64, right: OptionRight) -> Instrument {
+        Instrument {
+            expiry: Some(expiry.to_string()),
+            strike: Some(OrderedFloat(strike)),
+            right: Some(right),
+            multiplier: Some("100".to_string()),
+            ..Instrument::new(InstrumentType::Option, symbol, "SMART", "USD")
+        }
+    }
+
+    /// An option on a futures contract.
+    pub fn futures_option(symbol: &str, exchange: &str, expiry: &str, strike: f64, right: OptionRight) -> Instrument {
+        Instrument {
+            expiry: Some(expiry.to_string()),
+            strike: Some(OrderedFloat(strike)),
+            right: Some(right),
+            ..Instrument::new(InstrumentType::FuturesOption, symbol, exchange, "USD")
+        }
+    }
+
+    /// A currency pair on IDEALPRO, e.g. `Instrument::forex("EUR", "USD")` for EUR.USD.
+    pub fn forex(base: &str, quote: &str) -> Instrument {
+        Instrument::new(InstrumentType::Forex, base, "IDEALPRO", quote)
+    }
+
+    /// An index, e.g. `Instrument::index("SPX", "CBOE")`.
+    pub fn index(symbol: &str, exchange: &str) -> Instrument {
+        Instrument::new(InstrumentType::Index, symbol, exchange, "USD")
+    }
+
+    /// A crypto currency traded in USD on PAXOS.
+    pub fn crypto(symbol: &str) -> Instrument {
+        Instrument::new(InstrumentType::Crypto, symbol, "PAXOS", "USD")
+    }
+
+    pub fn with_multiplier(mut self, multiplier: &str) -> Instrument {
+        self.multiplier = Some(multiplier.to_string());
+        self
+    }
+
+    /// The ibapi contract to request data for.
+    pub fn to_contract(&self) -> Contract {
+        Contract {
+            symbol: self.symbol.clone(),
+            security_type: self.security_type.to_security_type(),
+            exchange: self.exchange.clone(),
+            currency: self.currency.clone(),
+            last_trade_date_or_contract_month: self.expiry.clone().unwrap_or_default(),
+            strike: self.strike.map(|strike| strike.into_inner()).unwrap_or_default(),
+            right: self.right.map(|right| right.code().to_string()).unwrap_or_default(),
+            multiplier: self.multiplier.clone().unwrap_or_default(),
+            // futures are requested by contract month, keep expired ones available for backtests
+            include_expired: self.security_type == InstrumentType::Future,
+            ..Default::default()
+        }
+    }
+
+    /// The name of the directory the instrument's data is cached in.
+    /// SMART routed USD stocks keep using their bare symbol (`AAPL`), everything else spells out
+    /// its identifying fields, e.g. `FUT_ES_CME_USD_202403` or `OPT_AAPL_SMART_USD_20240119_150_C_100`.
+    pub fn directory_name(&self) -> String {
+        if *self == Instrument::stock(&self.symbol) {
+            return sanitize(&self.symbol);
+        }
+        let mut parts = vec![
+            self.security_type.code().to_string(),
+            self.symbol.clone(),
+            self.exchange.clone(),
+            self.currency.clone(),
+        ];
+        if let Some(expiry) = &self.expiry {
+            parts.push(expiry.clone());
+        }
+        if let Some(strike) = self.strike {
+            parts.push(strike.to_string());
+        }
+        if let Some(right) = self.right {
+            parts.push(right.code().to_string());
+        }
+        if let Some(multiplier) = &self.multiplier {
+            parts.push(multiplier.clone());
+        }
+        parts.iter().map(|part| sanitize(part)).collect::<Vec<String>>().join("_")
+    }
+}
+
+/// Keeps a directory name portable without two parts ending up with the same name:
+/// letters, digits, `-` and `.` are kept, anything else (and a leading `.`) is escaped as `%` and
+/// the hex of its UTF-8 bytes, so `BRK B` becomes `BRK%20B` and does not meet `BRK-B`.
+fn sanitize(part: &str) -> String {
+    let mut sanitized = String::with_capacity(part.len());
+    for (i, c) in part.chars().enumerate() {
+        if c.is_ascii_alphanumeric() || c == '-' || (c == '.' && i > 0) {
+            sanitized.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                sanitized.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    sanitized
+}
+
+/// Plain tickers are SMART routed USD stocks, as they always have been.
+impl From<&str> for Instrument {
+    fn from(symbol: &str) -> Self {
+        Instrument::stock(symbol)
+    }
+}
+
+impl From<String> for Instrument {
+    fn from(symbol: String) -> Self {
+        Instrument::stock(&symbol)
+    }
+}
+
+impl From<&String> for Instrument {
+    fn from(symbol: &String) -> Self {
+        Instrument::stock(symbol)
+    }
+}
+
+impl From<&Instrument> for Instrument {
+    fn from(instrument: &Instrument) -> Self {
+        instrument.clone()
+    }
+}
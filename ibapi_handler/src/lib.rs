@@ -2,11 +2,34 @@ use std::fmt::{Debug};
 use ibapi::client::Client;
 use ibapi::contracts::Contract;
 use ibapi::market_data::historical::{BarSize, WhatToShow};
-use ibapi::market_data::historical::{Duration};
 use serde::{Deserialize, Serialize};
 use ordered_float::OrderedFloat;
 use anyhow::{Error, bail};
 use time::OffsetDateTime;
+use std::collections::BTreeMap;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+
+pub mod bar;
+pub mod chunking;
+pub mod config;
+// a TWS stand-in for tests, see the fake-tws feature
+#[cfg(feature = "fake-tws")]
+pub mod fake_tws;
+pub mod instrument;
+pub mod pacing;
+pub mod recording;
+pub mod series;
+
+pub use bar::{IBApiBarBuilder, PlainBar};
+pub use config::{IbapiConfig, TradingMode};
+pub use instrument::{Instrument, InstrumentType, OptionRight};
+pub use recording::{RecordedRequest, Recording};
+pub use series::{HashedWhatToShow, HistoricalSeries};
+
+use chunking::{plan_requests, HistoricalRequest};
+use pacing::{is_pacing_violation, PacingLimiter};
+use recording::HandlerMode;
 
 // We use OrderedFloat to avoid dealing with RangeMap's Eq requirement
 // Hopefully this doesn't cause any issues
@@ -23,10 +46,73 @@ pub struct IBApiBar {
 }
 
 impl IBApiBar {
+    /// The bar of `bar`'s values, unchecked. Outside of this crate bars are made with `builder`.
+    pub(crate) fn from_plain(bar: PlainBar) -> Self {
+        IBApiBar {
+            date: bar.date,
+            open: OrderedFloat::from(bar.open),
+            high: OrderedFloat::from(bar.high),
+            low: OrderedFloat::from(bar.low),
+            close: OrderedFloat::from(bar.close),
+            volume: OrderedFloat::from(bar.volume),
+            count: bar.count,
+            wap: OrderedFloat::from(bar.wap),
+        }
+    }
+
+    /// Starts building a bar whose values are checked once built.
+    pub fn builder() -> IBApiBarBuilder {
+        IBApiBarBuilder::default()
+    }
+
+    /// Checks that the bar is consistent: finite prices, high >= max(open, close),
+    /// low <= min(open, close) and a non-negative volume.
+    /// Bars of series without volume (IB reports -1 for e.g. MIDPOINT) do not pass.
+    pub fn validate(&self) -> Result<(), Error> {
+        let prices = [self.open, self.high, self.low, self.close, self.wap];
+        if prices.iter().any(|price| !price.is_finite()) || !self.volume.is_finite() {
+            bail!("Bar at {} has a non-finite value", self.date);
+        }
+        if self.high < self.open.max(self.close) {
+            bail!("Bar at {} has high {} below its open {} or close {}", self.date, self.high, self.open, self.close);
+        }
+        if self.low > self.open.min(self.close) {
+            bail!("Bar at {} has low {} above its open {} or close {}", self.date, self.low, self.open, self.close);
+        }
+        if self.volume < OrderedFloat(0.0) {
+            bail!("Bar at {} has negative volume {}", self.date, self.volume);
+        }
+        if self.count < 0 {
+            bail!("Bar at {} has negative count {}", self.date, self.count);
+        }
+        Ok(())
+    }
+
+    /// The bar itself if it passes `validate`.
+    pub fn validated(self) -> Result<Self, Error> {
+        self.validate()?;
+        Ok(self)
+    }
+
     pub fn date(&self) -> i64 {
         self.date.clone()
     }
 
+    /// The bar's start as a UTC date time.
+    pub fn datetime(&self) -> Result<OffsetDateTime, Error> {
+        Ok(OffsetDateTime::from_unix_timestamp(self.date)?)
+    }
+
+    /// Moves the bar to start at `datetime`.
+    pub fn set_datetime(&mut self, datetime: OffsetDateTime) {
+        self.date = datetime.unix_timestamp();
+    }
+
+    /// The bar's values as plain f64s.
+    pub fn to_plain(&self) -> PlainBar {
+        PlainBar::from(self)
+    }
+
     pub fn open(&self) -> OrderedFloat<f64> {
         self.open.clone()
     }
@@ -56,94 +142,227 @@ impl IBApiBar {
     }
 }
 
+/// Returned when TWS/Gateway cannot be reached. Callers can tell it apart from other failures
+/// with `error.downcast_ref::<OfflineError>()` and fall back to cached data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OfflineError {
+    pub address: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for OfflineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "offline: TWS/Gateway at {} is unavailable ({})", self.address, self.reason)
+    }
+}
+
+impl std::error::Error for OfflineError {}
+
 pub struct IbapiHandler {
-    client: Client,
+    client: Option<Client>, // None until the first request, or after the connection was lost
+    config: IbapiConfig,
+    pacing: PacingLimiter,
+    mode: HandlerMode,
 }
 
+/// How often a request refused for pacing is retried, and how long to back off before the first retry
+/// (doubling on every further one).
+const PACING_RETRIES: u32 = 5;
+const PACING_BACKOFF: std::time::Duration = std::time::Duration::from_secs(15);
+
 impl IbapiHandler {
-    pub fn new() -> Result<IbapiHandler, Error> {
-        let client = connect_to_tws()?;
+    /// Connects to the TWS/Gateway described by `config` right away.
+    pub fn new(config: IbapiConfig) -> Result<IbapiHandler, Error> {
+        let mut handler = IbapiHandler::lazy(config);
+        handler.connect()?;
+        Ok(handler)
+    }
+
+    /// A handler that only connects to the TWS/Gateway described by `config` once it is asked for data.
+    pub fn lazy(config: IbapiConfig) -> IbapiHandler {
+        IbapiHandler {
+            client: None,
+            config,
+            pacing: PacingLimiter::default(),
+            mode: HandlerMode::Live,
+        }
+    }
+
+    /// A lazy handler that saves every historical-data response to `path` (see recording::Recording),
+    /// adding to the recording already there.
+    pub fn recording<P: AsRef<Path>>(config: IbapiConfig, path: P) -> Result<IbapiHandler, Error> {
+        let recording = if path.as_ref().exists() { Recording::load(path.as_ref())? } else { Recording::default() };
         Ok(IbapiHandler {
-            client,
+            mode: HandlerMode::Recording { path: path.as_ref().to_path_buf(), recording },
+            ..IbapiHandler::lazy(config)
         })
     }
 
-    pub fn get_historical_data(&self, contract: &Contract, bar_size: BarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
-        // calculate duration
-        let duration = end_date - start_date;
-        // convert seconds to ibapi duration
-        let duration = convert_durations(duration, bar_size);
-        let data = self.client.historical_data(
-            contract,
-            end_date,
-            duration,
-            bar_size,
-            WhatToShow::Trades,
-            true
-        )?;
+    /// A handler that never connects and answers from the recording at `path`.
+    /// Requests that were not recorded fail.
+    pub fn replay<P: AsRef<Path>>(path: P) -> Result<IbapiHandler, Error> {
+        Ok(IbapiHandler::from_recording(Recording::load(path)?))
+    }
 
-        let bars = convert_bar_vec_to_wrapper(data.bars);
-        Ok(bars)
+    /// A handler that never connects and answers from `recording`.
+    pub fn from_recording(recording: Recording) -> IbapiHandler {
+        IbapiHandler {
+            mode: HandlerMode::Replay(recording),
+            ..IbapiHandler::lazy(IbapiConfig::default())
+        }
     }
-}
 
-pub fn connect_to_tws() -> Result<Client, Error> {
-    let client = Client::connect("127.0.0.1:7497", 100);
-    return match client {
-        Ok(c) => {
-            println!("Connected to TWS");
-            Ok(c)
-        },
-        Err(e) => {
-            println!("Error: {:?}", e);
-            bail!(e)
+    /// Replays the recording at `fixture`, or, when `FQ_IB_RECORD` is set, records into it from the TWS/Gateway
+    /// described by `config`. Lets tests run without TWS while keeping a way to refresh their data.
+    /// The fixtures committed in this repository are hand-written stand-ins, not recordings of TWS,
+    /// see their `description`.
+    pub fn from_fixture<P: AsRef<Path>>(config: IbapiConfig, fixture: P) -> Result<IbapiHandler, Error> {
+        if std::env::var_os("FQ_IB_RECORD").is_some() {
+            IbapiHandler::recording(config, fixture)
+        } else {
+            IbapiHandler::replay(fixture)
         }
     }
-}
 
-fn clamp_duration(seconds: i32) -> Duration {
-    if seconds > 86400 {
-        Duration::days(seconds / 86400)
-    } else {
-        Duration::seconds(seconds)
+    /// The responses recorded or replayed so far, if the handler records or replays.
+    pub fn recorded(&self) -> Option<&Recording> {
+        match &self.mode {
+            HandlerMode::Live => None,
+            HandlerMode::Recording { recording, .. } => Some(recording),
+            HandlerMode::Replay(recording) => Some(recording),
+        }
     }
-}
 
-/// takes a time duration and converts it into a ibapi duration
-pub fn convert_durations(duration: time::Duration, bar_size: BarSize) -> Duration{
-
-    let seconds = duration.whole_seconds() as i32;
-    println!("Seconds: {:?}", seconds);
-
-    return match bar_size {
-        BarSize::Sec => clamp_duration(seconds),
-        BarSize::Sec5 => clamp_duration(seconds),
-        BarSize::Sec15 => clamp_duration(seconds),
-        BarSize::Sec30 => clamp_duration(seconds),
-        BarSize::Min => clamp_duration(seconds),
-        BarSize::Min2 => clamp_duration(seconds),
-        BarSize::Min3 => clamp_duration(seconds),
-        BarSize::Min5 => clamp_duration(seconds),
-        BarSize::Min15 => clamp_duration(seconds),
-        BarSize::Min20 => clamp_duration(seconds),
-        BarSize::Min30 => clamp_duration(seconds),
-        BarSize::Hour => clamp_duration(seconds),
-        BarSize::Hour2 => clamp_duration(seconds),
-        BarSize::Hour3 => clamp_duration(seconds),
-        BarSize::Hour4 => clamp_duration(seconds),
-        BarSize::Hour8 => clamp_duration(seconds),
-        BarSize::Day => {
-            let days = seconds / 86400;
-            if days > 365 {
-                Duration::years(seconds / 31536000)
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.mode, HandlerMode::Replay(_))
+    }
+
+    /// The settings this handler connects with.
+    pub fn config(&self) -> &IbapiConfig {
+        &self.config
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Connects unless already connected. Fails with an OfflineError if TWS/Gateway cannot be reached.
+    pub fn connect(&mut self) -> Result<(), Error> {
+        if self.client.is_none() {
+            match connect_to_tws(&self.config) {
+                Ok(client) => self.client = Some(client),
+                Err(e) => return Err(OfflineError { address: self.config.address(), reason: e.to_string() }.into()),
             }
-            else {
-                Duration::days(seconds / 86400)
+        }
+        Ok(())
+    }
+
+    /// Drops the connection, the next request connects again.
+    pub fn disconnect(&mut self) {
+        self.client = None;
+    }
+
+    /// Retrieves the trade bars of `contract` during regular trading hours between `start_date` and `end_date`.
+    pub fn get_historical_data(&mut self, contract: &Contract, bar_size: BarSize, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+        self.get_historical_series(contract, bar_size, &HistoricalSeries::default(), start_date, end_date)
+    }
+
+    /// Retrieves the bars of `series` for `contract` between `start_date` and `end_date`.
+    /// The window is split into requests IB accepts for `bar_size` (see chunking::plan_requests),
+    /// which are paced to IB's limits and retried with backoff on pacing violations.
+    /// The results are stitched together sorted by date, without duplicates, and clipped to the window:
+    /// bars IB returns from before `start_date` (requests span whole days) or after `end_date` are dropped.
+    pub fn get_historical_series(&mut self, contract: &Contract, bar_size: BarSize, series: &HistoricalSeries, start_date: OffsetDateTime, end_date: OffsetDateTime) -> Result<Vec<IBApiBar>, Error> {
+        let what_to_show = series.what_to_show.to_what_to_show();
+        let window = start_date.unix_timestamp()..=end_date.unix_timestamp();
+        let mut bars = BTreeMap::new();
+        for request in plan_requests(bar_size, start_date, end_date) {
+            for bar in self.request_historical_data(contract, bar_size, what_to_show, series.use_rth, &request)? {
+                if window.contains(&bar.date) {
+                    bars.insert(bar.date, bar);
+                }
             }
-        },
-        BarSize::Week => Duration::weeks(seconds / 604800),
-        BarSize::Month => Duration::months(seconds / 2592000),
+        }
+        Ok(bars.into_values().collect())
+    }
+
+    fn request_historical_data(&mut self, contract: &Contract, bar_size: BarSize, what_to_show: WhatToShow, use_rth: bool, request: &HistoricalRequest) -> Result<Vec<IBApiBar>, Error> {
+        // IB paces per contract, exchange and tick type
+        let contract_key = format!("{}|{:?}|{}|{}", contract.symbol, contract.security_type, contract.exchange, what_to_show.to_string());
+        let request_key = format!("{}|{}|{}|{}|{}", contract_key, request.end.unix_timestamp(), request.duration.to_string(), bar_size.to_string(), use_rth);
+        let recorded = RecordedRequest::new(contract, bar_size, what_to_show, use_rth, request);
+        if let HandlerMode::Replay(recording) = &self.mode {
+            return match recording.response(&recorded) {
+                Some(bars) => Ok(bars.clone()),
+                None => bail!("No recorded response for {:?}", recorded),
+            };
+        }
+        let mut backoff = PACING_BACKOFF;
+        let mut retries = 0;
+        let mut reconnected = false;
+        loop {
+            self.connect()?;
+            self.pacing.wait(&request_key, &contract_key);
+            let client = match &self.client {
+                Some(client) => client,
+                None => bail!("Not connected to TWS"),
+            };
+            let data = client.historical_data(
+                contract,
+                request.end,
+                request.duration,
+                bar_size,
+                what_to_show,
+                use_rth
+            );
+            match data {
+                Ok(data) => {
+                    let bars = convert_bar_vec_to_wrapper(data.bars);
+                    if let HandlerMode::Recording { path, recording } = &mut self.mode {
+                        recording.record(recorded, bars.clone());
+                        recording.save(path.as_path())?;
+                    }
+                    return Ok(bars);
+                },
+                Err(e) if is_pacing_violation(&e) && retries < PACING_RETRIES => {
+                    log::warn!("Pacing violation, retrying in {:?}", backoff);
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    retries += 1;
+                },
+                // the connection dropped (TWS restarted, network blip), reconnect once and try again
+                Err(ibapi::Error::Io(e)) if !reconnected => {
+                    log::warn!("Lost connection to TWS ({:?}), reconnecting", e);
+                    // the request never got an answer, retrying it is not a repeat IB would refuse
+                    self.pacing.exempt_identical(&request_key);
+                    self.disconnect();
+                    reconnected = true;
+                },
+                Err(e) => bail!(e),
+            }
+        }
+    }
+}
+
+pub fn connect_to_tws(config: &IbapiConfig) -> Result<Client, Error> {
+    // ibapi blocks on connect without a timeout, so make sure something is listening first
+    let timeout = std::time::Duration::from_secs(config.connect_timeout_secs);
+    let address = match config.address().to_socket_addrs()?.next() {
+        Some(address) => address,
+        None => bail!("Could not resolve {}", config.address()),
     };
+    if let Err(e) = TcpStream::connect_timeout(&address, timeout) {
+        bail!("Could not reach TWS at {}: {}", config.address(), e);
+    }
+
+    let client = Client::connect(&config.address(), config.client_id);
+    return match client {
+        Ok(c) => {
+            log::info!("Connected to TWS at {} as client {}", config.address(), config.client_id);
+            Ok(c)
+        },
+        Err(e) => bail!(e),
+    }
 }
 
 // /// Given a `client` and a `contract`, retrieves historical data for the contract
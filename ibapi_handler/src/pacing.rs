@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Keeps historical data requests within IB's pacing limits:
+/// - at most `max_requests` requests in any `window` (60 per 10 minutes),
+/// - no identical request within `identical_interval` (15 seconds),
+/// - at most `max_per_contract` requests for the same contract in any `contract_window` (IB flags 6 in 2 seconds).
+///
+/// The limiter works on caller supplied instants so it can be driven without sleeping;
+/// `wait` is the blocking convenience used by IbapiHandler.
+#[derive(Debug, Clone)]
+pub struct PacingLimiter {
+    pub max_requests: usize,
+    pub window: Duration,
+    pub identical_interval: Duration,
+    pub max_per_contract: usize,
+    pub contract_window: Duration,
+    history: VecDeque<(Instant, Option<String>, String)>, // (sent at, request key, contract key), oldest first
+}
+
+impl Default for PacingLimiter {
+    fn default() -> Self {
+        PacingLimiter {
+            max_requests: 60,
+            window: Duration::from_secs(600),
+            identical_interval: Duration::from_secs(15),
+            max_per_contract: 5,
+            contract_window: Duration::from_secs(2),
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl PacingLimiter {
+    /// How long to hold off before sending `request` for `contract` at `now` (zero if it may go now).
+    pub fn delay(&self, request: &str, contract: &str, now: Instant) -> Duration {
+        let mut delay = Duration::ZERO;
+        let mut wait_until = |sent: Instant, period: Duration| {
+            delay = delay.max((sent + period).saturating_duration_since(now));
+        };
+
+        let recent: Vec<&(Instant, Option<String>, String)> = self.history.iter()
+            .filter(|(sent, _, _)| now.saturating_duration_since(*sent) < self.window)
+            .collect();
+        if recent.len() >= self.max_requests {
+            wait_until(recent[recent.len() - self.max_requests].0, self.window);
+        }
+
+        if let Some((sent, _, _)) = self.history.iter().rev().find(|(_, key, _)| key.as_deref() == Some(request)) {
+            wait_until(*sent, self.identical_interval);
+        }
+
+        let same_contract: Vec<Instant> = self.history.iter()
+            .filter(|(sent, _, key)| key == contract && now.saturating_duration_since(*sent) < self.contract_window)
+            .map(|(sent, _, _)| *sent)
+            .collect();
+        if same_contract.len() >= self.max_per_contract {
+            wait_until(same_contract[same_contract.len() - self.max_per_contract], self.contract_window);
+        }
+        delay
+    }
+
+    /// Records that `request` for `contract` was sent at `now`.
+    pub fn record(&mut self, request: &str, contract: &str, now: Instant) {
+        let keep = self.window.max(self.identical_interval).max(self.contract_window);
+        while let Some((sent, _, _)) = self.history.front() {
+            if now.saturating_duration_since(*sent) < keep {
+                break;
+            }
+            self.history.pop_front();
+        }
+        self.history.push_back((now, Some(request.to_string()), contract.to_string()));
+    }
+
+    /// Lets `request` be sent again right away, for retrying an attempt that failed without an answer
+    /// (e.g. a dropped connection). The earlier attempts still count toward the other limits.
+    pub fn exempt_identical(&mut self, request: &str) {
+        for (_, key, _) in self.history.iter_mut() {
+            if key.as_deref() == Some(request) {
+                *key = None;
+            }
+        }
+    }
+
+    /// Blocks until `request` may be sent and records it.
+    pub fn wait(&mut self, request: &str, contract: &str) {
+        loop {
+            let now = Instant::now();
+            let delay = self.delay(request, contract, now);
+            if delay.is_zero() {
+                self.record(request, contract, now);
+                return;
+            }
+            log::info!("Pacing historical data requests, waiting {:?}", delay);
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// Whether an error returned by TWS is a historical data pacing violation (error 162).
+pub fn is_pacing_violation(error: &ibapi::Error) -> bool {
+    error.to_string().to_lowercase().contains("pacing violation")
+}
@@ -0,0 +1,121 @@
+//! Record and replay of historical-data requests, so tests run without TWS.
+//! What is recorded are the bars a request was answered with, after ibapi decoded them, not the raw
+//! TWS messages: a replay gives back the same bars but does not go through ibapi's decoding again.
+//! The decoding is covered by the fake_tws tests instead.
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Error};
+use ibapi::contracts::Contract;
+use ibapi::market_data::historical::{BarSize, WhatToShow};
+use serde::{Deserialize, Serialize};
+use crate::chunking::HistoricalRequest;
+use crate::IBApiBar;
+
+/// The contract fields a historical-data request is identified by.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedContract {
+    pub symbol: String,
+    pub security_type: String,
+    pub exchange: String,
+    pub currency: String,
+    pub expiry: String,
+    pub strike: f64,
+    pub right: String,
+    pub multiplier: String,
+}
+
+impl From<&Contract> for RecordedContract {
+    fn from(contract: &Contract) -> Self {
+        RecordedContract {
+            symbol: contract.symbol.clone(),
+            security_type: contract.security_type.to_string(),
+            exchange: contract.exchange.clone(),
+            currency: contract.currency.clone(),
+            expiry: contract.last_trade_date_or_contract_month.clone(),
+            strike: contract.strike,
+            right: contract.right.clone(),
+            multiplier: contract.multiplier.clone(),
+        }
+    }
+}
+
+/// A historical-data request as it was sent to TWS, in IB's own terms (`"1 day"`, `"7 D"`, `"TRADES"`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub contract: RecordedContract,
+    /// The end of the request as a unix timestamp.
+    pub end: i64,
+    pub duration: String,
+    pub bar_size: String,
+    pub what_to_show: String,
+    pub use_rth: bool,
+}
+
+impl RecordedRequest {
+    pub fn new(contract: &Contract, bar_size: BarSize, what_to_show: WhatToShow, use_rth: bool, request: &HistoricalRequest) -> Self {
+        RecordedRequest {
+            contract: RecordedContract::from(contract),
+            end: request.end.unix_timestamp(),
+            duration: request.duration.to_string(),
+            bar_size: bar_size.to_string(),
+            what_to_show: what_to_show.to_string(),
+            use_rth,
+        }
+    }
+}
+
+/// A request and the bars TWS answered it with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedExchange {
+    pub request: RecordedRequest,
+    pub bars: Vec<IBApiBar>,
+}
+
+/// Historical-data requests and the decoded bars they were answered with, saved as JSON so tests can replay
+/// them without TWS. Only successful responses are recorded; errors (pacing, disconnects) are not replayed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    /// Where the responses came from, e.g. which TWS session and when.
+    #[serde(default)]
+    pub description: Option<String>,
+    pub exchanges: Vec<RecordedExchange>,
+}
+
+impl Recording {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Could not read recording {}", path.as_ref().display()))?;
+        serde_json::from_str(&contents).with_context(|| format!("Invalid recording {}", path.as_ref().display()))
+    }
+
+    /// Writes the recording as pretty-printed JSON, creating its directory if needed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path.as_ref(), serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Could not write recording {}", path.as_ref().display()))
+    }
+
+    /// The bars recorded for `request`, if it was recorded.
+    pub fn response(&self, request: &RecordedRequest) -> Option<&Vec<IBApiBar>> {
+        self.exchanges.iter().find(|exchange| exchange.request == *request).map(|exchange| &exchange.bars)
+    }
+
+    /// Records the response to `request`, replacing an earlier one for the same request.
+    pub fn record(&mut self, request: RecordedRequest, bars: Vec<IBApiBar>) {
+        match self.exchanges.iter_mut().find(|exchange| exchange.request == request) {
+            Some(exchange) => exchange.bars = bars,
+            None => self.exchanges.push(RecordedExchange { request, bars }),
+        }
+    }
+}
+
+/// Where an IbapiHandler gets its bars from.
+pub(crate) enum HandlerMode {
+    Live,
+    /// Live, saving every response to `path` as it comes in.
+    Recording { path: PathBuf, recording: Recording },
+    /// Never connects, answers from the recording.
+    Replay(Recording),
+}
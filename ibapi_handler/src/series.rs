@@ -0,0 +1,72 @@
+use ibapi::market_data::historical::WhatToShow;
+use serde::{Deserialize, Serialize};
+
+/// Mirrors ibapi's WhatToShow for the bar series we request, so it can be hashed, stored and used in cache keys.
+/// There is no ADJUSTED_LAST: ibapi 0.4 has no way to request it, and IB only serves it up to now
+/// (without an end date), which chunked requests cannot use.
+#[derive(Clone, Debug, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum HashedWhatToShow {
+    Trades,
+    MidPoint,
+    Bid,
+    Ask,
+    BidAsk,
+    HistoricalVolatility,
+    OptionImpliedVolatility,
+}
+
+impl HashedWhatToShow {
+    /// The ibapi value to request.
+    pub fn to_what_to_show(&self) -> WhatToShow {
+        match self {
+            HashedWhatToShow::Trades => WhatToShow::Trades,
+            HashedWhatToShow::MidPoint => WhatToShow::MidPoint,
+            HashedWhatToShow::Bid => WhatToShow::Bid,
+            HashedWhatToShow::Ask => WhatToShow::Ask,
+            HashedWhatToShow::BidAsk => WhatToShow::BidAsk,
+            HashedWhatToShow::HistoricalVolatility => WhatToShow::HistoricalVolatility,
+            HashedWhatToShow::OptionImpliedVolatility => WhatToShow::OptionImpliedVolatility,
+        }
+    }
+
+    /// A short lowercase name, e.g. `bid_ask`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashedWhatToShow::Trades => "trades",
+            HashedWhatToShow::MidPoint => "midpoint",
+            HashedWhatToShow::Bid => "bid",
+            HashedWhatToShow::Ask => "ask",
+            HashedWhatToShow::BidAsk => "bid_ask",
+            HashedWhatToShow::HistoricalVolatility => "historical_volatility",
+            HashedWhatToShow::OptionImpliedVolatility => "option_implied_volatility",
+        }
+    }
+}
+
+/// Which bars of an instrument to request: the series and whether to stick to regular trading hours.
+/// The default, trades during regular trading hours, is what was always requested before.
+#[derive(Clone, Debug, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct HistoricalSeries {
+    pub what_to_show: HashedWhatToShow,
+    pub use_rth: bool,
+}
+
+impl Default for HistoricalSeries {
+    fn default() -> Self {
+        HistoricalSeries {
+            what_to_show: HashedWhatToShow::Trades,
+            use_rth: true,
+        }
+    }
+}
+
+impl HistoricalSeries {
+    pub fn new(what_to_show: HashedWhatToShow, use_rth: bool) -> Self {
+        HistoricalSeries { what_to_show, use_rth }
+    }
+
+    /// e.g. `bid_rth` or `trades_eth` (extended trading hours).
+    pub fn name(&self) -> String {
+        format!("{}_{}", self.what_to_show.name(), if self.use_rth { "rth" } else { "eth" })
+    }
+}
@@ -22,7 +22,9 @@ mod ibapi_tests {
 
     #[test]
     pub fn save_test() {
-        let range_store = RangeDataStorage::new(None);
+        let location = std::env::temp_dir().join("ibapi_handler_tests").join("data.json");
+        let location = location.to_str().unwrap().to_string();
+        let range_store = RangeDataStorage::new(Some(location.clone()));
         let now = OffsetDateTime::now_utc();
         let day = time::Duration::days(1);
         let tomorrow = now.checked_add(day).unwrap();
@@ -30,9 +32,10 @@ mod ibapi_tests {
         let tomorrow_timestamp = tomorrow.unix_timestamp();
         let mut range_store = range_store.unwrap();
         range_store.insert(today_timestamp, tomorrow_timestamp, 3);
+        range_store.set_autosave(true);
         drop(range_store);
 
-        let range_store = RangeDataStorage::new(Some("data.json".to_string()));
+        let range_store = RangeDataStorage::new(Some(location));
         let range_store = range_store.unwrap();
         assert_eq!(range_store.get(today_timestamp), Some(&3));
         assert_eq!(range_store.get(tomorrow_timestamp), Some(&3));
@@ -44,11 +47,60 @@ mod handler_tests {
     use ibapi::contracts::Contract;
     use ibapi::market_data::historical::BarSize;
     use time::Month;
-    use ibapi_handler::IbapiHandler;
+    use time::macros::datetime;
+    use ibapi_handler::{IBApiBar, IbapiConfig, IbapiHandler, OfflineError, Recording, TradingMode};
+
+    #[test]
+    pub fn config_test() {
+        let config = IbapiConfig::default();
+        assert_eq!(config.address(), "127.0.0.1:7497");
+        assert_eq!(config.client_id, 100);
+
+        let config = IbapiConfig::from_toml_str("host = \"10.0.0.5\"\nclient_id = 7\nmode = \"live\"\n").unwrap();
+        assert_eq!(config.mode, TradingMode::Live);
+        assert_eq!(config.address(), "10.0.0.5:7496");
+        assert_eq!(config.client_id, 7);
+        assert_eq!(config.connect_timeout_secs, IbapiConfig::default().connect_timeout_secs);
+
+        let config = IbapiConfig::from_toml_str("port = 4002").unwrap();
+        assert_eq!(config.address(), "127.0.0.1:4002");
+        assert!(IbapiConfig::from_toml_str("mode = \"demo\"").is_err());
+
+        // variables are looked up through a function, so the tests never touch the process environment
+        let vars = |name: &str| match name {
+            "FQ_IB_PORT" => Some("4001".to_string()),
+            "FQ_IB_MODE" => Some("Live".to_string()),
+            _ => None,
+        };
+        let config = IbapiConfig::from_vars(vars).unwrap();
+        assert_eq!(config.port(), 4001);
+        assert_eq!(config.mode, TradingMode::Live);
+        assert_eq!(config.host, IbapiConfig::default().host);
+        assert!(IbapiConfig::from_vars(|name| (name == "FQ_IB_CLIENT_ID").then(|| "seven".to_string())).is_err());
+        assert!(IbapiConfig::from_vars(|name| (name == "FQ_IB_MODE").then(|| "demo".to_string())).is_err());
+    }
+
+    #[test]
+    pub fn lazy_offline_test() {
+        let config = IbapiConfig { port: Some(1), connect_timeout_secs: 1, ..IbapiConfig::default() };
+        let mut handler = IbapiHandler::lazy(config.clone());
+        assert!(!handler.is_connected());
+        let error = handler.connect().unwrap_err();
+        let offline = error.downcast_ref::<OfflineError>();
+        assert!(offline.is_some(), "Error: {:?}", error);
+        assert_eq!(offline.unwrap().address, "127.0.0.1:1");
+        assert!(!handler.is_connected());
+
+        let error = IbapiHandler::new(config).err().unwrap();
+        assert!(error.downcast_ref::<OfflineError>().is_some());
+    }
 
     #[test]
     pub fn instantiate_test() {
-        let handler = IbapiHandler::new();
+        // replays tests/fixtures/aapl_daily_2020.json unless FQ_IB_RECORD is set,
+        // a hand-written stand-in rather than a recording of TWS
+        let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/aapl_daily_2020.json");
+        let handler = IbapiHandler::from_fixture(IbapiConfig::from_env().unwrap(), fixture);
         assert!(handler.is_ok());
         let mut handler = handler.unwrap();
         let contract = Contract::stock("AAPL");
@@ -67,4 +119,322 @@ mod handler_tests {
         }
         println!("Bars: {:?}", result.len());
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn replay_test() {
+        let path = std::env::temp_dir().join("ibapi_handler_tests").join("recording.json");
+        let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/aapl_daily_2020.json");
+        let recording = Recording::load(&fixture).unwrap();
+        recording.save(&path).unwrap();
+        // saving a loaded recording gives back the same bytes
+        assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&fixture).unwrap());
+
+        let mut handler = IbapiHandler::replay(&path).unwrap();
+        assert!(handler.is_replaying());
+        let contract = Contract::stock("AAPL");
+        let bars = handler.get_historical_data(&contract, BarSize::Day, datetime!(2020-01-01 00:00:00 UTC), datetime!(2021-01-01 00:00:00 UTC)).unwrap();
+        let recorded: Vec<IBApiBar> = recording.exchanges.iter().flat_map(|exchange| exchange.bars.clone()).collect();
+        assert_eq!(bars.len(), recorded.len());
+        assert!(bars.iter().all(|bar| recorded.contains(bar)));
+        assert!(!handler.is_connected());
+
+        // anything that was not recorded fails instead of going to TWS
+        let error = handler.get_historical_data(&Contract::stock("MSFT"), BarSize::Day, datetime!(2020-01-01 00:00:00 UTC), datetime!(2021-01-01 00:00:00 UTC)).unwrap_err();
+        assert!(error.to_string().contains("No recorded response"));
+    }
+}
+#[cfg(test)]
+mod chunking_tests {
+    use std::time::{Duration, Instant};
+    use ibapi::market_data::historical::BarSize;
+    use time::macros::datetime;
+    use ibapi_handler::chunking::{max_request_seconds, plan_requests};
+    use ibapi_handler::pacing::PacingLimiter;
+
+    #[test]
+    pub fn plan_requests_test() {
+        // a year of 15 minute bars takes 53 one week requests
+        let start = datetime!(2020-01-01 00:00:00 UTC);
+        let end = datetime!(2021-01-01 00:00:00 UTC);
+        let requests = plan_requests(BarSize::Min15, start, end);
+        assert_eq!(requests.len(), 53);
+        assert_eq!(requests[0].end, end);
+        assert_eq!(requests[0].duration.to_string(), "7 D");
+        assert_eq!(requests[1].end, end - time::Duration::weeks(1));
+        for request in &requests {
+            assert!(request.seconds <= max_request_seconds(BarSize::Min15));
+        }
+        // consecutive requests line up without gaps
+        for pair in requests.windows(2) {
+            assert_eq!(pair[1].end.unix_timestamp(), pair[0].end.unix_timestamp() - pair[0].seconds);
+        }
+        assert!(requests[52].end.unix_timestamp() - requests[52].seconds <= start.unix_timestamp());
+
+        // second bars are requested in seconds
+        let requests = plan_requests(BarSize::Sec, end - time::Duration::minutes(45), end);
+        let durations: Vec<String> = requests.iter().map(|request| request.duration.to_string()).collect();
+        assert_eq!(durations, vec!["1800 S", "900 S"]);
+
+        // 2020 is a leap year, so its 366 days take a 365 day request and a one day one
+        let requests = plan_requests(BarSize::Day, start, end);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].duration.to_string(), "365 D");
+        assert_eq!(requests[1].duration.to_string(), "86400 S");
+        // while a year of 365 days fits in one
+        assert_eq!(plan_requests(BarSize::Day, end, datetime!(2022-01-01 00:00:00 UTC)).len(), 1);
+        assert_eq!(plan_requests(BarSize::Day, end, end).len(), 1);
+    }
+
+    #[test]
+    pub fn pacing_limiter_test() {
+        let mut limiter = PacingLimiter::default();
+        let start = Instant::now();
+
+        // identical requests are 15 seconds apart
+        limiter.record("AAPL 1", "AAPL", start);
+        assert_eq!(limiter.delay("AAPL 1", "AAPL", start + Duration::from_secs(5)), Duration::from_secs(10));
+        assert_eq!(limiter.delay("AAPL 2", "AAPL", start), Duration::ZERO);
+        // unless the earlier attempt failed without an answer
+        let mut retrying = limiter.clone();
+        retrying.exempt_identical("AAPL 1");
+        assert_eq!(retrying.delay("AAPL 1", "AAPL", start + Duration::from_secs(5)), Duration::ZERO);
+
+        // the sixth request for a contract within two seconds has to wait
+        for i in 2..=5 {
+            limiter.record(&format!("AAPL {}", i), "AAPL", start);
+        }
+        assert_eq!(limiter.delay("AAPL 6", "AAPL", start + Duration::from_millis(500)), Duration::from_millis(1500));
+        assert_eq!(limiter.delay("MSFT 1", "MSFT", start + Duration::from_millis(500)), Duration::ZERO);
+
+        // 60 requests in 10 minutes
+        let mut limiter = PacingLimiter::default();
+        for i in 0..60 {
+            limiter.record(&format!("request {}", i), &format!("contract {}", i), start + Duration::from_secs(i * 3));
+        }
+        let now = start + Duration::from_secs(200);
+        assert_eq!(limiter.delay("request 60", "contract 60", now), Duration::from_secs(400));
+        assert_eq!(limiter.delay("request 60", "contract 60", start + Duration::from_secs(600)), Duration::ZERO);
+    }
+}
+
+#[cfg(test)]
+mod instrument_tests {
+    use ibapi::contracts::SecurityType;
+    use ibapi_handler::{Instrument, InstrumentType, OptionRight};
+
+    #[test]
+    pub fn directory_name_test() {
+        assert_eq!(Instrument::stock("AAPL").directory_name(), "AAPL");
+        assert_eq!(Instrument::from("MSFT"), Instrument::stock("MSFT"));
+        assert_eq!(Instrument::future("ES", "CME", "202403").directory_name(), "FUT_ES_CME_USD_202403");
+        assert_eq!(Instrument::option("AAPL", "20240119", 152.5, OptionRight::Call).directory_name(), "OPT_AAPL_SMART_USD_20240119_152.5_C_100");
+        assert_eq!(Instrument::forex("EUR", "USD").directory_name(), "CASH_EUR_IDEALPRO_USD");
+
+        // a stock routed elsewhere does not share the SMART/USD cache
+        let mut sap = Instrument::stock("SAP");
+        sap.exchange = "IBIS".to_string();
+        sap.currency = "EUR".to_string();
+        assert_eq!(sap.directory_name(), "STK_SAP_IBIS_EUR");
+
+        // symbols that only differ in characters a directory name can not hold keep apart
+        assert_eq!(Instrument::stock("BRK B").directory_name(), "BRK%20B");
+        assert_eq!(Instrument::stock("BRK-B").directory_name(), "BRK-B");
+        assert_eq!(Instrument::stock("BRK_B").directory_name(), "BRK%5FB");
+        assert_eq!(Instrument::stock("BRK%20B").directory_name(), "BRK%2520B");
+        assert_eq!(Instrument::stock("..").directory_name(), "%2E.");
+    }
+
+    #[test]
+    pub fn to_contract_test() {
+        let contract = Instrument::stock("AAPL").to_contract();
+        assert_eq!(contract.symbol, "AAPL");
+        assert_eq!(contract.security_type, SecurityType::Stock);
+        assert_eq!(contract.exchange, "SMART");
+
+        let contract = Instrument::futures_option("ES", "CME", "202403", 5000.0, OptionRight::Put)
+            .with_multiplier("50")
+            .to_contract();
+        assert_eq!(contract.security_type, SecurityType::FuturesOption);
+        assert_eq!(contract.last_trade_date_or_contract_month, "202403");
+        assert_eq!(contract.strike, 5000.0);
+        assert_eq!(contract.right, "P");
+        assert_eq!(contract.multiplier, "50");
+
+        let contract = Instrument::crypto("BTC").to_contract();
+        assert_eq!(contract.security_type, SecurityType::Crypto);
+        assert_eq!(contract.exchange, "PAXOS");
+
+        let instrument = Instrument::index("SPX", "CBOE");
+        assert_eq!(instrument.security_type, InstrumentType::Index);
+        let toml = toml::to_string(&instrument).unwrap();
+        assert_eq!(toml::from_str::<Instrument>(&toml).unwrap(), instrument);
+    }
+}
+
+#[cfg(test)]
+mod series_tests {
+    use ibapi::market_data::historical::WhatToShow;
+    use ibapi_handler::{HashedWhatToShow, HistoricalSeries};
+
+    #[test]
+    pub fn series_test() {
+        let series = HistoricalSeries::default();
+        assert_eq!(series.what_to_show, HashedWhatToShow::Trades);
+        assert!(series.use_rth);
+        assert_eq!(series.name(), "trades_rth");
+        assert_eq!(HistoricalSeries::new(HashedWhatToShow::BidAsk, false).name(), "bid_ask_eth");
+        assert_eq!(HashedWhatToShow::MidPoint.to_what_to_show(), WhatToShow::MidPoint);
+    }
+}
+
+mod bar_tests {
+    use ibapi_handler::{IBApiBar, PlainBar};
+    use time::macros::datetime;
+
+    #[test]
+    fn builder_test() {
+        let bar = IBApiBar::builder()
+            .datetime(datetime!(2024-01-02 14:30 UTC))
+            .ohlc(10.0, 12.0, 9.0, 11.0)
+            .volume(100.0)
+            .count(5)
+            .build()
+            .unwrap();
+        assert_eq!(bar.datetime().unwrap(), datetime!(2024-01-02 14:30 UTC));
+        assert_eq!(bar.high().into_inner(), 12.0);
+        assert_eq!(bar.volume().into_inner(), 100.0);
+        assert_eq!(bar.count(), 5);
+        // wap defaults to the typical price
+        assert_eq!(bar.wap().into_inner(), (12.0 + 9.0 + 11.0) / 3.0);
+
+        // missing fields
+        assert!(IBApiBar::builder().date(0).open(1.0).build().is_err());
+        // high below the close, low above the open, negative volume
+        assert!(IBApiBar::builder().date(0).ohlc(10.0, 10.5, 9.0, 11.0).build().is_err());
+        assert!(IBApiBar::builder().date(0).ohlc(10.0, 12.0, 10.5, 11.0).build().is_err());
+        assert!(IBApiBar::builder().date(0).ohlc(10.0, 12.0, 9.0, 11.0).volume(-1.0).build().is_err());
+        assert!(IBApiBar::builder().date(0).ohlc(f64::NAN, 12.0, 9.0, 11.0).build().is_err());
+    }
+
+    #[test]
+    fn plain_bar_test() {
+        let bar = IBApiBar::builder().date(1_700_000_000).ohlc(10.0, 12.0, 9.0, 11.0).volume(100.0).count(5).wap(10.5).build().unwrap();
+        let plain = PlainBar::from(&bar);
+        assert_eq!(plain, PlainBar { date: 1_700_000_000, open: 10.0, high: 12.0, low: 9.0, close: 11.0, volume: 100.0, count: 5, wap: 10.5 });
+        assert_eq!(IBApiBar::try_from(plain).unwrap(), bar);
+
+        let invalid = PlainBar { high: 8.0, ..plain };
+        assert!(IBApiBar::try_from(invalid).is_err());
+
+        let mut moved = bar.clone();
+        moved.set_datetime(datetime!(2024-01-02 0:00 UTC));
+        assert_eq!(moved.date(), datetime!(2024-01-02 0:00 UTC).unix_timestamp());
+    }
+}
+
+mod fake_tws_tests {
+    use ibapi::contracts::Contract;
+    use ibapi::market_data::historical::BarSize;
+    use time::macros::datetime;
+    use ibapi_handler::fake_tws::{FakeHandshake, FakeResponse, FakeTws};
+    use ibapi_handler::{connect_to_tws, IBApiBar, IbapiHandler, OfflineError};
+
+    fn daily_bars() -> Vec<IBApiBar> {
+        (0..5).map(|day| IBApiBar::builder().date(datetime!(2021-01-04 00:00 UTC).unix_timestamp() + day * 86400).ohlc(10.0, 11.5, 9.25, 10.75).volume(1000.0).count(12).wap(10.5).build().unwrap()).collect()
+    }
+
+    #[test]
+    fn historical_data_test() {
+        let fake = FakeTws::serving(daily_bars()).unwrap();
+        let client = connect_to_tws(&fake.config());
+        assert!(client.is_ok(), "Error: {:?}", client.err());
+        drop(client);
+
+        let mut handler = IbapiHandler::new(fake.config()).unwrap();
+        assert!(handler.is_connected());
+        let bars = handler.get_historical_data(&Contract::stock("AAPL"), BarSize::Day, datetime!(2021-01-04 00:00 UTC), datetime!(2021-01-07 00:00 UTC)).unwrap();
+        assert_eq!(bars, daily_bars()[..3].to_vec());
+
+        let requests = fake.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].symbol, "AAPL");
+        assert_eq!(requests[0].security_type, "STK");
+        assert_eq!(requests[0].end, "20210107 00:00:00 UTC");
+        assert_eq!(requests[0].duration, "3 D");
+        assert_eq!(requests[0].bar_size, "1 day");
+        assert_eq!(requests[0].what_to_show, "TRADES");
+        assert!(requests[0].use_rth);
+        // each client is preceded by connect_to_tws probing the port
+        assert_eq!(fake.connections(), 4);
+    }
+
+    #[test]
+    fn clip_to_window_test() {
+        // the 2.5 day window is requested as 3 days, reaching back to the start of the 4th
+        let fake = FakeTws::serving(daily_bars()).unwrap();
+        let mut handler = IbapiHandler::lazy(fake.config());
+        let bars = handler.get_historical_data(&Contract::stock("AAPL"), BarSize::Day, datetime!(2021-01-04 12:00 UTC), datetime!(2021-01-07 00:00 UTC)).unwrap();
+        assert_eq!(fake.requests()[0].duration, "3 D");
+        assert_eq!(bars, daily_bars()[1..3].to_vec());
+
+        // bars after the end are dropped as well, the end itself is part of the window
+        let fake = FakeTws::with_responder(FakeHandshake::Normal, |_| FakeResponse::Bars(daily_bars())).unwrap();
+        let mut handler = IbapiHandler::lazy(fake.config());
+        let bars = handler.get_historical_data(&Contract::stock("AAPL"), BarSize::Day, datetime!(2021-01-05 00:00 UTC), datetime!(2021-01-06 00:00 UTC)).unwrap();
+        assert_eq!(bars, daily_bars()[1..3].to_vec());
+    }
+
+    #[test]
+    fn intraday_bars_test() {
+        let open = datetime!(2021-01-04 14:30 UTC).unix_timestamp();
+        let bars: Vec<IBApiBar> = (0..10).map(|minute| IBApiBar::builder().date(open + minute * 60).ohlc(10.0, 10.5, 9.5, 10.25).volume(100.0).count(3).wap(10.1).build().unwrap()).collect();
+        let fake = FakeTws::serving(bars.clone()).unwrap();
+        let mut handler = IbapiHandler::lazy(fake.config());
+        let served = handler.get_historical_data(&Contract::stock("AAPL"), BarSize::Min, datetime!(2021-01-04 14:30 UTC), datetime!(2021-01-04 14:35 UTC)).unwrap();
+        assert_eq!(served, bars[..5].to_vec());
+    }
+
+    #[test]
+    fn rejected_handshake_test() {
+        let fake = FakeTws::with_responder(FakeHandshake::Close, |_| FakeResponse::Silence).unwrap();
+        let error = IbapiHandler::new(fake.config()).err().unwrap();
+        assert!(error.downcast_ref::<OfflineError>().is_some(), "Error: {:?}", error);
+
+        let fake = FakeTws::with_responder(FakeHandshake::Raw(vec!["not a version".to_string()]), |_| FakeResponse::Silence).unwrap();
+        let error = IbapiHandler::new(fake.config()).err().unwrap();
+        assert!(error.downcast_ref::<OfflineError>().is_some(), "Error: {:?}", error);
+    }
+
+    #[test]
+    fn error_response_test() {
+        let fake = FakeTws::with_responder(FakeHandshake::Normal, |_| FakeResponse::Error {
+            code: 162,
+            message: "Historical Market Data Service error message:HMDS query returned no data".to_string(),
+        }).unwrap();
+        let mut handler = IbapiHandler::lazy(fake.config());
+        let error = handler.get_historical_data(&Contract::stock("AAPL"), BarSize::Day, datetime!(2021-01-04 00:00 UTC), datetime!(2021-01-07 00:00 UTC)).unwrap_err();
+        assert!(error.to_string().contains("HMDS query returned no data"), "Error: {:?}", error);
+    }
+
+    #[test]
+    fn malformed_response_test() {
+        let fake = FakeTws::with_responder(FakeHandshake::Normal, |request| FakeResponse::Raw(vec![
+            "17".to_string(), request.request_id.clone(), "20210104  00:00:00".to_string(), "20210107  00:00:00".to_string(),
+            "1".to_string(), "20210104".to_string(), "not a price".to_string(),
+        ])).unwrap();
+        let mut handler = IbapiHandler::lazy(fake.config());
+        let result = handler.get_historical_data(&Contract::stock("AAPL"), BarSize::Day, datetime!(2021-01-04 00:00 UTC), datetime!(2021-01-07 00:00 UTC));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn response_timeout_test() {
+        let fake = FakeTws::with_responder(FakeHandshake::Normal, |_| FakeResponse::Silence).unwrap();
+        let mut handler = IbapiHandler::lazy(fake.config());
+        let started = std::time::Instant::now();
+        let result = handler.get_historical_data(&Contract::stock("AAPL"), BarSize::Day, datetime!(2021-01-04 00:00 UTC), datetime!(2021-01-07 00:00 UTC));
+        assert!(result.is_err());
+        // ibapi waits 10 seconds for a response
+        assert!(started.elapsed() >= std::time::Duration::from_secs(9));
+    }
+}
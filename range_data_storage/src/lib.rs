@@ -3,12 +3,91 @@ pub mod range_data_storage {
     use std::io::Write;
     use serde::{Deserialize, Serialize};
     use serde;
+    use std::collections::BTreeMap;
     use std::path::Path;
-    use rangemap::{RangeInclusiveMap, StepLite};
+    use std::ops::RangeInclusive;
+    use rangemap::{RangeInclusiveMap, RangeInclusiveSet, StepLite};
     use serde::de::DeserializeOwned;
     use anyhow::{bail, Error};
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
+    /// Marks a binary RangeDataStorage file, followed by a little endian u16 format version.
+    const BINARY_MAGIC: &[u8; 4] = b"FQRD";
+    /// Marks a binary PointDataStorage file, the layout after the magic is the same.
+    const POINT_BINARY_MAGIC: &[u8; 4] = b"FQPD";
+    const BINARY_VERSION: u16 = 1;
+
+    /// How a RangeDataStorage is laid out on disk.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Default)]
+    pub enum StorageFormat {
+        /// Pretty printed JSON, easy to inspect but large and slow to parse.
+        #[default]
+        Json,
+        /// `FQRD` (`FQPD` for a PointDataStorage) magic, u16 format version, then the bincode encoding of the storage.
+        Binary,
+    }
+
+    impl StorageFormat {
+        /// The file extension (without the dot) used for this format.
+        pub fn extension(&self) -> &'static str {
+            match self {
+                StorageFormat::Json => "json",
+                StorageFormat::Binary => "bin",
+            }
+        }
+
+        /// Guesses the format of a file from its extension, defaulting to JSON.
+        pub fn from_location(location: &str) -> StorageFormat {
+            let location = location.strip_suffix(".bak").unwrap_or(location);
+            match Path::new(location).extension().and_then(|extension| extension.to_str()) {
+                Some("bin") => StorageFormat::Binary,
+                _ => StorageFormat::Json,
+            }
+        }
+
+        /// Tells the format of already read file contents apart by the binary header.
+        pub fn detect(bytes: &[u8]) -> StorageFormat {
+            if bytes.starts_with(BINARY_MAGIC) || bytes.starts_with(POINT_BINARY_MAGIC) {
+                StorageFormat::Binary
+            } else {
+                StorageFormat::Json
+            }
+        }
+
+        fn encode<T: Serialize>(&self, value: &T, magic: &[u8; 4]) -> Result<Vec<u8>, Error> {
+            match self {
+                StorageFormat::Json => Ok(serde_json::to_vec_pretty(value)?),
+                StorageFormat::Binary => {
+                    let mut bytes = Vec::new();
+                    bytes.extend_from_slice(magic);
+                    bytes.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+                    bincode::serialize_into(&mut bytes, value)?;
+                    Ok(bytes)
+                }
+            }
+        }
+
+        fn decode<T: DeserializeOwned>(bytes: &[u8], magic: &[u8; 4]) -> Result<T, Error> {
+            match StorageFormat::detect(bytes) {
+                StorageFormat::Json => Ok(serde_json::from_slice(bytes)?),
+                StorageFormat::Binary => {
+                    if !bytes.starts_with(magic) {
+                        bail!("Binary storage file holds a different kind of storage");
+                    }
+                    let header = BINARY_MAGIC.len() + 2;
+                    if bytes.len() < header {
+                        bail!("Binary storage file is truncated");
+                    }
+                    let version = u16::from_le_bytes([bytes[BINARY_MAGIC.len()], bytes[BINARY_MAGIC.len() + 1]]);
+                    if version != BINARY_VERSION {
+                        bail!("Unsupported binary storage version {} (expected {})", version, BINARY_VERSION);
+                    }
+                    Ok(bincode::deserialize(&bytes[header..])?)
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct RangeDataStorage<K, V> where
         K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
         V: Serialize + Eq + Clone + DeserializeOwned{
@@ -17,6 +96,29 @@ pub mod range_data_storage {
         V: Eq + Clone + DeserializeOwned,
     "))]
         range_map: RangeInclusiveMap<K, V>,
+        #[serde(skip)]
+        location: Option<String>, // the file this storage was loaded from / flushes to
+        #[serde(skip)]
+        autosave: bool, // flush to location when dropped
+        #[serde(skip)]
+        format: StorageFormat, // the format save/flush write in
+        #[serde(skip)]
+        unreadable: Option<String>, // a file that could not be parsed on load, never kept as a backup
+    }
+
+    /// Clones never autosave, only the instance that opted in writes to the backing file on drop.
+    impl<K, V> Clone for RangeDataStorage<K, V> where
+        K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
+        V: Serialize + Eq + Clone + DeserializeOwned {
+        fn clone(&self) -> Self {
+            RangeDataStorage {
+                range_map: self.range_map.clone(),
+                location: self.location.clone(),
+                autosave: false,
+                format: self.format,
+                unreadable: self.unreadable.clone(),
+            }
+        }
     }
 
     impl<K, V> RangeDataStorage<K, V>
@@ -24,77 +126,114 @@ pub mod range_data_storage {
             K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
             V: Serialize + Eq + Clone + DeserializeOwned {
 
+        /// Creates a storage backed by the file at `location`, loading it if it exists.
+        /// Without a location the storage lives in memory only (until `set_location`).
+        /// The storage keeps the format of the file it was loaded from; new files take
+        /// their format from the extension (`.bin` is binary, anything else JSON).
         pub fn new(location: Option<String>) -> Result<RangeDataStorage<K, V>, Error> {
-            if location.is_some() {
+            if let Some(location) = location {
                 // load from file
-                let location = location.unwrap();
 
                 // do directories for the file path exist?
                 let path = Path::new(&location);
                 if !path.exists() {
                     // create directories
-                    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    match path.parent() {
+                        Some(parent) => std::fs::create_dir_all(parent)?,
+                        None => bail!("{} has no parent directory", location),
+                    }
                 }
 
-                // does the file exist?
-                if path.exists() {
-                    let data = std::fs::read_to_string(location)?;
-                    let data: RangeDataStorage<K, V> = serde_json::from_str(&data)?;
-
-                    // clone so that the borrowed value can be returned
-                    // let cloned_data = data.clone();
+                // does the file (or the copy of it from before the last save) exist?
+                let loaded = read_with_recovery(&location, |bytes| {
+                    let mut data: RangeDataStorage<K, V> = StorageFormat::decode(bytes, BINARY_MAGIC)?;
+                    data.format = StorageFormat::detect(bytes);
+                    Ok(data)
+                })?;
+                if let Some((mut data, recovered)) = loaded {
+                    if recovered {
+                        data.unreadable = Some(location.clone());
+                    }
+                    data.location = Some(location);
                     return Ok(data);
                 }
+                return Ok(
+                    RangeDataStorage {
+                        range_map: RangeInclusiveMap::new(),
+                        format: StorageFormat::from_location(&location),
+                        location: Some(location),
+                        autosave: false,
+                        unreadable: None,
+                    }
+                );
             }
             Ok(
                 RangeDataStorage {
                     range_map: RangeInclusiveMap::new(),
+                    location: None,
+                    autosave: false,
+                    format: StorageFormat::Json,
+                    unreadable: None,
                 }
             )
         }
+
+        /// The file this storage flushes to, if any.
+        pub fn location(&self) -> Option<&String> {
+            self.location.as_ref()
+        }
+
+        /// Changes the file this storage flushes to. Nothing is written until the next flush.
+        pub fn set_location(&mut self, location: String) {
+            self.location = Some(location);
+        }
+
+        /// Whether the storage flushes itself to its location when dropped.
+        pub fn autosave(&self) -> bool {
+            self.autosave
+        }
+
+        /// Opts in to (or out of) flushing to the storage's own location when it is dropped.
+        /// Off by default; clones of an autosaving storage do not autosave.
+        pub fn set_autosave(&mut self, autosave: bool) {
+            self.autosave = autosave;
+        }
+
+        /// The format save and flush write in.
+        pub fn format(&self) -> StorageFormat {
+            self.format
+        }
+
+        /// Changes the format save and flush write in, e.g. to convert a JSON file to binary.
+        pub fn set_format(&mut self, format: StorageFormat) {
+            self.format = format;
+        }
+
+        /// Writes the storage to the location it was created with.
+        pub fn flush(&mut self) -> Result<(), Error> {
+            match self.location.clone() {
+                Some(location) => self.save(location),
+                None => bail!("RangeDataStorage has no location to flush to"),
+            }
+        }
+
         pub fn insert(&mut self, key_start: K, key_end: K, value: V) {
             let range = key_start..=key_end;
             self.range_map.insert(range, value);
         }
 
 
+        /// Writes the storage to `location` without ever leaving a half-written file behind:
+        /// the data goes to a temporary file that is synced and then renamed over `location`.
+        /// The previous contents of `location` are kept as its backup (`<location>.bak`), unless they could not
+        /// be parsed when the storage was loaded: then the backup it was recovered from stays as it is.
         pub fn save(&mut self, location: String) -> Result<(), Error>{
-            // print how many entries there are
-            println!("Entries: {}", self.range_map.len());
-            // create all parent directories
-            let path = Path::new(&location);
-            if !path.exists() {
-                let parent = path.parent();
-                if parent.is_none() {
-                    bail!("Parent directory does not exist");
-                }
-                let parent = parent.unwrap();
-                let res = std::fs::create_dir_all(parent);
-                match res {
-                    Ok(_) => {},
-                    Err(e) => { bail!(e)}
-                }
-            }
-            let file = std::fs::File::create(location);
-            if file.is_err() {
-                bail!("Error creating file");
-            }
-            let file = file.unwrap();
-            let mut writer = std::io::BufWriter::new(file);
-            // convert self to pretty json and write to file
-            let res = serde_json::to_string_pretty(self);
-            match res {
-                Ok(data) => {
-                    let res = writer.write_all(data.as_bytes());
-                    match res {
-                        Ok(_) => Ok(()),
-                        Err(e) => { bail!(e)}
-                    }
-                },
-                Err(e) => {
-                    bail!(e)
-                }
+            let data = self.format.encode(self, BINARY_MAGIC)?;
+            write_atomically(&location, &data, self.unreadable.as_ref() != Some(&location))?;
+            if self.unreadable.as_ref() == Some(&location) {
+                self.unreadable = None;
             }
+            Ok(())
         }
 
         pub fn contains(&mut self, key: K) -> bool {
@@ -110,10 +249,49 @@ pub mod range_data_storage {
             self.range_map.get(&key)
         }
 
+        /// The number of stored ranges.
         pub fn len(&self) -> usize {
             self.range_map.len()
         }
 
+        /// Returns the sub-ranges of `key_start..=key_end` that no stored range covers, in key order.
+        pub fn missing_intervals(&self, key_start: &K, key_end: &K) -> Vec<RangeInclusive<K>> {
+            let outer = key_start.clone()..=key_end.clone();
+            self.range_map.gaps(&outer).collect()
+        }
+
+        /// Iterates, in key order, over the stored ranges that overlap `range`.
+        /// Stored ranges are returned whole, they are not clipped to `range`.
+        pub fn range(&self, range: RangeInclusive<K>) -> impl Iterator<Item = (&RangeInclusive<K>, &V)> {
+            self.range_map.overlapping(range)
+        }
+
+        /// The key ranges holding a value, in key order. Adjacent stored ranges are coalesced,
+        /// whatever their values, so this answers "what do we have" rather than "how is it stored".
+        pub fn covered_intervals(&self) -> Vec<RangeInclusive<K>> {
+            let mut covered = RangeInclusiveSet::new();
+            for (range, _) in self.range_map.iter() {
+                covered.insert(range.clone());
+            }
+            covered.into_iter().collect()
+        }
+
+        /// The lowest key holding a value.
+        pub fn first_key(&self) -> Option<&K> {
+            self.range_map.first_range_value().map(|(range, _)| range.start())
+        }
+
+        /// The highest key holding a value.
+        pub fn last_key(&self) -> Option<&K> {
+            self.range_map.last_range_value().map(|(range, _)| range.end())
+        }
+
+        /// Removes every value stored for a key in `key_start..=key_end`.
+        /// Stored ranges sticking out of the window are cut back, keeping their value outside of it.
+        pub fn remove_range(&mut self, key_start: K, key_end: K) {
+            self.range_map.remove(key_start..=key_end);
+        }
+
         pub fn add_from(&mut self, other: &RangeDataStorage<K, V>) {
             for (range, value) in other.range_map.iter() {
                 self.range_map.insert(range.clone(), value.clone());
@@ -121,13 +299,352 @@ pub mod range_data_storage {
         }
     }
 
+    /// Stores one value per key (e.g. one bar per timestamp) next to a separate record of the key
+    /// ranges that have been asked for. Unlike RangeDataStorage, overlapping inserts merge value by
+    /// value and range queries return exactly the values inside the range; the coverage remembers
+    /// which parts of a range were requested but legitimately had no values.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct PointDataStorage<K, V> where
+        K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
+        V: Serialize + Clone + DeserializeOwned {
+        #[serde(bound(deserialize = r"
+        K: Ord + Clone + DeserializeOwned + StepLite,
+        V: Clone + DeserializeOwned,
+    "))]
+        points: BTreeMap<K, V>,
+        #[serde(bound(deserialize = r"
+        K: Ord + Clone + DeserializeOwned + StepLite,
+    "))]
+        coverage: RangeInclusiveSet<K>,
+        #[serde(skip)]
+        location: Option<String>, // the file this storage was loaded from / flushes to
+        #[serde(skip)]
+        autosave: bool, // flush to location when dropped
+        #[serde(skip)]
+        format: StorageFormat, // the format save/flush write in
+        #[serde(skip)]
+        unreadable: Option<String>, // a file that could not be parsed on load, never kept as a backup
+    }
+
+    /// Clones never autosave, only the instance that opted in writes to the backing file on drop.
+    impl<K, V> Clone for PointDataStorage<K, V> where
+        K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
+        V: Serialize + Clone + DeserializeOwned {
+        fn clone(&self) -> Self {
+            PointDataStorage {
+                points: self.points.clone(),
+                coverage: self.coverage.clone(),
+                location: self.location.clone(),
+                autosave: false,
+                format: self.format,
+                unreadable: self.unreadable.clone(),
+            }
+        }
+    }
+
+    impl<K, V> PointDataStorage<K, V>
+        where
+            K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
+            V: Serialize + Clone + DeserializeOwned {
+
+        /// Creates a storage backed by the file at `location`, loading it if it exists.
+        /// Behaves like `RangeDataStorage::new` with regard to locations, formats and recovery.
+        pub fn new(location: Option<String>) -> Result<PointDataStorage<K, V>, Error> {
+            if let Some(location) = location {
+                let path = Path::new(&location);
+                if !path.exists() {
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                }
+
+                let loaded = read_with_recovery(&location, |bytes| {
+                    let mut data: PointDataStorage<K, V> = StorageFormat::decode(bytes, POINT_BINARY_MAGIC)?;
+                    data.format = StorageFormat::detect(bytes);
+                    Ok(data)
+                })?;
+                if let Some((mut data, recovered)) = loaded {
+                    if recovered {
+                        data.unreadable = Some(location.clone());
+                    }
+                    data.location = Some(location);
+                    return Ok(data);
+                }
+                let format = StorageFormat::from_location(&location);
+                let mut data = PointDataStorage::new(None)?;
+                data.location = Some(location);
+                data.format = format;
+                return Ok(data);
+            }
+            Ok(
+                PointDataStorage {
+                    points: BTreeMap::new(),
+                    coverage: RangeInclusiveSet::new(),
+                    location: None,
+                    autosave: false,
+                    format: StorageFormat::Json,
+                    unreadable: None,
+                }
+            )
+        }
+
+        /// Builds a point storage out of a RangeDataStorage holding a Vec of values per range,
+        /// keying every value by `key_of`. The stored ranges become the coverage.
+        pub fn from_range_storage<F>(ranges: &RangeDataStorage<K, Vec<V>>, key_of: F) -> PointDataStorage<K, V>
+            where V: Eq, F: Fn(&V) -> K {
+            let mut points = BTreeMap::new();
+            let mut coverage = RangeInclusiveSet::new();
+            for (range, values) in ranges.range_map.iter() {
+                coverage.insert(range.clone());
+                for value in values {
+                    points.insert(key_of(value), value.clone());
+                }
+            }
+            PointDataStorage {
+                points,
+                coverage,
+                location: ranges.location.clone(),
+                autosave: false,
+                format: ranges.format,
+                unreadable: ranges.unreadable.clone(),
+            }
+        }
+
+        /// The file this storage flushes to, if any.
+        pub fn location(&self) -> Option<&String> {
+            self.location.as_ref()
+        }
+
+        /// Changes the file this storage flushes to. Nothing is written until the next flush.
+        pub fn set_location(&mut self, location: String) {
+            self.location = Some(location);
+        }
+
+        /// Whether the storage flushes itself to its location when dropped.
+        pub fn autosave(&self) -> bool {
+            self.autosave
+        }
+
+        /// Opts in to (or out of) flushing to the storage's own location when it is dropped.
+        pub fn set_autosave(&mut self, autosave: bool) {
+            self.autosave = autosave;
+        }
+
+        /// The format save and flush write in.
+        pub fn format(&self) -> StorageFormat {
+            self.format
+        }
+
+        /// Changes the format save and flush write in.
+        pub fn set_format(&mut self, format: StorageFormat) {
+            self.format = format;
+        }
+
+        /// Writes the storage to the location it was created with.
+        pub fn flush(&mut self) -> Result<(), Error> {
+            match self.location.clone() {
+                Some(location) => self.save(location),
+                None => bail!("PointDataStorage has no location to flush to"),
+            }
+        }
+
+        /// Writes the storage to `location` through a temporary file, see `RangeDataStorage::save`.
+        pub fn save(&mut self, location: String) -> Result<(), Error> {
+            let data = self.format.encode(self, POINT_BINARY_MAGIC)?;
+            write_atomically(&location, &data, self.unreadable.as_ref() != Some(&location))?;
+            if self.unreadable.as_ref() == Some(&location) {
+                self.unreadable = None;
+            }
+            Ok(())
+        }
+
+        /// Marks `key_start..=key_end` as covered and stores `values` by key, replacing values
+        /// already stored under the same key. Values may lie outside of the covered range.
+        pub fn insert<I: IntoIterator<Item = (K, V)>>(&mut self, key_start: K, key_end: K, values: I) {
+            self.coverage.insert(key_start..=key_end);
+            self.points.extend(values);
+        }
+
+        /// Stores a single value without touching the coverage.
+        pub fn insert_point(&mut self, key: K, value: V) {
+            self.points.insert(key, value);
+        }
+
+        /// The value stored under exactly `key`.
+        pub fn get(&self, key: &K) -> Option<&V> {
+            self.points.get(key)
+        }
+
+        /// Whether `key` lies in a covered range (whether or not a value is stored for it).
+        pub fn contains(&self, key: &K) -> bool {
+            self.coverage.contains(key)
+        }
+
+        /// Whether all of `key_start..=key_end` is covered.
+        pub fn contains_range(&self, key_start: &K, key_end: &K) -> bool {
+            self.missing_intervals(key_start, key_end).is_empty()
+        }
+
+        /// Returns the sub-ranges of `key_start..=key_end` that are not covered, in key order.
+        pub fn missing_intervals(&self, key_start: &K, key_end: &K) -> Vec<RangeInclusive<K>> {
+            let outer = key_start.clone()..=key_end.clone();
+            self.coverage.gaps(&outer).collect()
+        }
+
+        /// The covered ranges, in key order. Adjacent and overlapping inserts are coalesced.
+        pub fn covered_intervals(&self) -> Vec<RangeInclusive<K>> {
+            self.coverage.iter().cloned().collect()
+        }
+
+        /// Iterates, in key order, over the values whose keys lie in `range`.
+        pub fn range(&self, range: RangeInclusive<K>) -> impl Iterator<Item = (&K, &V)> {
+            self.points.range(range)
+        }
+
+        /// The lowest key holding a value.
+        pub fn first_key(&self) -> Option<&K> {
+            self.points.keys().next()
+        }
+
+        /// The highest key holding a value.
+        pub fn last_key(&self) -> Option<&K> {
+            self.points.keys().next_back()
+        }
+
+        /// Forgets `key_start..=key_end`: the values stored in it are removed and it is no longer covered.
+        pub fn remove_range(&mut self, key_start: K, key_end: K) {
+            let removed: Vec<K> = self.points.range(key_start.clone()..=key_end.clone()).map(|(key, _)| key.clone()).collect();
+            for key in removed {
+                self.points.remove(&key);
+            }
+            self.coverage.remove(key_start..=key_end);
+        }
+
+        /// The number of stored values.
+        pub fn len(&self) -> usize {
+            self.points.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.points.is_empty()
+        }
+    }
+
+    impl <K, V> Drop for PointDataStorage<K, V> where
+        K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
+        V: Serialize + Clone + DeserializeOwned {
+        fn drop(&mut self) {
+            if !self.autosave || self.location.is_none() {
+                return;
+            }
+            match self.flush() {
+                Ok(_) => {},
+                Err(e) => log::error!("Error while dropping PointDataStorage: {:?}", e),
+            }
+        }
+    }
+
+    /// Where the last good copy of the file at `location` is kept while a new one is saved.
+    pub fn backup_location(location: &str) -> String {
+        format!("{}.bak", location)
+    }
+
+    fn temporary_location(location: &str) -> String {
+        format!("{}.tmp", location)
+    }
+
+    /// Writes `bytes` to `location` through a synced temporary file and an atomic rename over the file there,
+    /// so `location` always holds either the old or the new contents. With `backup`, the old contents are
+    /// linked (or copied) to the backup location first, the backup being replaced atomically as well.
+    fn write_atomically(location: &str, bytes: &[u8], backup: bool) -> Result<(), Error> {
+        // create all parent directories
+        let path = Path::new(location);
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => bail!("Parent directory does not exist"),
+        };
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let temporary = temporary_location(location);
+        {
+            let file = std::fs::File::create(&temporary)?;
+            let mut writer = std::io::BufWriter::new(file);
+            writer.write_all(bytes)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        if backup && path.exists() {
+            let backup = backup_location(location);
+            let staged = temporary_location(&backup);
+            let _ = std::fs::remove_file(&staged);
+            if std::fs::hard_link(path, &staged).is_err() {
+                std::fs::copy(path, &staged)?;
+            }
+            std::fs::rename(&staged, backup)?;
+        }
+        std::fs::rename(&temporary, path)?;
+        sync_directory(parent);
+        Ok(())
+    }
+
+    /// Makes the renames in `directory` durable. Best effort: not every platform can open a directory.
+    fn sync_directory(directory: &Path) {
+        let directory = if directory.as_os_str().is_empty() { Path::new(".") } else { directory };
+        if let Ok(dir) = std::fs::File::open(directory) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    /// Reads and parses the file at `location`. If it is missing or cannot be parsed (a crash while
+    /// it was written by an older version, a truncated copy), falls back to its backup.
+    /// Returns None if neither exists, otherwise the data and whether it came from the backup.
+    fn read_with_recovery<T, F>(location: &str, parse: F) -> Result<Option<(T, bool)>, Error>
+        where F: Fn(&[u8]) -> Result<T, Error> {
+        let backup = backup_location(location);
+        let primary_error = if Path::new(location).exists() {
+            match std::fs::read(location).map_err(Error::from).and_then(|bytes| parse(&bytes)) {
+                Ok(data) => return Ok(Some((data, false))),
+                Err(e) => Some(e),
+            }
+        } else {
+            None
+        };
+
+        if Path::new(&backup).exists() {
+            let data = std::fs::read(&backup)?;
+            match parse(&data) {
+                Ok(data) => {
+                    if let Some(e) = &primary_error {
+                        log::warn!("Could not read {} ({:?}), recovered from {}", location, e, backup);
+                    }
+                    return Ok(Some((data, primary_error.is_some())));
+                },
+                Err(e) => {
+                    if primary_error.is_none() {
+                        bail!(e);
+                    }
+                }
+            }
+        }
+
+        match primary_error {
+            Some(e) => bail!(e),
+            None => Ok(None),
+        }
+    }
+
     impl <K, V> Drop for RangeDataStorage<K, V> where
         K: Ord + Clone + Eq + Serialize + DeserializeOwned + StepLite,
         V: Serialize+ Eq + Clone + DeserializeOwned {
         fn drop(&mut self) {
-            match self.save("@data/data.json".to_string()) {
+            if !self.autosave || self.location.is_none() {
+                return;
+            }
+            match self.flush() {
                 Ok(_) => {},
-                Err(e) => {println!("Error while dropping RangeDataStorage: {:?}", e)}
+                Err(e) => log::error!("Error while dropping RangeDataStorage: {:?}", e),
             }
         }
     }
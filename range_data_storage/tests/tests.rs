@@ -1,6 +1,6 @@
 mod all_tests {
     mod integer_range_tests {
-        use range_data_storage::range_data_storage::RangeDataStorage;
+        use range_data_storage::range_data_storage::{backup_location, PointDataStorage, RangeDataStorage, StorageFormat};
 
         #[test]
         pub fn insert_test() {
@@ -26,19 +26,291 @@ mod all_tests {
             assert_eq!(range_store.len(), 2);
         }
 
+        #[test]
+        pub fn missing_intervals_test() {
+            let mut range_store = RangeDataStorage::new(None).unwrap();
+            range_store.insert(3, 5, 3);
+            range_store.insert(8, 9, 8);
+            assert_eq!(range_store.missing_intervals(&1, &10), vec![1..=2, 6..=7, 10..=10]);
+            assert_eq!(range_store.missing_intervals(&3, &5), vec![]);
+            assert_eq!(range_store.missing_intervals(&4, &8), vec![6..=7]);
+        }
+
+        fn temp_location(name: &str) -> String {
+            let path = std::env::temp_dir().join("range_data_storage_tests").join(name);
+            let location = path.to_str().unwrap().to_string();
+            for file in [location.clone(), backup_location(&location), format!("{}.tmp", location)] {
+                if std::path::Path::new(&file).exists() {
+                    std::fs::remove_file(&file).unwrap();
+                }
+            }
+            location
+        }
+
         #[test]
         pub fn save_test() {
-            let range_store = RangeDataStorage::new(None);
+            let location = temp_location("save_test.json");
+            let range_store = RangeDataStorage::new(Some(location.clone()));
             let mut range_store = range_store.unwrap();
             range_store.insert(1, 3, 3);
+            range_store.set_autosave(true);
             drop(range_store);
 
-            let range_store = RangeDataStorage::new(Some("data.json".to_string()));
-            let mut range_store = range_store.unwrap();
+            let range_store = RangeDataStorage::new(Some(location));
+            let range_store = range_store.unwrap();
             assert_eq!(range_store.get(1), Some(&3));
             assert_eq!(range_store.get(2), Some(&3));
             assert_eq!(range_store.get(3), Some(&3));
         }
+
+        #[test]
+        pub fn flush_test() {
+            let location = temp_location("flush_test.json");
+            let mut range_store = RangeDataStorage::new(Some(location.clone())).unwrap();
+            range_store.insert(1, 3, 3);
+            assert!(range_store.flush().is_ok());
+            assert!(std::path::Path::new(&location).exists());
+
+            let reloaded: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(location.clone())).unwrap();
+            assert_eq!(reloaded.location(), Some(&location));
+            assert_eq!(reloaded.get(2), Some(&3));
+
+            // an in-memory storage has nowhere to flush to
+            let mut in_memory: RangeDataStorage<i32, i32> = RangeDataStorage::new(None).unwrap();
+            assert!(in_memory.flush().is_err());
+        }
+
+        #[test]
+        pub fn drop_without_autosave_test() {
+            let location = temp_location("drop_without_autosave_test.json");
+            let mut range_store = RangeDataStorage::new(Some(location.clone())).unwrap();
+            range_store.insert(1, 3, 3);
+            range_store.set_autosave(true);
+
+            // clones never autosave, and autosave is off by default
+            let clone = range_store.clone();
+            assert!(!clone.autosave());
+            drop(clone);
+            range_store.set_autosave(false);
+            drop(range_store);
+            assert!(!std::path::Path::new(&location).exists());
+        }
+
+        #[test]
+        pub fn atomic_save_keeps_backup_test() {
+            let location = temp_location("atomic_save_test.json");
+            let mut range_store = RangeDataStorage::new(Some(location.clone())).unwrap();
+            range_store.insert(1, 3, 3);
+            range_store.flush().unwrap();
+            range_store.insert(5, 6, 6);
+            range_store.flush().unwrap();
+
+            // the previous save is kept as the backup and no temporary file is left over
+            assert!(!std::path::Path::new(&format!("{}.tmp", location)).exists());
+            let backup: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(backup_location(&location))).unwrap();
+            assert_eq!(backup.len(), 1);
+            let current: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(location)).unwrap();
+            assert_eq!(current.len(), 2);
+        }
+
+        #[test]
+        pub fn unusable_location_test() {
+            // no parent directory to create
+            let nowhere: Result<RangeDataStorage<i32, i32>, _> = RangeDataStorage::new(Some(String::new()));
+            assert!(nowhere.is_err());
+
+            // a parent directory that can not be created, since a file is in its way
+            let file = temp_location("unusable_location_test.json");
+            std::fs::create_dir_all(std::path::Path::new(&file).parent().unwrap()).unwrap();
+            std::fs::write(&file, "").unwrap();
+            let below_file: Result<RangeDataStorage<i32, i32>, _> = RangeDataStorage::new(Some(format!("{}/data.json", file)));
+            assert!(below_file.is_err());
+        }
+
+        #[test]
+        pub fn recover_from_corrupt_file_test() {
+            let location = temp_location("recover_test.json");
+            let mut range_store = RangeDataStorage::new(Some(location.clone())).unwrap();
+            range_store.insert(1, 3, 3);
+            range_store.flush().unwrap();
+            range_store.insert(5, 6, 6);
+            range_store.flush().unwrap();
+
+            // simulate a crash halfway through writing the file
+            let contents = std::fs::read_to_string(&location).unwrap();
+            std::fs::write(&location, &contents[..contents.len() / 2]).unwrap();
+
+            let recovered: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(location.clone())).unwrap();
+            assert_eq!(recovered.get(2), Some(&3));
+            assert_eq!(recovered.get(5), None);
+
+            // without a backup to fall back on the corruption is reported
+            std::fs::remove_file(backup_location(&location)).unwrap();
+            let corrupt: Result<RangeDataStorage<i32, i32>, _> = RangeDataStorage::new(Some(location));
+            assert!(corrupt.is_err());
+        }
+
+        #[test]
+        pub fn save_after_recovery_keeps_backup_test() {
+            let location = temp_location("save_after_recovery_test.json");
+            let mut range_store = RangeDataStorage::new(Some(location.clone())).unwrap();
+            range_store.insert(1, 3, 3);
+            range_store.flush().unwrap();
+            range_store.insert(5, 6, 6);
+            range_store.flush().unwrap();
+            let contents = std::fs::read_to_string(&location).unwrap();
+            std::fs::write(&location, &contents[..contents.len() / 2]).unwrap();
+
+            // the corrupt file is replaced, not moved over the backup the storage was recovered from
+            let mut recovered: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(location.clone())).unwrap();
+            recovered.insert(8, 9, 9);
+            recovered.flush().unwrap();
+            let backup: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(backup_location(&location))).unwrap();
+            assert_eq!(backup.len(), 1);
+            assert_eq!(backup.get(2), Some(&3));
+            let current: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(location.clone())).unwrap();
+            assert_eq!(current.len(), 2);
+
+            // once a good file was written, the next save backs it up again
+            drop(current);
+            let mut current: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(location.clone())).unwrap();
+            current.insert(11, 12, 12);
+            current.flush().unwrap();
+            let backup: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(backup_location(&location))).unwrap();
+            assert_eq!(backup.len(), 2);
+            assert!(!std::path::Path::new(&format!("{}.tmp", backup_location(&location))).exists());
+        }
+
+        #[test]
+        pub fn binary_format_test() {
+            let location = temp_location("binary_test.bin");
+            let mut range_store = RangeDataStorage::new(Some(location.clone())).unwrap();
+            assert_eq!(range_store.format(), StorageFormat::Binary);
+            range_store.insert(1, 3, 3);
+            range_store.insert(5, 6, 6);
+            range_store.flush().unwrap();
+
+            // versioned header in front of the payload
+            let bytes = std::fs::read(&location).unwrap();
+            assert_eq!(&bytes[..6], b"FQRD\x01\x00");
+            assert_eq!(StorageFormat::detect(&bytes), StorageFormat::Binary);
+
+            let reloaded: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(location.clone())).unwrap();
+            assert_eq!(reloaded.format(), StorageFormat::Binary);
+            assert_eq!(reloaded.len(), 2);
+            assert_eq!(reloaded.get(2), Some(&3));
+            assert_eq!(reloaded.get(6), Some(&6));
+
+            // unknown versions are refused rather than misread
+            let mut future = bytes.clone();
+            future[4] = 2;
+            std::fs::write(&location, future).unwrap();
+            std::fs::remove_file(backup_location(&location)).ok();
+            let refused: Result<RangeDataStorage<i32, i32>, _> = RangeDataStorage::new(Some(location));
+            assert!(refused.is_err());
+        }
+
+        #[test]
+        pub fn convert_json_to_binary_test() {
+            let json_location = temp_location("convert_test.json");
+            let binary_location = temp_location("convert_test.bin");
+            let mut range_store = RangeDataStorage::new(Some(json_location)).unwrap();
+            assert_eq!(range_store.format(), StorageFormat::Json);
+            range_store.insert(1, 3, 3);
+            range_store.flush().unwrap();
+
+            range_store.set_format(StorageFormat::Binary);
+            range_store.save(binary_location.clone()).unwrap();
+            let converted: RangeDataStorage<i32, i32> = RangeDataStorage::new(Some(binary_location)).unwrap();
+            assert_eq!(converted.format(), StorageFormat::Binary);
+            assert_eq!(converted.get(2), Some(&3));
+        }
+
+        #[test]
+        pub fn range_query_test() {
+            let mut range_store: RangeDataStorage<i32, i32> = RangeDataStorage::new(None).unwrap();
+            assert_eq!(range_store.first_key(), None);
+            range_store.insert(1, 3, 1);
+            range_store.insert(4, 6, 2);
+            range_store.insert(10, 12, 3);
+            assert_eq!(range_store.first_key(), Some(&1));
+            assert_eq!(range_store.last_key(), Some(&12));
+            assert_eq!(range_store.covered_intervals(), vec![1..=6, 10..=12]);
+
+            let values: Vec<i32> = range_store.range(5..=10).map(|(_, value)| *value).collect();
+            assert_eq!(values, vec![2, 3]);
+
+            range_store.remove_range(3, 10);
+            assert_eq!(range_store.covered_intervals(), vec![1..=2, 11..=12]);
+            assert_eq!(range_store.get(2), Some(&1));
+            assert_eq!(range_store.get(11), Some(&3));
+            assert_eq!(range_store.missing_intervals(&0, &12), vec![0..=0, 3..=10]);
+        }
+
+        #[test]
+        pub fn point_storage_remove_range_test() {
+            let mut point_store: PointDataStorage<i32, i32> = PointDataStorage::new(None).unwrap();
+            point_store.insert(0, 10, vec![(1, 1), (5, 5), (9, 9)]);
+            assert_eq!(point_store.first_key(), Some(&1));
+            assert_eq!(point_store.last_key(), Some(&9));
+            point_store.remove_range(4, 9);
+            assert_eq!(point_store.len(), 1);
+            assert_eq!(point_store.last_key(), Some(&1));
+            assert_eq!(point_store.covered_intervals(), vec![0..=3, 10..=10]);
+        }
+
+        #[test]
+        pub fn point_storage_merge_test() {
+            let mut point_store: PointDataStorage<i32, i32> = PointDataStorage::new(None).unwrap();
+            point_store.insert(1, 5, vec![(1, 10), (3, 30), (5, 50)]);
+            // overlapping insert only replaces the values it brings along
+            point_store.insert(4, 8, vec![(5, 55), (7, 70)]);
+            assert_eq!(point_store.len(), 4);
+            assert_eq!(point_store.get(&3), Some(&30));
+            assert_eq!(point_store.get(&5), Some(&55));
+            assert_eq!(point_store.get(&2), None);
+            // covered, even though no value was stored there
+            assert!(point_store.contains(&2));
+            assert_eq!(point_store.covered_intervals(), vec![1..=8]);
+
+            let window: Vec<(i32, i32)> = point_store.range(2..=5).map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(window, vec![(3, 30), (5, 55)]);
+
+            point_store.insert(12, 14, vec![]);
+            assert_eq!(point_store.missing_intervals(&0, &15), vec![0..=0, 9..=11, 15..=15]);
+            assert!(point_store.contains_range(&12, &14));
+        }
+
+        #[test]
+        pub fn point_storage_save_test() {
+            for (name, format) in [("point_test.json", StorageFormat::Json), ("point_test.bin", StorageFormat::Binary)] {
+                let location = temp_location(name);
+                let mut point_store: PointDataStorage<i32, i32> = PointDataStorage::new(Some(location.clone())).unwrap();
+                assert_eq!(point_store.format(), format);
+                point_store.insert(1, 5, vec![(2, 20), (4, 40)]);
+                point_store.flush().unwrap();
+
+                let reloaded: PointDataStorage<i32, i32> = PointDataStorage::new(Some(location.clone())).unwrap();
+                assert_eq!(reloaded.format(), format);
+                assert_eq!(reloaded.get(&4), Some(&40));
+                assert_eq!(reloaded.covered_intervals(), vec![1..=5]);
+
+                // a range storage file is not mistaken for a point storage
+                let range_store: Result<RangeDataStorage<i32, i32>, _> = RangeDataStorage::new(Some(location));
+                assert!(range_store.is_err());
+            }
+        }
+
+        #[test]
+        pub fn point_storage_from_range_storage_test() {
+            let mut range_store: RangeDataStorage<i32, Vec<i32>> = RangeDataStorage::new(None).unwrap();
+            range_store.insert(0, 9, vec![2, 4]);
+            range_store.insert(20, 29, vec![21]);
+            let point_store = PointDataStorage::from_range_storage(&range_store, |value| *value);
+            assert_eq!(point_store.len(), 3);
+            assert_eq!(point_store.get(&21), Some(&21));
+            assert_eq!(point_store.covered_intervals(), vec![0..=9, 20..=29]);
+        }
     }
 
     // // Strings currently don't work because of the lack of StepLite implementation
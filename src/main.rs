@@ -1,11 +1,19 @@
 use ibapi::contracts::Contract;
 use ibapi::market_data::historical::BarSize;
-use ibapi_handler;
+use ibapi_handler::{IbapiConfig, IbapiHandler};
 use range_data_storage::range_data_storage::RangeDataStorage;
+use time::OffsetDateTime;
 
 fn main() {
-    let client = ibapi_handler::connect_to_tws();
-    match client {
+    let config = match IbapiConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return
+        }
+    };
+    let handler = IbapiHandler::new(config);
+    match handler {
         Ok(_) => { println!("Connected to TWS") },
         Err(e) => {
             println!("Error: {:?}", e);
@@ -14,13 +22,14 @@ fn main() {
     }
 
     // attempt to retrieve historical data
-    let mut client = client.unwrap();
+    let mut handler = handler.unwrap();
     let contract = Contract::stock("AAPL");
-    let data = ibapi_handler::get_historical_data(
-        &mut client,
-        contract,
-        365, // get daily data for last 365 days
-        BarSize::Day
+    let end_date = OffsetDateTime::now_utc();
+    let data = handler.get_historical_data(
+        &contract,
+        BarSize::Day,
+        end_date - time::Duration::days(365), // get daily data for last 365 days
+        end_date
     );
     match data {
         Ok(_) => { println!("Historical data retrieved") },
@@ -32,16 +41,15 @@ fn main() {
 
     let data = data.unwrap();
 
-    for bar in &data.bars {
+    for bar in &data {
         println!("Bar: {:?}", bar);
-        bar.date.unix_timestamp();
     }
 
     // collect bars
-    let bars = data.bars.clone();
+    let bars = data.clone();
 
 
-    let mut data_store = RangeDataStorage::new(Some("@data/data.json".to_string()));
+    let data_store = RangeDataStorage::new(Some("@data/data.json".to_string()));
     match data_store {
         Ok(_) => { println!("Data store created") },
         Err(e) => {
@@ -50,12 +58,15 @@ fn main() {
         }
     }
     let mut data_store = data_store.unwrap();
-    let start_date = bars[0].date.unix_timestamp();
-    let end_date = bars[bars.len() - 1].date.unix_timestamp();
-    if data_store.contains_range(start_date, end_date) {
+    let start_date = bars[0].date;
+    let end_date = bars[bars.len() - 1].date;
+    if data_store.contains_range(&start_date, &end_date) {
         println!("Already obtained this information");
     } else {
-        let wrappers = ibapi_handler::convert_bar_vec_to_wrapper(bars);
-        data_store.insert(start_date, end_date, wrappers);
+        data_store.insert(start_date, end_date, bars);
+        match data_store.flush() {
+            Ok(_) => { println!("Data store saved") },
+            Err(e) => { println!("Error: {:?}", e) }
+        }
     }
 }